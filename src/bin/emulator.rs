@@ -0,0 +1,83 @@
+#![deny(warnings)]
+#![allow(dead_code)]
+
+extern crate ajidamal;
+
+extern crate clap;
+
+use std::io::{self, BufRead};
+
+use clap::{Arg, App};
+
+use ajidamal::gsm::emulator::{Emulator};
+use ajidamal::gsm::transport::{PtyTransport};
+
+// Runs a software modem on a pty so that `gsm --port <pty>` can be
+// started without the real radio. Events can be injected by typing
+// into stdin:
+//
+//   ring          - announce an incoming call (again)
+//   answer        - pick up the outgoing call at the other end
+//   busy          - refuse the outgoing call at the other end
+//   hangup        - end the call at the other end
+//   sms <pdu>     - store a message on the SIM and send +CMTI
+//   urc <line>    - send an arbitrary unsolicited line
+//   reboot        - forget the configuration and print RDY
+fn main() {
+    let matches = App::new("Ajidamal Modem Emulator")
+        .arg(Arg::with_name("inbox")
+             .long("inbox")
+             .help("File of PDUs to preload into the SIM inbox")
+             .takes_value(true))
+        .arg(Arg::with_name("pin")
+             .long("pin")
+             .help("Lock the SIM with this PIN")
+             .takes_value(true))
+        .get_matches();
+
+    let mut emulator = Emulator::new();
+    if let Some(path) = matches.value_of("inbox") {
+        emulator.load_inbox(path).unwrap();
+    }
+
+    if let Some(pin) = matches.value_of("pin") {
+        emulator.lock_sim(pin.to_string());
+    }
+
+    let pty = PtyTransport::open().unwrap();
+    println!("Emulated modem listening on {}", pty.slave_path());
+
+    let (handle, thread) = emulator.spawn(pty).unwrap();
+
+    let stdin = io::stdin();
+    for line in stdin.lock().lines() {
+        let line = line.unwrap();
+        let line = line.trim();
+
+        let (event, argument) = match line.find(' ') {
+            Some(i) => (&line[..i], line[i + 1..].trim()),
+            None => (line, ""),
+        };
+
+        let result = match event {
+            "ring" => handle.ring(),
+            "answer" => handle.remote_answer(),
+            "busy" => handle.remote_busy(),
+            "hangup" => handle.remote_hang_up(),
+            "sms" => handle.deliver(argument.to_string()),
+            "urc" => handle.unsolicited(argument.to_string()),
+            "reboot" => handle.reboot(),
+            "" => continue,
+            _ => {
+                println!("unknown event {:?}", event);
+                continue;
+            }
+        };
+
+        if result.is_err() {
+            break;
+        }
+    }
+
+    println!("{:?}", thread.join());
+}
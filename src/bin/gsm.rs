@@ -6,14 +6,148 @@
 
 extern crate ajidamal;
 
+extern crate clap;
+
+use std::io;
+
+use clap::{Arg, App, ArgMatches};
+
 use ajidamal::gsm;
 use ajidamal::server;
 
 // TODO: Set up logging so that this program doesn't spew to stdout
 // for all of its messages.
 
+// Builds the modem configuration from (in increasing precedence) the
+// defaults, the --config file, AJI_GSM_* environment variables and the
+// remaining flags.
+fn load_config(matches: &ArgMatches) -> io::Result<gsm::config::ModemConfig> {
+    let mut config = match matches.value_of("config") {
+        Some(path) => try!(gsm::config::ModemConfig::from_file(path)),
+        None => gsm::config::ModemConfig::default(),
+    };
+
+    try!(config.apply_env());
+
+    if let Some(port) = matches.value_of("port") {
+        config.port = port.to_string();
+    }
+
+    if let Some(baud_rate) = matches.value_of("baud") {
+        try!(config.set_baud_rate(baud_rate));
+    }
+
+    if let Some(flow_control) = matches.value_of("flow-control") {
+        try!(config.set_flow_control(flow_control));
+    }
+
+    if let Some(parity) = matches.value_of("parity") {
+        try!(config.set_parity(parity));
+    }
+
+    if let Some(timeouts) = matches.values_of("timeout") {
+        for timeout in timeouts {
+            try!(config.set_timeout(timeout));
+        }
+    }
+
+    if let Some(commands) = matches.values_of("init") {
+        config.init_commands.extend(commands.map(|c| c.to_string()));
+    }
+
+    if let Some(path) = matches.value_of("call-log") {
+        config.call_log = Some(path.to_string());
+    }
+
+    Ok(config)
+}
+
 fn main() {
-    match gsm::Radio::new() {
+    let matches = App::new("Ajidamal GSM")
+        .arg(Arg::with_name("config")
+             .long("config")
+             .help("JSON file with the modem settings")
+             .takes_value(true))
+        .arg(Arg::with_name("port")
+             .long("port")
+             .help("Serial device of the modem (e.g. the pty printed by `emulator`)")
+             .takes_value(true))
+        .arg(Arg::with_name("baud")
+             .long("baud")
+             .help("Baud rate of the serial device")
+             .takes_value(true))
+        .arg(Arg::with_name("flow-control")
+             .long("flow-control")
+             .help("Flow control on the serial device")
+             .possible_values(&["none", "software", "hardware"])
+             .takes_value(true))
+        .arg(Arg::with_name("parity")
+             .long("parity")
+             .help("Parity on the serial device")
+             .possible_values(&["none", "odd", "even"])
+             .takes_value(true))
+        .arg(Arg::with_name("timeout")
+             .long("timeout")
+             .help("Timeout for a command type in milliseconds, e.g. SendSMS=90000")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1))
+        .arg(Arg::with_name("init")
+             .long("init")
+             .help("Extra AT command to send whenever the modem is initialised")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1))
+        .arg(Arg::with_name("call-log")
+             .long("call-log")
+             .help("File to keep the call history in")
+             .takes_value(true))
+        .arg(Arg::with_name("record")
+             .long("record")
+             .help("Append all traffic with the modem to this session file")
+             .takes_value(true))
+        .arg(Arg::with_name("replay")
+             .long("replay")
+             .help("Play the modem's side of a recorded session instead of opening a port")
+             .takes_value(true)
+             .conflicts_with_all(&["port", "record"]))
+        .get_matches();
+
+    let mut config = match load_config(&matches) {
+        Ok(config) => config,
+        Err(e) => {
+            println!("Invalid modem configuration: {}", e);
+            return;
+        }
+    };
+
+    let radio = if let Some(session) = matches.value_of("replay") {
+        // The replayed calls were made long ago, if at all.
+        config.call_log = None;
+
+        match gsm::recording::ReplayTransport::open(session) {
+            Ok(transport) => gsm::Radio::new_with_transport(transport, &config),
+            Err(e) => {
+                println!("Could not read session {}: {:?}", session, e);
+                return;
+            }
+        }
+    } else {
+        match matches.value_of("record") {
+            Some(session) => {
+                let session = session.to_string();
+                let port_config = config.clone();
+                gsm::Radio::new_with_connector(&config, move || {
+                    gsm::transport::open_serial_port(&port_config).and_then(|transport| {
+                        gsm::recording::RecordingTransport::create(transport, &session)
+                    })
+                })
+            },
+            None => gsm::Radio::new(&config),
+        }
+    };
+
+    match radio {
         Ok(phone) => {
             println!("Successfully started radio, starting HTTP server.");
             server::Server::start(phone);
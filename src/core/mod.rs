@@ -9,6 +9,9 @@ use std::thread;
 use std::time::{Duration};
 
 use display::ui::{Command, Interface};
+use gsm::call_log::{CallLogEntry};
+use gsm::device::{DeviceInfo};
+use gsm::signal::{SignalReading};
 use gsm::sms::{Message};
 
 use self::futures::{Future, Stream};
@@ -17,6 +20,16 @@ use self::tokio_core::reactor;
 
 const CORE_THREAD_SLEEP_MS: u64 = 10000;
 
+// There are no buttons yet, so the screen moves on to the next page
+// every time the thread wakes up.
+fn page(turn: usize) -> Command {
+    match turn % 3 {
+        0 => Command::ShowMessages,
+        1 => Command::ShowCallLog,
+        _ => Command::ShowAbout,
+    }
+}
+
 pub struct Core {
     interface: Interface,
     thread_handler: thread::JoinHandle<()>
@@ -43,9 +56,35 @@ impl Core {
         // serial port
         thread::Builder::new().name("aji/core".to_string()).spawn(
             move || {
+                // The device info doesn't change while we run, so it
+                // is only fetched until the radio first answers.
+                let mut have_device_info = false;
+                let mut turn = 0;
+
                 loop {
+                    if !have_device_info {
+                        if let Ok(Some(info)) = get_device_info() {
+                            sender.send(Command::SetDeviceInfo(info)).unwrap();
+                            have_device_info = true;
+                        }
+                    }
+
+                    match get_signal() {
+                        Ok(signal) => sender.send(Command::SetSignalBars(signal.map(|s| s.bars))).unwrap(),
+                        Err(e) => println!("could not get the signal strength: {:?}", e),
+                    }
+
+                    match get_call_log() {
+                        Ok(entries) => sender.send(Command::SetCallLog(entries)).unwrap(),
+                        Err(e) => println!("could not get the call log: {:?}", e),
+                    }
+
                     let messages = get_messages().unwrap();
                     sender.send(Command::SetMessages(messages)).unwrap();
+
+                    sender.send(page(turn)).unwrap();
+                    turn += 1;
+
                     thread::sleep(Duration::from_millis(CORE_THREAD_SLEEP_MS));
                 }
             })
@@ -68,3 +107,52 @@ fn get_messages() -> Result<Vec<Message>, Error> {
 
     core.run(work)
 }
+
+// None if the server couldn't reach the modem.
+fn get_device_info() -> Result<Option<DeviceInfo>, Error> {
+    let mut core = reactor::Core::new()?;
+    let client = Client::new(&core.handle());
+
+    let uri = "http://127.0.0.1:3000/device".parse()?;
+    let work = client.get(uri).and_then(|res| {
+        let success = res.status().is_success();
+
+        res.body().concat2().and_then(move |body: Chunk| {
+            if success {
+                Ok(serde_json::from_slice(&body).ok())
+            } else {
+                Ok(None)
+            }
+        })
+    });
+
+    core.run(work)
+}
+
+fn get_signal() -> Result<Option<SignalReading>, Error> {
+    let mut core = reactor::Core::new()?;
+    let client = Client::new(&core.handle());
+
+    let uri = "http://127.0.0.1:3000/signal".parse()?;
+    let work = client.get(uri).and_then(|res| {
+        res.body().concat2().and_then(move |body: Chunk| {
+            Ok(serde_json::from_slice(&body).unwrap_or(None))
+        })
+    });
+
+    core.run(work)
+}
+
+fn get_call_log() -> Result<Vec<CallLogEntry>, Error> {
+    let mut core = reactor::Core::new()?;
+    let client = Client::new(&core.handle());
+
+    let uri = "http://127.0.0.1:3000/calls/log".parse()?;
+    let work = client.get(uri).and_then(|res| {
+        res.body().concat2().and_then(move |body: Chunk| {
+            Ok(serde_json::from_slice(&body).unwrap_or(Vec::new()))
+        })
+    });
+
+    core.run(work)
+}
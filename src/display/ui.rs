@@ -14,12 +14,31 @@ use super::frame_buffer::{FrameBuffer};
 
 use self::chrono::prelude::*;
 
+use gsm::call_log::{CallKind, CallLogEntry};
+use gsm::device::DeviceInfo;
 use gsm::sms::Message;
 
 const UI_THREAD_SLEEP_MS: u64 = 50;
 
 pub enum Command {
-    SetMessages(Vec<Message>)
+    SetMessages(Vec<Message>),
+    SetDeviceInfo(DeviceInfo),
+    // 0 to 4, or None while the strength isn't known.
+    SetSignalBars(Option<u8>),
+    SetCallLog(Vec<CallLogEntry>),
+    // Switch the area under the status bar between the messages, the
+    // call log and the About screen.
+    ShowMessages,
+    ShowCallLog,
+    ShowAbout,
+}
+
+// What is shown under the status bar.
+#[derive(Clone, Copy, PartialEq)]
+enum Page {
+    Messages,
+    CallLog,
+    About,
 }
 
 pub enum ScreenFactory {
@@ -90,6 +109,10 @@ impl Interface {
                                                        "Other Media 4".to_string(),
                                                        Local::now()));
 
+                let mut about_view = AboutView::new();
+                let mut call_log_view = CallLogView::new();
+                let mut page = Page::Messages;
+
                 loop {
                     match receiver.recv() {
                         Ok(cmd) => {
@@ -101,7 +124,22 @@ impl Interface {
                                                                                msg.contents,
                                                                                msg.time_stamp.with_timezone(&Local)));
                                     }
-                                }
+                                },
+                                Command::SetDeviceInfo(info) => about_view.set_info(info),
+                                Command::SetSignalBars(bars) => status_bar.set_signal_bars(bars),
+                                Command::SetCallLog(entries) => call_log_view.set_entries(entries),
+                                Command::ShowMessages => {
+                                    page = Page::Messages;
+                                    main_view.mark_dirty();
+                                },
+                                Command::ShowCallLog => {
+                                    page = Page::CallLog;
+                                    call_log_view.mark_dirty();
+                                },
+                                Command::ShowAbout => {
+                                    page = Page::About;
+                                    about_view.mark_dirty();
+                                },
                             }
                         },
                         _ => return,
@@ -117,14 +155,19 @@ impl Interface {
                             &text_renderer);
                     }
 
-                    if main_view.needs_redraw() {
+                    let content_bounds = Rect::new(Point::new(/*x=*/0, status_bar_height),
+                                                   width as u64,
+                                                   height as u64 - status_bar_height);
+
+                    let delegate: &mut Delegate = match page {
+                        Page::Messages => &mut main_view,
+                        Page::CallLog => &mut call_log_view,
+                        Page::About => &mut about_view,
+                    };
+
+                    if delegate.needs_redraw() {
                         changed = true;
-                        main_view.draw(
-                            &mut View::new(Rect::new(Point::new(/*x=*/0, status_bar_height),
-                                                     width as u64,
-                                                     height as u64 - status_bar_height),
-                                           &mut root_view),
-                            &text_renderer);
+                        delegate.draw(&mut View::new(content_bounds, &mut root_view), &text_renderer);
                     }
 
                     if changed {
@@ -290,10 +333,157 @@ impl MainView {
     }
 }
 
+// Lists what the modem and SIM report about themselves.
+#[derive(Debug)]
+struct AboutView {
+    info: Option<DeviceInfo>,
+    drawn: bool
+}
+
+impl Delegate for AboutView {
+    fn needs_redraw(&self) -> bool {
+        !self.drawn
+    }
+
+    fn draw(&mut self, view: &mut View, text: &TextRenderer) {
+        let (width, height) = (view.width(), view.height());
+        view.draw_box(Point::origin(), width as usize, height as usize, Color::gray(/*intensity=*/0));
+
+        let rows = match self.info {
+            Some(ref info) => {
+                let unknown = "-".to_string();
+                vec![
+                    ("Manufacturer", info.manufacturer.clone()),
+                    ("Model", info.model.clone()),
+                    ("Firmware", info.revision.clone()),
+                    ("IMEI", info.imei.clone()),
+                    ("IMSI", info.imsi.clone().unwrap_or(unknown.clone())),
+                    ("ICCID", info.iccid.clone().unwrap_or(unknown.clone())),
+                    ("Network", info.service_provider.clone().unwrap_or(unknown)),
+                ]
+            },
+            None => vec![("Modem", "not available".to_string())],
+        };
+
+        let mut y = 2;
+        for (label, value) in rows.into_iter() {
+            let row_buffer = text.rasterize(/*size=*/12.0, Color::gray(/*intensity=*/255),
+                                            &format!("{}: {}", label, value));
+            if y + row_buffer.height() > height {
+                break;
+            }
+
+            // Long values (the ICCID) are cut off at the edge of the
+            // screen.
+            let row_width = cmp::min(width - 4, row_buffer.width());
+            view.render(&row_buffer,
+                        Rect::new(Point::new(2, y), row_width, row_buffer.height()),
+                        Rect::from_origin(row_width, row_buffer.height()));
+
+            y += row_buffer.height() + 2;
+        }
+
+        self.drawn = true;
+    }
+}
+
+impl AboutView {
+    fn new() -> AboutView {
+        AboutView {
+            info: None,
+            drawn: false
+        }
+    }
+
+    fn mark_dirty(&mut self) {
+        self.drawn = false;
+    }
+
+    fn set_info(&mut self, info: DeviceInfo) {
+        self.info = Some(info);
+        self.mark_dirty();
+    }
+}
+
+// The most recent calls first, one row each, e.g.
+// "Missed  +15551234567  09:21 AM".
+#[derive(Debug)]
+struct CallLogView {
+    entries: Vec<CallLogEntry>,
+    drawn: bool
+}
+
+impl Delegate for CallLogView {
+    fn needs_redraw(&self) -> bool {
+        !self.drawn
+    }
+
+    fn draw(&mut self, view: &mut View, text: &TextRenderer) {
+        let (width, height) = (view.width(), view.height());
+        view.draw_box(Point::origin(), width as usize, height as usize, Color::gray(/*intensity=*/0));
+
+        let mut y = 2;
+        for entry in self.entries.iter().rev() {
+            let label = match entry.kind {
+                CallKind::Incoming => "In",
+                CallKind::Outgoing => "Out",
+                CallKind::Missed => "Missed",
+                CallKind::Rejected => "Rejected",
+            };
+
+            let who = entry.name.clone()
+                .or(entry.number.clone())
+                .unwrap_or("Unknown".to_string());
+
+            // Missed calls stand out in red.
+            let color = match entry.kind {
+                CallKind::Missed => Color::new(255, 80, 80),
+                _ => Color::gray(/*intensity=*/255),
+            };
+
+            let time = entry.started_at.with_timezone(&Local);
+            let row_buffer = text.rasterize(/*size=*/12.0, color,
+                                            &format!("{}  {}  {}", label, who, time.format("%I:%M %p")));
+            if y + row_buffer.height() > height {
+                break;
+            }
+
+            let row_width = cmp::min(width - 4, row_buffer.width());
+            view.render(&row_buffer,
+                        Rect::new(Point::new(2, y), row_width, row_buffer.height()),
+                        Rect::from_origin(row_width, row_buffer.height()));
+
+            y += row_buffer.height() + 2;
+        }
+
+        self.drawn = true;
+    }
+}
+
+impl CallLogView {
+    fn new() -> CallLogView {
+        CallLogView {
+            entries: Vec::new(),
+            drawn: false
+        }
+    }
+
+    fn mark_dirty(&mut self) {
+        self.drawn = false;
+    }
+
+    fn set_entries(&mut self, entries: Vec<CallLogEntry>) {
+        if entries != self.entries {
+            self.entries = entries;
+            self.mark_dirty();
+        }
+    }
+}
 
 #[derive(Debug)]
 struct StatusBar {
     render_time: Option<DateTime<Local>>,
+    signal_bars: Option<u8>,
 }
 
 impl Delegate for StatusBar {
@@ -326,6 +516,22 @@ impl Delegate for StatusBar {
         let text_start_x = (view.width() - time_buffer.width()) - 5;
         view.render_full(&time_buffer, text_start_x, /*y=*/1);
 
+        // Four bars of increasing height on the left, the ones above
+        // the current strength in light gray. Without a reading they
+        // are all light gray.
+        let bar_width = 3;
+        let bar_spacing = 1;
+        let bottom = view.height() - 3;
+        for i in 0..4 {
+            let bar_height = 3 * (i + 1);
+            let color = match self.signal_bars {
+                Some(bars) if (i as u8) < bars => Color::gray(/*intensity=*/0),
+                _ => Color::gray(/*intensity=*/200),
+            };
+
+            view.draw_box(Point::new(5 + i * (bar_width + bar_spacing), bottom - bar_height),
+                          bar_width as usize, bar_height as usize, color);
+        }
 
         self.render_time = Some(Local::now());
     }
@@ -334,7 +540,16 @@ impl Delegate for StatusBar {
 impl StatusBar {
     fn new() -> StatusBar {
         StatusBar {
-            render_time: None
+            render_time: None,
+            signal_bars: None,
+        }
+    }
+
+    fn set_signal_bars(&mut self, bars: Option<u8>) {
+        if bars != self.signal_bars {
+            self.signal_bars = bars;
+            // Forces a redraw.
+            self.render_time = None;
         }
     }
 }
@@ -0,0 +1,591 @@
+extern crate chrono;
+
+// Voice calls. The modem only tells part of the story on its own (RING
+// and +CLIP for an incoming call, NO CARRIER or BUSY when one ends), so
+// while any call is up the CallManager lists the calls with AT+CLCC
+// every second and reconciles its model with the answer. The URCs just
+// make it look sooner.
+//
+// A call that comes in while another is up is Waiting (+CCWA, once call
+// waiting is on). Answering it holds the other call, and several calls
+// are juggled with `CallPipe::hold`.
+
+use std::io;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use self::chrono::prelude::*;
+
+use gsm::command::Pipeline;
+use gsm::errors::Error;
+use gsm::framing::FinalResult;
+use gsm::supervisor;
+use gsm::supplementary::HoldAction;
+use gsm::urc::{Urc, UrcDispatcher, UrcKind};
+
+// How often AT+CLCC is sent while there is a call.
+const CALL_POLL_INTERVAL_MS: u64 = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum CallDirection {
+    Outgoing, // 0
+    Incoming, // 1
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum CallState {
+    // No call at all.
+    Idle,
+    Dialing,
+    // The other phone is ringing.
+    Alerting,
+    Active,
+    Held,
+    Incoming,
+    // An incoming call while another call is up.
+    Waiting,
+    Ended,
+}
+
+impl CallState {
+    // The <stat> of +CLCC.
+    pub fn from_code(code: u8) -> Result<CallState, Error> {
+        match code {
+            0 => Ok(CallState::Active),
+            1 => Ok(CallState::Held),
+            2 => Ok(CallState::Dialing),
+            3 => Ok(CallState::Alerting),
+            4 => Ok(CallState::Incoming),
+            5 => Ok(CallState::Waiting),
+            _ => Err(Error::ParseError),
+        }
+    }
+
+    pub fn is_ringing(&self) -> bool {
+        *self == CallState::Incoming || *self == CallState::Waiting
+    }
+}
+
+// How a call ended.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum CallOutcome {
+    // It was answered, and one side hung up.
+    Completed,
+    Busy,
+    NoAnswer,
+    // Incoming, and nobody answered.
+    Missed,
+    // Incoming, and we hung up without answering.
+    Rejected,
+    // Outgoing, and we hung up before it was answered.
+    Cancelled,
+    Failed,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Call {
+    // The index of the call in +CLCC, once the modem has listed it.
+    pub id: Option<u32>,
+    pub direction: CallDirection,
+    // None for withheld numbers, and until +CLIP arrives.
+    pub number: Option<String>,
+    // The name the network sends with +CLIP, if any.
+    pub name: Option<String>,
+    pub state: CallState,
+    pub started_at: DateTime<Utc>,
+    pub connected_at: Option<DateTime<Utc>>,
+    pub ended_at: Option<DateTime<Utc>>,
+    pub outcome: Option<CallOutcome>,
+}
+
+impl Call {
+    fn new(direction: CallDirection, number: Option<String>, state: CallState) -> Call {
+        Call {
+            id: None,
+            direction: direction,
+            number: number,
+            name: None,
+            state: state,
+            started_at: Utc::now(),
+            connected_at: None,
+            ended_at: None,
+            outcome: None,
+        }
+    }
+
+    fn set_state(&mut self, state: CallState) {
+        if state == CallState::Active && self.connected_at.is_none() {
+            self.connected_at = Some(Utc::now());
+        }
+
+        self.state = state;
+    }
+
+    // `hint` is what we know about why the call ended: that the modem
+    // said BUSY, or that we hung up ourselves (Cancelled).
+    fn end(&mut self, hint: Option<CallOutcome>) {
+        let outcome = if self.connected_at.is_some() {
+            CallOutcome::Completed
+        } else {
+            match (self.direction, hint) {
+                (CallDirection::Incoming, Some(CallOutcome::Cancelled)) => CallOutcome::Rejected,
+                (_, Some(outcome)) => outcome,
+                (CallDirection::Incoming, None) => CallOutcome::Missed,
+                (CallDirection::Outgoing, None) if self.state == CallState::Alerting => CallOutcome::NoAnswer,
+                (CallDirection::Outgoing, None) => CallOutcome::Failed,
+            }
+        };
+
+        self.state = CallState::Ended;
+        self.ended_at = Some(Utc::now());
+        self.outcome = Some(outcome);
+    }
+
+    // How long the call was connected for.
+    pub fn duration(&self) -> Option<::std::time::Duration> {
+        match (self.connected_at, self.ended_at) {
+            (Some(connected), Some(ended)) => ended.signed_duration_since(connected).to_std().ok(),
+            _ => None,
+        }
+    }
+}
+
+// One line of +CLCC.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CallEntry {
+    pub id: u32,
+    pub direction: CallDirection,
+    pub state: CallState,
+    pub multiparty: bool,
+    pub number: Option<String>,
+}
+
+// Digits, * and # (for short codes), with an optional leading +.
+// Anything else would end up on the ATD command line, where ; and
+// letters change what the modem does.
+pub fn is_valid_number(number: &str) -> bool {
+    let digits = if number.starts_with('+') { &number[1..] } else { number };
+
+    !digits.is_empty() && digits.len() <= 40 &&
+        digits.chars().all(|c| c.is_digit(10) || c == '*' || c == '#')
+}
+
+enum Request {
+    GetCalls { response: mpsc::Sender<Vec<Call>> },
+    Dial { number: String, response: mpsc::Sender<Result<(), Error>> },
+    Answer { response: mpsc::Sender<Result<(), Error>> },
+    Reject { response: mpsc::Sender<Result<(), Error>> },
+    HangUp { response: mpsc::Sender<Result<(), Error>> },
+    Hold { action: HoldAction, response: mpsc::Sender<Result<(), Error>> },
+    Subscribe { sender: mpsc::Sender<Call> },
+    // Forwarded by the UrcDispatcher.
+    Urc(Urc),
+}
+
+#[derive(Clone, Debug)]
+pub struct CallPipe(mpsc::Sender<Request>);
+
+impl CallPipe {
+    fn request<T, F: FnOnce(mpsc::Sender<T>) -> Request>(&self, build: F) -> mpsc::Receiver<T> {
+        // Without the manager, the receiver reports the disconnection.
+        let (send, recv) = mpsc::channel();
+        self.0.send(build(send)).ok();
+
+        recv
+    }
+
+    // The calls that are up or ringing.
+    pub fn calls(&self) -> mpsc::Receiver<Vec<Call>> {
+        self.request(|response| Request::GetCalls { response: response })
+    }
+
+    // The state of the phone as a whole: the state of the call that
+    // matters most to the user, or Idle.
+    pub fn state(&self) -> CallState {
+        let calls = self.calls().recv().unwrap_or(Vec::new());
+        let precedence = [CallState::Incoming, CallState::Active, CallState::Alerting, CallState::Dialing,
+                          CallState::Waiting, CallState::Held];
+
+        precedence.iter()
+            .find(|&&state| calls.iter().any(|c| c.state == state))
+            .cloned()
+            .unwrap_or(CallState::Idle)
+    }
+
+    pub fn dial(&self, number: String) -> mpsc::Receiver<Result<(), Error>> {
+        self.request(|response| Request::Dial { number: number, response: response })
+    }
+
+    pub fn answer(&self) -> mpsc::Receiver<Result<(), Error>> {
+        self.request(|response| Request::Answer { response: response })
+    }
+
+    // Turns down a ringing call.
+    pub fn reject(&self) -> mpsc::Receiver<Result<(), Error>> {
+        self.request(|response| Request::Reject { response: response })
+    }
+
+    // Ends every call.
+    pub fn hang_up(&self) -> mpsc::Receiver<Result<(), Error>> {
+        self.request(|response| Request::HangUp { response: response })
+    }
+
+    // Holds, swaps, joins or ends some of the calls (AT+CHLD).
+    pub fn hold(&self, action: HoldAction) -> mpsc::Receiver<Result<(), Error>> {
+        self.request(|response| Request::Hold { action: action, response: response })
+    }
+
+    // Receives every call whenever its state changes, including the
+    // final change to Ended.
+    pub fn subscribe(&self) -> mpsc::Receiver<Call> {
+        let (send, recv) = mpsc::channel();
+        self.0.send(Request::Subscribe { sender: send }).ok();
+
+        recv
+    }
+}
+
+struct CallData {
+    calls: Vec<Call>,
+    // Why the next calls to disappear from +CLCC ended, when we know
+    // better than "they're gone".
+    end_hint: Option<CallOutcome>,
+    subscribers: Vec<mpsc::Sender<Call>>,
+}
+
+impl CallData {
+    fn publish(&mut self, call: &Call) {
+        println!("call {:?}: {:?}", call.number, call.state);
+        self.subscribers.retain(|s| s.send(call.clone()).is_ok());
+    }
+
+    fn ringing_call(&mut self) -> Option<&mut Call> {
+        self.calls.iter_mut().find(|c| c.state.is_ringing())
+    }
+
+    fn handle_urc(&mut self, urc: Urc) {
+        match urc {
+            Urc::Ring => {
+                if self.ringing_call().is_none() {
+                    let call = Call::new(CallDirection::Incoming, None, CallState::Incoming);
+                    self.publish(&call);
+                    self.calls.push(call);
+                }
+            },
+            Urc::CallerId { number, name, .. } => {
+                // +CLIP follows every RING.
+                let updated = match self.ringing_call() {
+                    Some(ref mut call) if call.number.is_none() => {
+                        call.number = Some(number);
+                        call.name = name;
+                        Some(call.clone())
+                    },
+                    Some(_) => None,
+                    None => {
+                        let mut call = Call::new(CallDirection::Incoming, Some(number), CallState::Incoming);
+                        call.name = name;
+                        self.calls.push(call.clone());
+                        Some(call)
+                    },
+                };
+
+                if let Some(call) = updated {
+                    self.publish(&call);
+                }
+            },
+            Urc::CallWaiting { number, name, .. } => {
+                let updated = match self.calls.iter_mut().find(|c| c.state == CallState::Waiting) {
+                    Some(ref mut call) if call.number.is_none() => {
+                        call.number = Some(number);
+                        call.name = name;
+                        Some(call.clone())
+                    },
+                    Some(_) => None,
+                    None => {
+                        let mut call = Call::new(CallDirection::Incoming, Some(number), CallState::Waiting);
+                        call.name = name;
+                        self.calls.push(call.clone());
+                        Some(call)
+                    },
+                };
+
+                if let Some(call) = updated {
+                    self.publish(&call);
+                }
+            },
+            Urc::Busy => self.end_hint = Some(CallOutcome::Busy),
+            Urc::NoAnswer => self.end_hint = Some(CallOutcome::NoAnswer),
+            _ => {},
+        }
+    }
+
+    // Brings the calls in line with what +CLCC listed. `listed_at` is
+    // when AT+CLCC was sent: calls that appeared after that can't be
+    // in the list yet.
+    fn reconcile(&mut self, entries: Vec<CallEntry>, listed_at: DateTime<Utc>) {
+        let mut changed = Vec::new();
+
+        for entry in entries.iter() {
+            // A call we only know from RING or ATD gets its index now.
+            let position = self.calls.iter().position(|c| c.id == Some(entry.id))
+                .or_else(|| self.calls.iter().position(|c| c.id.is_none() && c.direction == entry.direction));
+
+            match position {
+                Some(i) => {
+                    let call = &mut self.calls[i];
+                    call.id = Some(entry.id);
+                    if call.number.is_none() {
+                        call.number = entry.number.clone();
+                    }
+
+                    if call.state != entry.state {
+                        call.set_state(entry.state);
+                        changed.push(call.clone());
+                    }
+                },
+                None => {
+                    let mut call = Call::new(entry.direction, entry.number.clone(), entry.state);
+                    call.id = Some(entry.id);
+                    call.set_state(entry.state);
+                    changed.push(call.clone());
+                    self.calls.push(call);
+                },
+            }
+        }
+
+        let (ended, remaining): (Vec<Call>, Vec<Call>) = self.calls.drain(..).partition(|call| {
+            match call.id {
+                Some(id) => !entries.iter().any(|e| e.id == id),
+                None => call.started_at < listed_at,
+            }
+        });
+        self.calls = remaining;
+
+        let hint = if ended.is_empty() { None } else { self.end_hint.take() };
+        for mut call in ended.into_iter() {
+            call.end(hint);
+            changed.push(call);
+        }
+
+        for call in changed.iter() {
+            self.publish(call);
+        }
+    }
+
+    // Ends a call that the modem refused to set up.
+    fn fail(&mut self, mut call: Call, error: &Error) {
+        let outcome = match *error {
+            Error::CommandFailed(FinalResult::Busy) => CallOutcome::Busy,
+            Error::CommandFailed(FinalResult::NoAnswer) => CallOutcome::NoAnswer,
+            _ => CallOutcome::Failed,
+        };
+
+        call.end(Some(outcome));
+        self.publish(&call);
+    }
+}
+
+pub struct CallManager {
+    cmd_send: CallPipe,
+    join_handle: thread::JoinHandle<Result<(), ()>>,
+}
+
+impl CallManager {
+    pub fn new(pipeline: Pipeline, urcs: &UrcDispatcher) -> CallManager {
+        let (send, recv) = mpsc::channel::<Request>();
+        urcs.forward(&[UrcKind::Ring, UrcKind::CallerId, UrcKind::CallWaiting, UrcKind::NoCarrier, UrcKind::Busy,
+                       UrcKind::NoAnswer], send.clone(), Request::Urc);
+
+        CallManager {
+            join_handle: CallManager::start_daemon(pipeline, recv).unwrap(),
+            cmd_send: CallPipe(send),
+        }
+    }
+
+    pub fn get_pipe(&self) -> CallPipe {
+        self.cmd_send.clone()
+    }
+
+    // Returns once every CallPipe is gone and the UrcDispatcher has
+    // been dropped.
+    pub fn exit(self) {
+        drop(self.cmd_send);
+        println!("exited call manager {:?}", self.join_handle.join());
+    }
+
+    // Returns whether the calls should be listed again.
+    fn handle_request(data: &mut CallData, pipeline: &Pipeline, request: Request) -> bool {
+        match request {
+            // Anything the modem says about calls is a reason to look
+            // at the call list.
+            Request::Urc(urc) => {
+                data.handle_urc(urc);
+                true
+            },
+            Request::GetCalls { response } => {
+                response.send(data.calls.clone()).ok();
+                false
+            },
+            Request::Dial { number, response } => {
+                let result = match pipeline.dial(&number) {
+                    Ok(pending) => {
+                        let call = Call::new(CallDirection::Outgoing, Some(number), CallState::Dialing);
+                        data.publish(&call);
+
+                        let result = pending.wait();
+                        match result {
+                            Ok(_) => data.calls.push(call),
+                            Err(ref e) => data.fail(call, e),
+                        }
+
+                        result
+                    },
+                    Err(e) => Err(e),
+                };
+
+                let dialed = result.is_ok();
+                response.send(result).ok();
+                dialed
+            },
+            Request::Answer { response } => {
+                // ATA would leave a waiting call waiting.
+                let result = match data.ringing_call().map(|c| c.state) {
+                    None => Err(Error::NoCall),
+                    Some(CallState::Waiting) => {
+                        pipeline.hold_control(HoldAction::Swap).and_then(|r| r.wait())
+                    },
+                    Some(_) => pipeline.answer().and_then(|r| r.wait()),
+                };
+
+                if result.is_ok() {
+                    let answered = data.ringing_call().map(|call| {
+                        call.set_state(CallState::Active);
+                        call.clone()
+                    });
+
+                    if let Some(call) = answered {
+                        data.publish(&call);
+                    }
+                }
+
+                response.send(result).ok();
+                true
+            },
+            Request::Reject { response } => {
+                // ATH would end the other call too.
+                let result = match data.ringing_call().map(|c| c.state) {
+                    None => Err(Error::NoCall),
+                    Some(state) => {
+                        data.end_hint = Some(CallOutcome::Rejected);
+                        if state == CallState::Waiting {
+                            pipeline.hold_control(HoldAction::ReleaseHeld).and_then(|r| r.wait())
+                        } else {
+                            pipeline.hangup().and_then(|r| r.wait())
+                        }
+                    },
+                };
+
+                response.send(result).ok();
+                true
+            },
+            Request::HangUp { response } => {
+                let result = if data.calls.is_empty() {
+                    Err(Error::NoCall)
+                } else {
+                    data.end_hint = Some(CallOutcome::Cancelled);
+                    pipeline.hangup().and_then(|r| r.wait())
+                };
+
+                response.send(result).ok();
+                true
+            },
+            Request::Hold { action, response } => {
+                let result = if data.calls.is_empty() {
+                    Err(Error::NoCall)
+                } else {
+                    pipeline.hold_control(action).and_then(|r| r.wait())
+                };
+
+                response.send(result).ok();
+                true
+            },
+            Request::Subscribe { sender } => {
+                data.subscribers.push(sender);
+                false
+            },
+        }
+    }
+
+    fn start_daemon(pipeline: Pipeline,
+                    cmd_recv: mpsc::Receiver<Request>) -> io::Result<thread::JoinHandle<Result<(), ()>>> {
+        thread::Builder::new().name("aji/calls".to_string()).spawn(
+            move || {
+                let mut data = CallData {
+                    calls: Vec::new(),
+                    end_hint: None,
+                    subscribers: Vec::new(),
+                };
+
+                // When the calls are listed next. Without a call (or a
+                // failed listing to repeat) there is nothing to poll
+                // for, and the thread sleeps until a request or URC.
+                let mut next_poll: Option<Instant> = None;
+
+                loop {
+                    let received = match next_poll {
+                        Some(at) => {
+                            let now = Instant::now();
+                            if at > now {
+                                cmd_recv.recv_timeout(at - now)
+                            } else {
+                                Err(mpsc::RecvTimeoutError::Timeout)
+                            }
+                        },
+                        None => cmd_recv.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+                    };
+
+                    let mut poll = match received {
+                        Ok(request) => CallManager::handle_request(&mut data, &pipeline, request),
+                        Err(mpsc::RecvTimeoutError::Timeout) => true,
+                        Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(()),
+                    };
+
+                    // RING and +CLIP arrive together; one listing is
+                    // enough for both.
+                    while let Ok(request) = cmd_recv.try_recv() {
+                        poll = CallManager::handle_request(&mut data, &pipeline, request) || poll;
+                    }
+
+                    if !poll {
+                        continue;
+                    }
+
+                    let listed_at = Utc::now();
+                    let mut poll_interval = CALL_POLL_INTERVAL_MS;
+                    let listed = match pipeline.list_calls().and_then(|r| r.wait()) {
+                        Ok(entries) => {
+                            data.reconcile(entries, listed_at);
+                            true
+                        },
+                        Err(Error::Disconnected) => return Err(()),
+                        Err(Error::Unavailable) => {
+                            println!("modem is reconnecting, listing the calls again soon");
+                            poll_interval = supervisor::RETRY_DELAY_MS;
+                            false
+                        },
+                        Err(e) => {
+                            println!("could not list the calls: {:?}", e);
+                            false
+                        }
+                    };
+
+                    next_poll = if !listed || !data.calls.is_empty() {
+                        Some(Instant::now() + Duration::from_millis(poll_interval))
+                    } else {
+                        None
+                    };
+                }
+            })
+    }
+}
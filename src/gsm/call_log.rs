@@ -0,0 +1,229 @@
+extern crate chrono;
+extern crate serde_json;
+
+// The history of calls, as a phone shows it: who called or was called,
+// when, for how long and whether the call was missed or rejected. An
+// entry is written when a call ends (the CallManager tells us, caller
+// ID included). Entries are appended to a file, one JSON object per
+// line, and read back at startup so that the log survives restarts.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use self::chrono::prelude::*;
+
+use gsm::call::{Call, CallDirection, CallOutcome, CallPipe, CallState};
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum CallKind {
+    Incoming,
+    Outgoing,
+    Missed,
+    Rejected,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CallLogEntry {
+    pub kind: CallKind,
+    // None for withheld numbers.
+    pub number: Option<String>,
+    // The name the network sent along with the number, if any.
+    pub name: Option<String>,
+    pub started_at: DateTime<Utc>,
+    // How long the call was connected, 0 if it never was.
+    pub duration_secs: u64,
+    pub outcome: CallOutcome,
+}
+
+impl CallLogEntry {
+    // The entry for a call that has ended, None for one that hasn't.
+    pub fn from_call(call: &Call) -> Option<CallLogEntry> {
+        let outcome = match (call.state, call.outcome) {
+            (CallState::Ended, Some(outcome)) => outcome,
+            _ => return None,
+        };
+
+        let kind = match (call.direction, outcome) {
+            (CallDirection::Incoming, CallOutcome::Missed) => CallKind::Missed,
+            (CallDirection::Incoming, CallOutcome::Rejected) => CallKind::Rejected,
+            (CallDirection::Incoming, _) => CallKind::Incoming,
+            (CallDirection::Outgoing, _) => CallKind::Outgoing,
+        };
+
+        Some(CallLogEntry {
+            kind: kind,
+            number: call.number.clone(),
+            name: call.name.clone(),
+            started_at: call.started_at,
+            duration_secs: call.duration().map_or(0, |d| d.as_secs()),
+            outcome: outcome,
+        })
+    }
+}
+
+// Reads a log written by `append`. Lines that can't be read (e.g. the
+// last one, if the device lost power halfway through writing it) are
+// skipped.
+fn load(path: &str) -> io::Result<Vec<CallLogEntry>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut entries = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = try!(line);
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        match serde_json::from_str(&line) {
+            Ok(entry) => entries.push(entry),
+            Err(e) => println!("skipping unreadable call log entry {:?}: {}", line, e),
+        }
+    }
+
+    Ok(entries)
+}
+
+fn append(path: &str, entry: &CallLogEntry) -> io::Result<()> {
+    let mut file = try!(OpenOptions::new().create(true).append(true).open(path));
+    let line = try!(serde_json::to_string(entry).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)));
+
+    file.write_all(format!("{}\n", line).as_bytes())
+}
+
+// The entries, oldest first, shared with everyone who shows them.
+#[derive(Clone)]
+pub struct CallLog(Arc<Mutex<Vec<CallLogEntry>>>);
+
+impl CallLog {
+    pub fn entries(&self) -> Vec<CallLogEntry> {
+        self.0.lock().unwrap().clone()
+    }
+
+    // How many calls were missed after `since`, for a "2 missed calls"
+    // notice.
+    pub fn missed_since(&self, since: DateTime<Utc>) -> usize {
+        self.0.lock().unwrap().iter()
+            .filter(|e| e.kind == CallKind::Missed && e.started_at > since)
+            .count()
+    }
+
+    fn push(&self, entry: CallLogEntry) {
+        self.0.lock().unwrap().push(entry);
+    }
+}
+
+pub struct CallLogger {
+    log: CallLog,
+    join_handle: thread::JoinHandle<Result<(), ()>>,
+}
+
+impl CallLogger {
+    // Records the calls that end on `calls`. Without a `path`, or
+    // when its directory can't be created, the log only lasts as long
+    // as the program.
+    pub fn new(calls: &CallPipe, path: Option<String>) -> CallLogger {
+        let path = path.and_then(|path| match Path::new(&path).parent().map(fs::create_dir_all) {
+            Some(Err(e)) => {
+                println!("could not create the directory for the call log {}, keeping it in memory: {:?}", path, e);
+                None
+            },
+            _ => Some(path),
+        });
+
+        let entries = match path {
+            Some(ref path) => load(path).unwrap_or_else(|e| {
+                println!("could not read the call log {}: {:?}", path, e);
+                Vec::new()
+            }),
+            None => Vec::new(),
+        };
+
+        let log = CallLog(Arc::new(Mutex::new(entries)));
+
+        CallLogger {
+            join_handle: CallLogger::start_daemon(calls, path, log.clone()).unwrap(),
+            log: log,
+        }
+    }
+
+    pub fn get_log(&self) -> CallLog {
+        self.log.clone()
+    }
+
+    pub fn exit(self) {
+        println!("exited call logger {:?}", self.join_handle.join());
+    }
+
+    fn start_daemon(calls: &CallPipe, path: Option<String>,
+                    log: CallLog) -> io::Result<thread::JoinHandle<Result<(), ()>>> {
+        let changes = calls.subscribe();
+
+        thread::Builder::new().name("aji/call_log".to_string()).spawn(
+            move || {
+                // Ends once the CallManager is gone.
+                for call in changes.iter() {
+                    let entry = match CallLogEntry::from_call(&call) {
+                        Some(entry) => entry,
+                        None => continue,
+                    };
+
+                    if let Some(ref path) = path {
+                        // The entry is still shown even if it can't
+                        // be saved.
+                        if let Err(e) = append(path, &entry) {
+                            println!("could not write the call log {}: {:?}", path, e);
+                        }
+                    }
+
+                    log.push(entry);
+                }
+
+                Ok(())
+            })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CallKind, CallLogEntry};
+    use gsm::call::{Call, CallDirection, CallOutcome, CallState};
+
+    use super::chrono::prelude::*;
+
+    #[test]
+    fn classifies_ended_calls() {
+        let started_at = Utc.ymd(2018, 1, 20).and_hms(9, 21, 0);
+        let mut call = Call {
+            id: Some(1),
+            direction: CallDirection::Incoming,
+            number: Some("+15551234567".to_string()),
+            name: None,
+            state: CallState::Incoming,
+            started_at: started_at,
+            connected_at: None,
+            ended_at: None,
+            outcome: None,
+        };
+
+        assert_eq!(CallLogEntry::from_call(&call), None);
+
+        call.state = CallState::Ended;
+        call.ended_at = Some(started_at);
+        call.outcome = Some(CallOutcome::Missed);
+        let entry = CallLogEntry::from_call(&call).unwrap();
+        assert_eq!((entry.kind, entry.duration_secs), (CallKind::Missed, 0));
+
+        call.connected_at = Some(started_at);
+        call.ended_at = Some(Utc.ymd(2018, 1, 20).and_hms(9, 23, 5));
+        call.outcome = Some(CallOutcome::Completed);
+        let entry = CallLogEntry::from_call(&call).unwrap();
+        assert_eq!((entry.kind, entry.duration_secs), (CallKind::Incoming, 125));
+    }
+}
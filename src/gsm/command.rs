@@ -1,14 +1,60 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc;
+use std::time::Duration;
 
-type RawCallback = mpsc::Sender<(CommandType, String)>;
+use gsm::ModemPipe;
+use gsm::call::{self, CallEntry};
+use gsm::errors::Error;
+use gsm::framing::FinalResult;
+use gsm::responses::{self, SMS};
+use gsm::operator::{self, AccessTechnology, CurrentOperator, Operator};
+use gsm::pdu;
+use gsm::phonebook::{self, PhonebookEntry, PhonebookRange, PhonebookStorage};
+use gsm::sim;
+use gsm::urc::Urc;
+use gsm::ussd;
+use gsm::supplementary::{self, CallerIdPresentation, CallerIdRestriction, ForwardingReason, ForwardingRule,
+                         HoldAction};
+use gsm::urc::Registration;
 
-#[derive(PartialEq, Debug)]
+// Everything the modem sent for a command: the information lines and
+// the final result code (if one arrived).
+#[derive(Debug)]
+pub struct RawResponse {
+    pub lines: Vec<String>,
+    pub result: Option<FinalResult>,
+    // Set when the command was dropped because the port was being
+    // reopened.
+    pub refused: bool,
+}
+
+type RawCallback = mpsc::Sender<RawResponse>;
+
+type ResponseParser<T> = fn(&[String]) -> Result<T, Error>;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
 pub enum CommandType {
     Attention, // AT
     Hangup, // ATH
     Dial, // ATD
+    Answer, // ATA
+    ListCalls, // AT+CLCC
+    SetCallerIdReporting, // AT+CLIP=1
+    SetToneDuration, // AT+VTD
+    Dtmf, // AT+VTS
+    CallForwarding, // AT+CCFC
+    CallWaiting, // AT+CCWA
+    CallerIdRestriction, // AT+CLIR
+    HoldControl, // AT+CHLD
+    Ussd, // AT+CUSD
+    CharacterSet, // AT+CSCS
+    Phonebook, // AT+CPBS, AT+CPBR, AT+CPBF, AT+CPBW
     SignalQuality, // AT+CSQ
-    OperatorSelect, // AT+COPS
+    OperatorSelect, // AT+COPS?
+    ScanOperators, // AT+COPS=?
+    SetOperator, // AT+COPS=0, AT+COPS=1,...
     NetworkSystemMode,
     ReadSMS,
     ListSMS,
@@ -16,24 +62,276 @@ pub enum CommandType {
     GetSMSC,
     EnableEcho,
     SetSMSMode,
-    SetResultCodeMode
+    SetResultCodeMode,
+    SetNewMessageIndication,
+    SetErrorReporting,
+    Configure, // Extra init commands from the ModemConfig or profile
+    Identify, // ATI
+    GetModel, // AT+CGMM
+    GetManufacturer, // AT+CGMI
+    GetRevision, // AT+CGMR
+    GetSerialNumber, // AT+GSN
+    GetSubscriberId, // AT+CIMI
+    GetIccid, // AT+CCID (or the vendor's equivalent)
+    GetServiceProvider, // AT+CSPN?
+    GetSimState, // AT+CPIN?
+    EnterPin, // AT+CPIN=
+    FacilityLock, // AT+CLCK
+    ChangePassword, // AT+CPWD
+    GetPinAttempts, // AT+SPIC and vendor equivalents
+    SetRegistrationReporting, // AT+CREG=2, AT+CGREG=2
+    GetNetworkRegistration, // AT+CREG?
+    GetGprsRegistration, // AT+CGREG?
+}
+
+impl CommandType {
+    // How long the modem gets to finish a command before the caller
+    // is told that it timed out. Anything that waits on the network
+    // is much slower than a local setting.
+    pub fn default_timeout(&self) -> Duration {
+        let millis = match *self {
+            CommandType::SendSMS => 60000,
+            CommandType::Dial | CommandType::Answer | CommandType::Hangup => 20000,
+            // The modem answers once the tone has played, and a tone
+            // can last up to 25.5s.
+            CommandType::Dtmf => 30000,
+            // Asks the network.
+            CommandType::CallForwarding | CommandType::CallWaiting | CommandType::CallerIdRestriction => 30000,
+            CommandType::HoldControl => 20000,
+            // Some modems only answer once the network has replied.
+            CommandType::Ussd => 30000,
+            CommandType::ListSMS => 20000,
+            CommandType::OperatorSelect => 10000,
+            // 27.007 allows a network search up to three minutes.
+            CommandType::ScanOperators => 180000,
+            CommandType::SetOperator => 120000,
+            // The SIM itself is slow to check a PIN.
+            CommandType::EnterPin | CommandType::FacilityLock | CommandType::ChangePassword => 10000,
+            CommandType::ReadSMS => 5000,
+            // Reads a whole chunk of entries off the SIM.
+            CommandType::Phonebook => 20000,
+            _ => 2000,
+        };
+
+        Duration::from_millis(millis)
+    }
+
+    // Call control has to get through even when the queue is full of
+    // slow background work such as a full inbox listing.
+    pub fn default_priority(&self) -> Priority {
+        match *self {
+            CommandType::Dial | CommandType::Answer | CommandType::Hangup => Priority::Interactive,
+            CommandType::SetToneDuration | CommandType::Dtmf | CommandType::HoldControl => Priority::Interactive,
+            CommandType::Ussd => Priority::Interactive,
+            CommandType::ListSMS | CommandType::ScanOperators => Priority::Background,
+            _ => Priority::Normal,
+        }
+    }
+
+    // How many times a timed-out command is written again. Commands
+    // with side effects on the network must never be repeated, and
+    // neither may anything that checks a PIN: a wrong PIN sent twice
+    // uses up two attempts.
+    pub fn default_retries(&self) -> u32 {
+        match *self {
+            CommandType::SendSMS | CommandType::Dial | CommandType::Answer => 0,
+            // A repeated tone is a different menu choice, and a
+            // repeated swap undoes the first one.
+            CommandType::Dtmf | CommandType::HoldControl => 0,
+            CommandType::CallForwarding | CommandType::CallWaiting | CommandType::CallerIdRestriction => 0,
+            // A repeated request starts a second session, and a
+            // repeated write to the first free slot stores the entry
+            // twice.
+            CommandType::Ussd | CommandType::Phonebook => 0,
+            // Not worth another three minutes.
+            CommandType::ScanOperators | CommandType::SetOperator => 0,
+            CommandType::EnterPin | CommandType::FacilityLock | CommandType::ChangePassword => 0,
+            _ => 2,
+        }
+    }
+}
+
+// Queued commands are written highest priority first, and in the order
+// they were sent within a priority. A command that is already on the
+// wire is never interrupted.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Priority {
+    Interactive,
+    Normal,
+    Background,
+}
+
+// A command that has been queued for the modem. The typed result is
+// parsed out of the raw response once the modem finishes.
+pub struct Response<T> {
+    receiver: mpsc::Receiver<RawResponse>,
+    parser: ResponseParser<T>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl<T> Response<T> {
+    fn parse(&self, raw: RawResponse) -> Result<T, Error> {
+        if raw.refused {
+            return Err(Error::Unavailable);
+        }
+
+        match raw.result {
+            Some(ref result) if result.is_success() => (self.parser)(&raw.lines),
+            Some(result) => Err(Error::CommandFailed(result)),
+            None => Err(Error::Timeout),
+        }
+    }
+
+    pub fn wait(self) -> Result<T, Error> {
+        match self.receiver.recv() {
+            Ok(raw) => self.parse(raw),
+            Err(_) => Err(Error::Disconnected),
+        }
+    }
+
+    pub fn wait_timeout(self, timeout: Duration) -> Result<T, Error> {
+        match self.receiver.recv_timeout(timeout) {
+            Ok(raw) => self.parse(raw),
+            Err(mpsc::RecvTimeoutError::Timeout) => Err(Error::Timeout),
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(Error::Disconnected),
+        }
+    }
+
+    // Gives up on the command. If it is still queued it is never
+    // sent; if the modem is already working on it, the response is
+    // discarded (and a pending payload prompt is aborted).
+    pub fn cancel(self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    // Returns the result if the modem has finished with the command,
+    // for callers that poll in a loop.
+    pub fn try_wait(&self) -> Option<Result<T, Error>> {
+        match self.receiver.try_recv() {
+            Ok(raw) => Some(self.parse(raw)),
+            Err(mpsc::TryRecvError::Empty) => None,
+            Err(mpsc::TryRecvError::Disconnected) => Some(Err(Error::Disconnected)),
+        }
+    }
+}
+
+pub type CommandResult<T> = Result<Response<T>, Error>;
+
+// Commands whose arguments are PINs, PUKs or passwords.
+const SECRET_COMMANDS: [&'static str; 3] = ["AT+CPIN=", "AT+CLCK=", "AT+CPWD="];
+
+// `command` as written to the modem, with the arguments of PIN, PUK and
+// password commands blanked out for logs and session recordings. The
+// length stays the same, so a recording can still be replayed.
+pub fn redact(command: &[u8]) -> Vec<u8> {
+    match SECRET_COMMANDS.iter().find(|prefix| command.starts_with(prefix.as_bytes())) {
+        Some(prefix) => command.iter().enumerate()
+            .map(|(i, &b)| if i < prefix.len() || b == b'\r' { b } else { b'*' })
+            .collect(),
+        None => command.to_vec(),
+    }
 }
 
-type CommandIssueResult = Result<(), mpsc::SendError<RawCommand>>;
+// Buffer unsolicited codes while the link is busy and announce every
+// message stored on the SIM with +CMTI.
+const NEW_MESSAGE_INDICATION: &'static str = "AT+CNMI=2,1,0,0,0";
 
 pub struct RawCommand {
     bytes: Vec<u8>,
     write_cr: bool,
+    // Written once the modem answers with the `> ` prompt.
+    continuation: Option<Vec<u8>>,
     sender: Option<RawCallback>,
     command_type: CommandType,
+    priority: Priority,
+    timeout: Duration,
+    retries: u32,
+    cancelled: Arc<AtomicBool>,
 }
 
 impl RawCommand {
-    pub fn get_callback(self) -> Option<(CommandType, RawCallback)> {
-        match self.sender {
-            Some(s) => Some((self.command_type, s)),
-            None => None
+    fn new(bytes: Vec<u8>, command_type: CommandType) -> RawCommand {
+        RawCommand {
+            bytes: bytes,
+            write_cr: true,
+            continuation: None,
+            sender: None,
+            command_type: command_type,
+            priority: command_type.default_priority(),
+            timeout: command_type.default_timeout(),
+            retries: command_type.default_retries(),
+            cancelled: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn get_callback(self) -> Option<RawCallback> {
+        self.sender
+    }
+
+    // Fails the command with Error::Unavailable, for commands that
+    // can't be written while the port is being reopened.
+    pub fn refuse(self) {
+        if let Some(sender) = self.sender {
+            sender.send(RawResponse {
+                lines: Vec::new(),
+                result: None,
+                refused: true,
+            }).ok();
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    // Uses up one retry, returning false once there are none left.
+    pub fn take_retry(&mut self) -> bool {
+        if self.retries > 0 {
+            self.retries -= 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    // Whether the command stops at a `> ` prompt for a payload.
+    pub fn has_payload(&self) -> bool {
+        self.command_type == CommandType::SendSMS
+    }
+
+    pub fn command_type(&self) -> &CommandType {
+        &self.command_type
+    }
+
+    pub fn take_continuation(&mut self) -> Option<Vec<u8>> {
+        self.continuation.take()
+    }
+
+    // The prefix the modem puts in front of information responses to
+    // this command, e.g. `+CREG` for `AT+CREG?`.
+    pub fn response_prefix(&self) -> Option<String> {
+        if !self.bytes.starts_with(b"AT+") && !self.bytes.starts_with(b"AT*") {
+            return None;
         }
+
+        let name: Vec<u8> = self.bytes[2..].iter()
+            .take_while(|&&b| b != b'=' && b != b'?')
+            .cloned()
+            .collect();
+
+        String::from_utf8(name).ok()
     }
 
     pub fn render(&self) -> Vec<u8> {
@@ -47,157 +345,533 @@ impl RawCommand {
     }
 }
 
+// Commands waiting for the serial thread, one lane per priority.
+pub struct CommandQueue {
+    lanes: [VecDeque<RawCommand>; 3],
+}
+
+impl CommandQueue {
+    pub fn new() -> CommandQueue {
+        CommandQueue {
+            lanes: [VecDeque::new(), VecDeque::new(), VecDeque::new()],
+        }
+    }
+
+    pub fn push(&mut self, command: RawCommand) {
+        self.lanes[command.priority as usize].push_back(command);
+    }
+
+    pub fn pop(&mut self) -> Option<RawCommand> {
+        self.lanes.iter_mut()
+            .filter_map(|lane| lane.pop_front())
+            .next()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lanes.iter().all(|lane| lane.is_empty())
+    }
+
+    // Refuses every queued command, so that whoever is waiting on one
+    // gets Error::Unavailable.
+    pub fn clear(&mut self) {
+        for lane in self.lanes.iter_mut() {
+            for command in lane.drain(..) {
+                command.refuse();
+            }
+        }
+    }
+
+    // Puts `commands` ahead of everything else that is queued, keeping
+    // their order.
+    pub fn prepend(&mut self, commands: Vec<RawCommand>) {
+        for mut command in commands.into_iter().rev() {
+            command.priority = Priority::Interactive;
+            self.lanes[Priority::Interactive as usize].push_front(command);
+        }
+    }
+}
+
+// The configuration the rest of the stack relies on, followed by any
+// `extra` commands for this particular modem. It is sent whenever the
+// modem is (re)opened or announces that it rebooted; nobody waits on
+// the results.
+pub fn init_sequence(extra: &[String]) -> Vec<RawCommand> {
+    let mut commands = vec![
+        // To make things easier to parse, turn off command echo and
+        // set the result code to the short codes.
+        RawCommand::new(b"ATE0".to_vec(), CommandType::EnableEcho),
+        RawCommand::new(format!("ATV{}", ResultCodeMode::ShortCode as i32).into_bytes(), CommandType::SetResultCodeMode),
+        RawCommand::new(format!("AT+CMEE={}", ErrorReportingMode::Numeric as i32).into_bytes(), CommandType::SetErrorReporting),
+        RawCommand::new(format!("AT+CMGF={}", SMSMode::PDUMode as i32).into_bytes(), CommandType::SetSMSMode),
+        RawCommand::new(NEW_MESSAGE_INDICATION.as_bytes().to_vec(), CommandType::SetNewMessageIndication),
+        // Report registration changes along with the location area
+        // and cell.
+        RawCommand::new(b"AT+CREG=2".to_vec(), CommandType::SetRegistrationReporting),
+        RawCommand::new(b"AT+CGREG=2".to_vec(), CommandType::SetRegistrationReporting),
+        // Follow every RING with the caller's number.
+        RawCommand::new(b"AT+CLIP=1".to_vec(), CommandType::SetCallerIdReporting),
+    ];
+
+    commands.extend(extra.iter().map(|c| RawCommand::new(c.clone().into_bytes(), CommandType::Configure)));
+    commands
+}
+
 pub struct Pipeline {
-    phone: mpsc::Sender<RawCommand>,
+    phone: ModemPipe,
+    // Overrides the priority of every command sent through this
+    // pipeline.
+    priority: Option<Priority>,
 }
 
 impl Pipeline {
-    pub fn new(phone: mpsc::Sender<RawCommand>) -> Pipeline {
+    pub fn new(phone: ModemPipe) -> Pipeline {
         Pipeline {
             phone: phone,
+            priority: None,
         }
     }
 
-    fn send_command(&self, cmd: RawCommand) -> CommandIssueResult {
-        self.phone.send(cmd)
+    // A pipeline for pollers and other work that should never hold up
+    // the user (or the other way around, for call control).
+    pub fn with_priority(phone: ModemPipe, priority: Priority) -> Pipeline {
+        Pipeline {
+            phone: phone,
+            priority: Some(priority),
+        }
     }
 
-    pub fn attention(&self, sender: Option<RawCallback>) -> CommandIssueResult {
-        self.send_command(RawCommand {
-            bytes: "AT".as_bytes().to_vec(),
-            write_cr: true,
-            sender: sender,
-            command_type: CommandType::Attention,
-        })
+    fn send_command<T>(&self, mut cmd: RawCommand, parser: ResponseParser<T>) -> CommandResult<T> {
+        let (send, recv) = mpsc::channel();
+        cmd.sender = Some(send);
+        if let Some(priority) = self.priority {
+            cmd.priority = priority;
+        }
+        let cancelled = cmd.cancelled.clone();
+
+        match self.phone.send(cmd) {
+            Ok(_) => Ok(Response {
+                receiver: recv,
+                parser: parser,
+                cancelled: cancelled,
+            }),
+            Err(_) => Err(Error::Disconnected),
+        }
+    }
+
+    fn command<T>(&self, command: String, command_type: CommandType, parser: ResponseParser<T>) -> CommandResult<T> {
+        self.send_command(RawCommand::new(command.into_bytes(), command_type), parser)
+    }
+
+    pub fn attention(&self) -> CommandResult<()> {
+        self.command("AT".to_string(), CommandType::Attention, responses::parse_empty)
     }
 
-    pub fn hangup(&self, sender: Option<RawCallback>) -> CommandIssueResult {
+    pub fn hangup(&self) -> CommandResult<()> {
         // VOICE CALL: END:
-        self.send_command(RawCommand {
-            bytes: "ATH".as_bytes().to_vec(),
-            write_cr: true,
-            sender: sender,
-            command_type: CommandType::Hangup,
-        })
+        self.command("ATH".to_string(), CommandType::Hangup, responses::parse_empty)
     }
 
-    pub fn dial(&self, number: &str, sender: Option<RawCallback>) -> CommandIssueResult {
+    pub fn dial(&self, number: &str) -> CommandResult<()> {
         // VOICE CALL: BEGIN:
-        self.send_command(RawCommand {
-            bytes: format!("ATD{};", number).as_bytes().to_vec(),
-            write_cr: true,
-            sender: sender,
-            command_type: CommandType::Dial,
-        })
+        if !call::is_valid_number(number) {
+            return Err(Error::InvalidArgument);
+        }
+
+        self.command(format!("ATD{};", number), CommandType::Dial, responses::parse_empty)
     }
 
-    pub fn signal_quality(&self, sender: Option<RawCallback>) -> CommandIssueResult {
-        self.send_command(RawCommand {
-            bytes: "AT+CSQ?".as_bytes().to_vec(),
-            write_cr: true,
-            sender: sender,
-            command_type: CommandType::SignalQuality,
-        })
+    pub fn answer(&self) -> CommandResult<()> {
+        self.command("ATA".to_string(), CommandType::Answer, responses::parse_empty)
     }
 
-    pub fn operator_select(&self, sender: Option<RawCallback>) -> CommandIssueResult {
-        self.send_command(RawCommand {
-            bytes: "AT+COPS?".as_bytes().to_vec(),
-            write_cr: true,
-            sender: sender,
-            command_type: CommandType::OperatorSelect,
-        })
+    pub fn list_calls(&self) -> CommandResult<Vec<CallEntry>> {
+        self.command("AT+CLCC".to_string(), CommandType::ListCalls, responses::parse_call_list)
     }
 
-    pub fn network_system_mode(&self, sender: Option<RawCallback>) -> CommandIssueResult {
-        self.send_command(RawCommand {
-            bytes: "AT+CNSMOD?".as_bytes().to_vec(),
-            write_cr: true,
-            sender: sender,
-            command_type: CommandType::NetworkSystemMode
-        })
+    // Sets how long each AT+VTS tone plays, in tenths of a second.
+    pub fn set_tone_duration(&self, tenths: u8) -> CommandResult<()> {
+        self.command(format!("AT+VTD={}", tenths), CommandType::SetToneDuration, responses::parse_empty)
     }
 
-    pub fn read_sms(&self, index: u32, sender: Option<RawCallback>) -> CommandIssueResult {
-        self.send_command(RawCommand {
-            bytes: format!("AT+CMGR={}", index).as_bytes().to_vec(),
-            write_cr: true,
-            sender: sender,
-            command_type: CommandType::ReadSMS,
-        })
+    pub fn call_forwarding(&self, reason: ForwardingReason) -> CommandResult<Vec<ForwardingRule>> {
+        self.command(format!("AT+CCFC={},2", reason.code()), CommandType::CallForwarding,
+                     responses::parse_call_forwarding)
     }
 
-    pub fn list_sms(&self, store: SMSStore, sender: Option<RawCallback>) -> CommandIssueResult {
-        self.send_command(RawCommand {
-            bytes: format!("AT+CMGL={}", store as i32).as_bytes().to_vec(),
-            write_cr: true,
-            sender: sender,
-            command_type: CommandType::ListSMS,
-        })
+    // Forwards voice calls to `number`, or stops forwarding them with
+    // None. `no_reply_time` (seconds) only applies to NoReply.
+    pub fn set_call_forwarding(&self, reason: ForwardingReason, number: Option<&str>,
+                               no_reply_time: Option<u8>) -> CommandResult<()> {
+        let command = match (number, no_reply_time) {
+            (None, _) => format!("AT+CCFC={},4", reason.code()),
+            (Some(number), _) if !call::is_valid_number(number) => return Err(Error::InvalidArgument),
+            (Some(_), Some(time)) if reason != ForwardingReason::NoReply ||
+                !supplementary::is_valid_no_reply_time(time) => return Err(Error::InvalidArgument),
+            (Some(number), time) => {
+                let number_type = if number.starts_with('+') { 145 } else { 129 };
+                match time {
+                    Some(time) => format!("AT+CCFC={},3,\"{}\",{},{},,,{}", reason.code(), number, number_type,
+                                          supplementary::CLASS_VOICE, time),
+                    None => format!("AT+CCFC={},3,\"{}\",{},{}", reason.code(), number, number_type,
+                                    supplementary::CLASS_VOICE),
+                }
+            },
+        };
+
+        self.command(command, CommandType::CallForwarding, responses::parse_empty)
+    }
+
+    // Whether call waiting is on for voice calls.
+    pub fn call_waiting(&self) -> CommandResult<bool> {
+        self.command("AT+CCWA=1,2".to_string(), CommandType::CallWaiting, responses::parse_call_waiting)
+    }
+
+    // Also turns on the +CCWA URC for waiting calls.
+    pub fn set_call_waiting(&self, enabled: bool) -> CommandResult<()> {
+        self.command(format!("AT+CCWA=1,{},{}", enabled as u8, supplementary::CLASS_VOICE), CommandType::CallWaiting,
+                     responses::parse_empty)
+    }
+
+    pub fn caller_id_restriction(&self) -> CommandResult<CallerIdRestriction> {
+        self.command("AT+CLIR?".to_string(), CommandType::CallerIdRestriction, responses::parse_caller_id_restriction)
+    }
+
+    pub fn set_caller_id_presentation(&self, presentation: CallerIdPresentation) -> CommandResult<()> {
+        self.command(format!("AT+CLIR={}", presentation.code()), CommandType::CallerIdRestriction,
+                     responses::parse_empty)
+    }
+
+    pub fn hold_control(&self, action: HoldAction) -> CommandResult<()> {
+        // The call index is a single digit after the operation.
+        match action {
+            HoldAction::ReleaseCall(id) | HoldAction::Split(id) if id < 1 || id > 9 => {
+                return Err(Error::InvalidArgument);
+            },
+            _ => {},
+        }
+
+        self.command(format!("AT+CHLD={}", action.argument()), CommandType::HoldControl, responses::parse_empty)
+    }
+
+    // Sends a USSD code such as *100#, or the reply to a menu. The
+    // network's answer comes later as a +CUSD URC, unless the modem
+    // holds on to the OK until it arrives.
+    pub fn ussd(&self, text: &str) -> CommandResult<Option<Urc>> {
+        if !ussd::is_valid_request(text) {
+            return Err(Error::InvalidArgument);
+        }
+
+        self.command(format!("AT+CUSD=1,\"{}\",{}", text, ussd::DCS_GSM7), CommandType::Ussd,
+                     responses::parse_ussd)
+    }
+
+    pub fn cancel_ussd(&self) -> CommandResult<()> {
+        self.command("AT+CUSD=2".to_string(), CommandType::Ussd, responses::parse_empty)
+    }
+
+    pub fn character_set(&self) -> CommandResult<String> {
+        self.command("AT+CSCS?".to_string(), CommandType::CharacterSet, responses::parse_character_set)
+    }
+
+    pub fn set_character_set(&self, charset: &str) -> CommandResult<()> {
+        if !phonebook::is_valid_character_set(charset) {
+            return Err(Error::InvalidArgument);
+        }
+
+        self.command(format!("AT+CSCS=\"{}\"", charset), CommandType::CharacterSet, responses::parse_empty)
+    }
+
+    pub fn phonebook_storages(&self) -> CommandResult<Vec<String>> {
+        self.command("AT+CPBS=?".to_string(), CommandType::Phonebook, responses::parse_phonebook_storages)
+    }
+
+    // How full `storage` is. Every command below selects its storage
+    // in the same line, so that requests for different storages can't
+    // come in between.
+    pub fn phonebook_status(&self, storage: &str) -> CommandResult<PhonebookStorage> {
+        if !phonebook::is_valid_storage(storage) {
+            return Err(Error::InvalidArgument);
+        }
+
+        self.command(phonebook::in_storage(storage, "+CPBS?"), CommandType::Phonebook,
+                     responses::parse_phonebook_status)
+    }
+
+    pub fn phonebook_range(&self, storage: &str) -> CommandResult<PhonebookRange> {
+        if !phonebook::is_valid_storage(storage) {
+            return Err(Error::InvalidArgument);
+        }
+
+        self.command(phonebook::in_storage(storage, "+CPBR=?"), CommandType::Phonebook,
+                     responses::parse_phonebook_range)
+    }
+
+    // Whether the modem sends the numbers in UCS2 too decides how the
+    // entries are read.
+    fn phonebook_parser(&self) -> ResponseParser<Vec<PhonebookEntry>> {
+        if self.phone.profile().ucs2_phonebook_numbers() {
+            responses::parse_ucs2_phonebook_entries
+        } else {
+            responses::parse_phonebook_entries
+        }
+    }
+
+    // The phonebook commands below send and receive names in UCS2 and
+    // then switch the modem back to `charset` (see phonebook.rs).
+    pub fn read_phonebook(&self, storage: &str, first: u32, last: u32,
+                          charset: &str) -> CommandResult<Vec<PhonebookEntry>> {
+        if !phonebook::is_valid_storage(storage) || first > last || !phonebook::is_valid_character_set(charset) {
+            return Err(Error::InvalidArgument);
+        }
+
+        self.command(phonebook::in_ucs2(storage, &format!("+CPBR={},{}", first, last), charset),
+                     CommandType::Phonebook, self.phonebook_parser())
+    }
+
+    // The entries whose name starts with `name`.
+    pub fn find_phonebook(&self, storage: &str, name: &str, charset: &str) -> CommandResult<Vec<PhonebookEntry>> {
+        if !phonebook::is_valid_storage(storage) || name.is_empty() || !phonebook::is_valid_character_set(charset) {
+            return Err(Error::InvalidArgument);
+        }
+
+        self.command(phonebook::in_ucs2(storage, &format!("+CPBF=\"{}\"", pdu::encode_ucs2(name)), charset),
+                     CommandType::Phonebook, self.phonebook_parser())
     }
 
-    pub fn send_sms(&self, data: Vec<u8>, sender: Option<RawCallback>) -> CommandIssueResult {
+    // Writes the entry at `index`, or in the first free slot.
+    pub fn write_phonebook(&self, storage: &str, index: Option<u32>, number: &str, name: &str,
+                           charset: &str) -> CommandResult<()> {
+        if !phonebook::is_valid_storage(storage) || !call::is_valid_number(number) ||
+            !phonebook::is_valid_character_set(charset) {
+            return Err(Error::InvalidArgument);
+        }
+
+        let number_type = if number.starts_with('+') { 145 } else { 129 };
+        let number = if self.phone.profile().ucs2_phonebook_numbers() {
+            pdu::encode_ucs2(number)
+        } else {
+            number.to_string()
+        };
+        let command = format!("+CPBW={},\"{}\",{},\"{}\"", index.map_or(String::new(), |i| i.to_string()),
+                              number, number_type, pdu::encode_ucs2(name));
+
+        self.command(phonebook::in_ucs2(storage, &command, charset), CommandType::Phonebook, responses::parse_empty)
+    }
+
+    pub fn delete_phonebook(&self, storage: &str, index: u32) -> CommandResult<()> {
+        if !phonebook::is_valid_storage(storage) {
+            return Err(Error::InvalidArgument);
+        }
+
+        self.command(phonebook::in_storage(storage, &format!("+CPBW={}", index)), CommandType::Phonebook,
+                     responses::parse_empty)
+    }
+
+    // Plays one DTMF tone into the active call (see dtmf::send_dtmf).
+    pub fn dtmf_tone(&self, tone: char) -> CommandResult<()> {
+        if !(tone.is_digit(10) || "*#ABCD".contains(tone)) {
+            return Err(Error::InvalidArgument);
+        }
+
+        self.command(format!("AT+VTS={}", tone), CommandType::Dtmf, responses::parse_empty)
+    }
+
+    pub fn signal_quality(&self) -> CommandResult<responses::SignalQuality> {
+        self.command("AT+CSQ".to_string(), CommandType::SignalQuality, responses::parse_signal_quality)
+    }
+
+    pub fn current_operator(&self) -> CommandResult<CurrentOperator> {
+        self.command("AT+COPS?".to_string(), CommandType::OperatorSelect, responses::parse_current_operator)
+    }
+
+    // Lists the networks in range. The modem answers nothing else
+    // until the search is over.
+    pub fn scan_operators(&self) -> CommandResult<Vec<Operator>> {
+        self.command("AT+COPS=?".to_string(), CommandType::ScanOperators, responses::parse_operator_list)
+    }
+
+    pub fn select_operator_automatically(&self) -> CommandResult<()> {
+        self.command("AT+COPS=0".to_string(), CommandType::SetOperator, responses::parse_empty)
+    }
+
+    // Pins the network given by its MCC and MNC, optionally on one
+    // access technology.
+    pub fn select_operator(&self, numeric: &str, access_technology: Option<AccessTechnology>) -> CommandResult<()> {
+        if !operator::is_valid_numeric(numeric) {
+            return Err(Error::InvalidArgument);
+        }
+
+        let command = match access_technology {
+            Some(act) => format!("AT+COPS=1,2,\"{}\",{}", numeric, act.code()),
+            None => format!("AT+COPS=1,2,\"{}\"", numeric),
+        };
+
+        self.command(command, CommandType::SetOperator, responses::parse_empty)
+    }
+
+    pub fn network_registration(&self) -> CommandResult<Registration> {
+        self.command("AT+CREG?".to_string(), CommandType::GetNetworkRegistration, responses::parse_network_registration)
+    }
+
+    pub fn gprs_registration(&self) -> CommandResult<Registration> {
+        self.command("AT+CGREG?".to_string(), CommandType::GetGprsRegistration, responses::parse_gprs_registration)
+    }
+
+    pub fn network_system_mode(&self) -> CommandResult<responses::SystemMode> {
+        match self.phone.profile().system_mode_query() {
+            Some(query) => self.command(query.to_string(), CommandType::NetworkSystemMode, responses::parse_system_mode),
+            None => Err(Error::Unsupported),
+        }
+    }
+
+    // The free-form identification text (manufacturer, model and
+    // revision on most modems).
+    pub fn identify(&self) -> CommandResult<Vec<String>> {
+        self.command("ATI".to_string(), CommandType::Identify, responses::parse_lines)
+    }
+
+    pub fn model_identification(&self) -> CommandResult<String> {
+        self.command("AT+CGMM".to_string(), CommandType::GetModel, responses::parse_single_value)
+    }
+
+    pub fn manufacturer_identification(&self) -> CommandResult<String> {
+        self.command("AT+CGMI".to_string(), CommandType::GetManufacturer, responses::parse_single_value)
+    }
+
+    pub fn revision_identification(&self) -> CommandResult<String> {
+        self.command("AT+CGMR".to_string(), CommandType::GetRevision, responses::parse_revision)
+    }
+
+    // The IMEI.
+    pub fn serial_number(&self) -> CommandResult<String> {
+        self.command("AT+GSN".to_string(), CommandType::GetSerialNumber, responses::parse_single_value)
+    }
+
+    // The IMSI. Fails with a CME error when there is no SIM or it is
+    // locked.
+    pub fn subscriber_identity(&self) -> CommandResult<String> {
+        self.command("AT+CIMI".to_string(), CommandType::GetSubscriberId, responses::parse_single_value)
+    }
+
+    pub fn iccid(&self) -> CommandResult<String> {
+        self.command(self.phone.profile().iccid_command().to_string(), CommandType::GetIccid,
+                     responses::parse_single_value)
+    }
+
+    // The service provider name stored on the SIM. Plenty of SIMs
+    // don't have one.
+    pub fn service_provider(&self) -> CommandResult<String> {
+        self.command("AT+CSPN?".to_string(), CommandType::GetServiceProvider, responses::parse_service_provider)
+    }
+
+    pub fn sim_state(&self) -> CommandResult<sim::SimState> {
+        self.command("AT+CPIN?".to_string(), CommandType::GetSimState, responses::parse_sim_state)
+    }
+
+    pub fn enter_pin(&self, pin: &str) -> CommandResult<()> {
+        if !sim::is_valid_code(pin) {
+            return Err(Error::InvalidArgument);
+        }
+
+        self.command(format!("AT+CPIN=\"{}\"", pin), CommandType::EnterPin, responses::parse_empty)
+    }
+
+    // Unblocks a SIM that has used up its PIN attempts, setting a new
+    // PIN.
+    pub fn enter_puk(&self, puk: &str, new_pin: &str) -> CommandResult<()> {
+        if !sim::is_valid_code(puk) || !sim::is_valid_code(new_pin) {
+            return Err(Error::InvalidArgument);
+        }
+
+        self.command(format!("AT+CPIN=\"{}\",\"{}\"", puk, new_pin), CommandType::EnterPin, responses::parse_empty)
+    }
+
+    // Whether the SIM asks for its PIN at power on.
+    pub fn pin_lock_state(&self) -> CommandResult<bool> {
+        self.command("AT+CLCK=\"SC\",2".to_string(), CommandType::FacilityLock, responses::parse_facility_lock)
+    }
+
+    pub fn set_pin_lock(&self, enabled: bool, pin: &str) -> CommandResult<()> {
+        if !sim::is_valid_code(pin) {
+            return Err(Error::InvalidArgument);
+        }
+
+        let mode = if enabled { 1 } else { 0 };
+        self.command(format!("AT+CLCK=\"SC\",{},\"{}\"", mode, pin), CommandType::FacilityLock, responses::parse_empty)
+    }
+
+    pub fn change_pin(&self, old_pin: &str, new_pin: &str) -> CommandResult<()> {
+        if !sim::is_valid_code(old_pin) || !sim::is_valid_code(new_pin) {
+            return Err(Error::InvalidArgument);
+        }
+
+        self.command(format!("AT+CPWD=\"SC\",\"{}\",\"{}\"", old_pin, new_pin), CommandType::ChangePassword,
+                     responses::parse_empty)
+    }
+
+    pub fn pin_attempts(&self) -> CommandResult<sim::PinAttempts> {
+        match self.phone.profile().pin_attempts_query() {
+            Some(query) => self.command(query.to_string(), CommandType::GetPinAttempts, responses::parse_pin_attempts),
+            None => Err(Error::Unsupported),
+        }
+    }
+
+    // Sends a setting that the stack doesn't otherwise know about,
+    // e.g. a vendor init command.
+    pub fn configure(&self, command: &str) -> CommandResult<()> {
+        self.command(command.to_string(), CommandType::Configure, responses::parse_empty)
+    }
+
+    pub fn read_sms(&self, index: u32) -> CommandResult<SMS> {
+        self.command(format!("AT+CMGR={}", index), CommandType::ReadSMS, responses::parse_read_sms)
+    }
+
+    pub fn list_sms(&self, store: SMSStore) -> CommandResult<Vec<SMS>> {
+        self.command(format!("AT+CMGL={}", store as i32), CommandType::ListSMS, responses::parse_sms_list)
+    }
+
+    pub fn send_sms(&self, data: Vec<u8>) -> CommandResult<responses::MessageReference> {
         let string_command = String::from_utf8(data).unwrap();
         println!("sending sms command {}", string_command);
 
-        self.send_command(RawCommand {
-            bytes: format!("AT+CMGS={}", string_command.len() / 2).as_bytes().to_vec(),
-            write_cr: true,
-            sender: None,
-            command_type: CommandType::SendSMS,
-        }).unwrap();
+        // The PDU has to follow the prompt directly, so it travels
+        // with the command rather than being queued separately.
+        let mut command = RawCommand::new(format!("AT+CMGS={}", string_command.len() / 2).into_bytes(),
+                                          CommandType::SendSMS);
+        command.continuation = Some(format!("{}\u{001a}", string_command).into_bytes());
 
-        self.send_command(RawCommand {
-            bytes: format!("{}\u{001a}", string_command).as_bytes().to_vec(),
-            write_cr: false,
-            sender: sender,
-            command_type: CommandType::SendSMS,
-        })
+        self.send_command(command, responses::parse_message_reference)
     }
 
-    pub fn get_smsc(&self, sender: Option<RawCallback>) -> CommandIssueResult {
-        self.send_command(RawCommand {
-            bytes: "AT+CSCA?".as_bytes().to_vec(),
-            write_cr: true,
-            sender: sender,
-            command_type: CommandType::GetSMSC,
-        })
+    pub fn get_smsc(&self) -> CommandResult<responses::ServiceCenter> {
+        self.command("AT+CSCA?".to_string(), CommandType::GetSMSC, responses::parse_service_center)
     }
 
-    pub fn set_command_echo(&self, echo: bool) -> CommandIssueResult {
+    pub fn set_command_echo(&self, echo: bool) -> CommandResult<()> {
         let param = if echo {
             "1"
         } else {
             "0"
         };
 
-        self.send_command(RawCommand {
-            bytes: format!("ATE{}", param).as_bytes().to_vec(),
-            write_cr: true,
-            sender: None,
-            command_type: CommandType::EnableEcho
-        })
+        self.command(format!("ATE{}", param), CommandType::EnableEcho, responses::parse_empty)
     }
 
-    pub fn set_sms_mode(&self, mode: SMSMode) -> CommandIssueResult {
-        self.send_command(RawCommand {
-            bytes: format!("AT+CMGF={}", mode as i32).as_bytes().to_vec(),
-            write_cr: true,
-            sender: None,
-            command_type: CommandType::SetSMSMode
-        })
+    pub fn set_sms_mode(&self, mode: SMSMode) -> CommandResult<()> {
+        self.command(format!("AT+CMGF={}", mode as i32), CommandType::SetSMSMode, responses::parse_empty)
     }
 
-    pub fn set_result_code_mode(&self, mode: ResultCodeMode) -> CommandIssueResult {
-        self.send_command(RawCommand {
-            bytes: format!("ATV{}", mode as i32).as_bytes().to_vec(),
-            write_cr: true,
-            sender: None,
-            command_type: CommandType::SetResultCodeMode
-        })
+    pub fn set_result_code_mode(&self, mode: ResultCodeMode) -> CommandResult<()> {
+        self.command(format!("ATV{}", mode as i32), CommandType::SetResultCodeMode, responses::parse_empty)
+    }
+
+    pub fn set_error_reporting(&self, mode: ErrorReportingMode) -> CommandResult<()> {
+        self.command(format!("AT+CMEE={}", mode as i32), CommandType::SetErrorReporting, responses::parse_empty)
+    }
+
+    pub fn set_new_message_indication(&self) -> CommandResult<()> {
+        self.command(NEW_MESSAGE_INDICATION.to_string(), CommandType::SetNewMessageIndication, responses::parse_empty)
     }
-    // Ringing: 2
-    // MISSED_CALL: 09:21AM <NUM>
 }
 
 pub enum ResultCodeMode {
@@ -205,6 +879,12 @@ pub enum ResultCodeMode {
     VerboseCode = 1
 }
 
+pub enum ErrorReportingMode {
+    Disabled = 0,
+    Numeric = 1,
+    Verbose = 2
+}
+
 pub enum SMSMode {
     PDUMode = 0,
     TextMode = 1
@@ -213,3 +893,257 @@ pub enum SMSMode {
 pub enum SMSStore {
     All = 4
 }
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Read, Write};
+    use std::sync::{Arc, Mutex, mpsc};
+    use std::time::{Duration, Instant};
+
+    use gsm::SerialModem;
+    use gsm::config::ModemConfig;
+    use gsm::errors::Error;
+    use gsm::framing::FinalResult;
+    use gsm::supervisor::{Connector, ModemHealth};
+    use gsm::urc::{Urc, UrcKind};
+    use gsm::transport::{self, MemoryTransport, Transport};
+    use super::{CommandQueue, CommandType, Pipeline, Priority, RawCommand, redact};
+
+    const ESCAPE: u8 = 0x1b;
+
+    fn command(bytes: &str, command_type: CommandType) -> RawCommand {
+        RawCommand::new(bytes.as_bytes().to_vec(), command_type)
+    }
+
+    // A serial thread talking to the other end of `MemoryTransport`,
+    // past the init sequence. AT+CSQ gives up quickly.
+    fn start() -> (Pipeline, MemoryTransport, SerialModem) {
+        start_with(None)
+    }
+
+    fn start_with(connector: Option<Connector<MemoryTransport>>) -> (Pipeline, MemoryTransport, SerialModem) {
+        let (host, mut modem) = transport::memory_pair().unwrap();
+        modem.set_timeout(Duration::from_millis(5000)).unwrap();
+
+        let mut config = ModemConfig::default();
+        config.timeouts.insert(CommandType::SignalQuality, 100);
+        let serial = SerialModem::new(host, connector, config).unwrap();
+        configure(&mut modem);
+
+        (Pipeline::new(serial.get_pipe()), modem, serial)
+    }
+
+    // Answers the init sequence.
+    fn configure(modem: &mut MemoryTransport) {
+        loop {
+            let line = next_command(modem);
+            reply(modem, "OK");
+            if line == "AT+CLIP=1" {
+                break;
+            }
+        }
+    }
+
+    // The next command written to the modem, or the escape that
+    // aborts a payload prompt.
+    fn next_command(modem: &mut MemoryTransport) -> String {
+        let mut line = Vec::new();
+        let mut byte = [0u8; 1];
+
+        loop {
+            modem.read_exact(&mut byte).unwrap();
+            match byte[0] {
+                b'\r' => return String::from_utf8(line).unwrap(),
+                ESCAPE => return "\u{1b}".to_string(),
+                b => line.push(b),
+            }
+        }
+    }
+
+    fn reply(modem: &mut MemoryTransport, lines: &str) {
+        modem.write_all(format!("\r\n{}\r\n", lines).as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn pops_by_priority_then_order() {
+        let mut queue = CommandQueue::new();
+        queue.push(command("AT+CMGL=4", CommandType::ListSMS));
+        queue.push(command("AT+CSQ", CommandType::SignalQuality));
+        queue.push(command("AT+COPS?", CommandType::OperatorSelect));
+        queue.push(command("ATH", CommandType::Hangup));
+
+        assert_eq!(queue.pop().unwrap().priority(), Priority::Interactive);
+        assert_eq!(queue.pop().unwrap().render(), b"AT+CSQ\r".to_vec());
+        assert_eq!(queue.pop().unwrap().render(), b"AT+COPS?\r".to_vec());
+        assert_eq!(queue.pop().unwrap().priority(), Priority::Background);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn redacts_secrets() {
+        assert_eq!(redact(b"AT+CPIN=\"1234\"\r"), b"AT+CPIN=******\r".to_vec());
+        assert_eq!(redact(b"AT+CPWD=\"SC\",\"1234\",\"4321\""), b"AT+CPWD=******************".to_vec());
+        assert_eq!(redact(b"AT+CPIN?\r"), b"AT+CPIN?\r".to_vec());
+        assert_eq!(redact(b"AT+CSQ\r"), b"AT+CSQ\r".to_vec());
+    }
+
+    #[test]
+    fn retries_then_times_out_on_a_silent_modem() {
+        let (pipeline, mut modem, _serial) = start();
+        let response = pipeline.signal_quality().unwrap();
+
+        // Written once, then once per retry.
+        for _ in 0..3 {
+            assert_eq!(next_command(&mut modem), "AT+CSQ");
+        }
+
+        match response.wait() {
+            Err(Error::Timeout) => {},
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+
+        // The modem is still usable afterwards.
+        let response = pipeline.attention().unwrap();
+        assert_eq!(next_command(&mut modem), "AT");
+        reply(&mut modem, "OK");
+        assert!(response.wait().is_ok());
+    }
+
+    #[test]
+    fn cancelled_commands_are_skipped_or_aborted() {
+        let (pipeline, mut modem, _serial) = start();
+
+        let first = pipeline.signal_quality().unwrap();
+        assert_eq!(next_command(&mut modem), "AT+CSQ");
+
+        // Still queued behind AT+CSQ, so it is never written.
+        pipeline.current_operator().unwrap().cancel();
+        let last = pipeline.attention().unwrap();
+
+        reply(&mut modem, "+CSQ: 20,99\r\n\r\nOK");
+        assert_eq!(first.wait().unwrap().rssi, 20);
+        assert_eq!(next_command(&mut modem), "AT");
+        reply(&mut modem, "OK");
+        assert!(last.wait().is_ok());
+
+        // Already at the payload prompt: the payload is replaced by
+        // the escape, and the modem's answer finishes the command.
+        let sms = pipeline.send_sms(b"0011".to_vec()).unwrap();
+        assert_eq!(next_command(&mut modem), "AT+CMGS=2");
+        sms.cancel();
+        modem.write_all(b"\r\n> ").unwrap();
+        assert_eq!(next_command(&mut modem), "\u{1b}");
+        reply(&mut modem, "OK");
+
+        let response = pipeline.attention().unwrap();
+        assert_eq!(next_command(&mut modem), "AT");
+        reply(&mut modem, "OK");
+        assert!(response.wait().is_ok());
+    }
+
+    #[test]
+    fn passes_call_progress_to_the_call_manager() {
+        let (pipeline, mut modem, serial) = start();
+        let progress = serial.urcs.subscribe(&[UrcKind::NoCarrier, UrcKind::Busy]);
+
+        // The other side hangs up while the calls are being listed.
+        let calls = pipeline.list_calls().unwrap();
+        assert_eq!(next_command(&mut modem), "AT+CLCC");
+        reply(&mut modem, "3\r\n\r\n0");
+        assert_eq!(calls.wait().unwrap(), vec![]);
+        assert_eq!(progress.recv_timeout(Duration::from_secs(5)).unwrap(), Urc::NoCarrier);
+
+        // BUSY is the result of a dial, though.
+        let dial = pipeline.dial("+15551234567").unwrap();
+        assert_eq!(next_command(&mut modem), "ATD+15551234567;");
+        reply(&mut modem, "7");
+        match dial.wait() {
+            Err(Error::CommandFailed(FinalResult::Busy)) => {},
+            other => panic!("expected BUSY, got {:?}", other),
+        }
+        assert!(progress.try_recv().is_err());
+    }
+
+    #[test]
+    fn fails_commands_while_the_modem_is_gone() {
+        let gone: Connector<MemoryTransport> = Box::new(|| Err(io::Error::new(io::ErrorKind::NotFound, "gone")));
+        let (pipeline, mut modem, serial) = start_with(Some(gone));
+
+        let in_flight = pipeline.signal_quality().unwrap();
+        assert_eq!(next_command(&mut modem), "AT+CSQ");
+        let queued = pipeline.attention().unwrap();
+        drop(modem);
+
+        match in_flight.wait() {
+            Err(Error::Unavailable) => {},
+            other => panic!("expected the command to be refused, got {:?}", other),
+        }
+        match queued.wait() {
+            Err(Error::Unavailable) => {},
+            other => panic!("expected the command to be refused, got {:?}", other),
+        }
+
+        // Sent while the supervisor waits to try the port again.
+        let started = Instant::now();
+        match pipeline.attention().and_then(|r| r.wait()) {
+            Err(Error::Unavailable) => {},
+            other => panic!("expected the command to be refused, got {:?}", other),
+        }
+        assert!(started.elapsed() < Duration::from_millis(500));
+        assert_eq!(serial.health.state(), ModemHealth::Recovering);
+    }
+
+    #[test]
+    fn closes_the_port_before_opening_it_again() {
+        let old: Arc<Mutex<Option<MemoryTransport>>> = Arc::new(Mutex::new(None));
+        let (opened, reopened) = mpsc::channel();
+
+        let previous = old.clone();
+        let connector: Connector<MemoryTransport> = Box::new(move || {
+            // An exclusive serial port can't be opened while the
+            // serial thread still holds it.
+            let mut previous = previous.lock().unwrap().take().unwrap();
+            let mut chunk = [0u8; 256];
+            loop {
+                match previous.read(&mut chunk) {
+                    Ok(0) => break,
+                    Ok(_) => {},
+                    Err(_) => return Err(io::Error::new(io::ErrorKind::AddrInUse, "still open")),
+                }
+            }
+
+            // The answer to a command that was given up on.
+            let (host, mut modem) = try!(transport::memory_pair());
+            try!(modem.set_timeout(Duration::from_millis(5000)));
+            reply(&mut modem, "+CSQ: 5,0\r\n\r\nOK");
+            opened.send(modem).unwrap();
+
+            Ok(host)
+        });
+        let (pipeline, modem, _serial) = start_with(Some(connector));
+        *old.lock().unwrap() = Some(modem);
+
+        // Three commands without an answer wedge the modem.
+        for _ in 0..3 {
+            match pipeline.signal_quality().and_then(|r| r.wait()) {
+                Err(Error::Timeout) => {},
+                other => panic!("expected a timeout, got {:?}", other),
+            }
+        }
+
+        let mut modem = reopened.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        // The late answer doesn't finish the first command.
+        next_command(&mut modem);
+        modem.set_timeout(Duration::from_millis(300)).unwrap();
+        assert!(modem.read(&mut [0u8; 1]).is_err());
+        modem.set_timeout(Duration::from_millis(5000)).unwrap();
+        reply(&mut modem, "OK");
+        configure(&mut modem);
+
+        let quality = pipeline.signal_quality().unwrap();
+        assert_eq!(next_command(&mut modem), "AT+CSQ");
+        reply(&mut modem, "+CSQ: 20,0\r\n\r\nOK");
+        assert_eq!(quality.wait().unwrap().rssi, 20);
+    }
+}
@@ -0,0 +1,238 @@
+extern crate serde_json;
+
+// Not every board has the modem on the Pi's UART: USB modems show up
+// as /dev/ttyUSB2 and friends, run at other rates and sometimes need
+// hardware flow control or a vendor command or two before they behave.
+// A ModemConfig collects those settings. It starts from the defaults
+// below and can be overridden by a JSON file, by AJI_GSM_* environment
+// variables and finally by command line flags (see bin/gsm.rs).
+
+use std::collections::HashMap;
+use std::env;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use super::command::CommandType;
+
+const DEFAULT_PORT: &'static str = "/dev/ttyAMA0";
+const DEFAULT_BAUD_RATE: usize = 115200;
+// Under the data directory of whoever runs the daemon.
+const DEFAULT_CALL_LOG: &'static str = "ajidamal/calls.log";
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FlowControl {
+    None,
+    Software,
+    Hardware,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ModemConfig {
+    pub port: String,
+    pub baud_rate: usize,
+    pub flow_control: FlowControl,
+    pub parity: Parity,
+    // Milliseconds, keyed by command type (e.g. "SendSMS"). Anything
+    // not listed keeps the default for its command type.
+    pub timeouts: HashMap<CommandType, u64>,
+    // Sent after the standard configuration whenever the modem is
+    // (re)initialised.
+    pub init_commands: Vec<String>,
+    // Where the call history is kept (see call_log), by default in
+    // $XDG_DATA_HOME or ~/.local/share. null keeps it in memory only.
+    pub call_log: Option<String>,
+}
+
+impl Default for ModemConfig {
+    fn default() -> ModemConfig {
+        ModemConfig {
+            port: DEFAULT_PORT.to_string(),
+            baud_rate: DEFAULT_BAUD_RATE,
+            flow_control: FlowControl::None,
+            parity: Parity::None,
+            timeouts: HashMap::new(),
+            init_commands: Vec::new(),
+            call_log: default_call_log(),
+        }
+    }
+}
+
+// None if there is neither an XDG_DATA_HOME nor a HOME to put the log
+// in.
+fn default_call_log() -> Option<String> {
+    let data_home = match (env::var("XDG_DATA_HOME"), env::var("HOME")) {
+        (Ok(ref data_home), _) if !data_home.is_empty() => Path::new(data_home).to_path_buf(),
+        (_, Ok(ref home)) if !home.is_empty() => Path::new(home).join(".local/share"),
+        _ => return None,
+    };
+
+    Some(data_home.join(DEFAULT_CALL_LOG).to_string_lossy().into_owned())
+}
+
+fn invalid_input(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+// Parses the value of a setting given as text, as in the environment
+// or on the command line.
+fn parse_setting<T: FromStr>(name: &str, value: &str) -> io::Result<T> {
+    value.trim().parse::<T>().or(Err(invalid_input(format!("invalid {}: {:?}", name, value))))
+}
+
+impl FromStr for FlowControl {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<FlowControl, ()> {
+        match s {
+            "none" => Ok(FlowControl::None),
+            "software" => Ok(FlowControl::Software),
+            "hardware" => Ok(FlowControl::Hardware),
+            _ => Err(()),
+        }
+    }
+}
+
+impl FromStr for Parity {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Parity, ()> {
+        match s {
+            "none" => Ok(Parity::None),
+            "odd" => Ok(Parity::Odd),
+            "even" => Ok(Parity::Even),
+            _ => Err(()),
+        }
+    }
+}
+
+impl ModemConfig {
+    // Reads a JSON file. Settings missing from the file keep their
+    // defaults.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<ModemConfig> {
+        let mut contents = String::new();
+        try!(try!(File::open(path)).read_to_string(&mut contents));
+
+        serde_json::from_str(&contents).map_err(|e| invalid_input(format!("invalid modem config: {}", e)))
+    }
+
+    // Applies the AJI_GSM_PORT, AJI_GSM_BAUD_RATE, AJI_GSM_FLOW_CONTROL,
+    // AJI_GSM_PARITY, AJI_GSM_TIMEOUTS, AJI_GSM_INIT_COMMANDS and
+    // AJI_GSM_CALL_LOG environment variables on top of this
+    // configuration.
+    pub fn apply_env(&mut self) -> io::Result<()> {
+        if let Ok(port) = env::var("AJI_GSM_PORT") {
+            self.port = port;
+        }
+
+        if let Ok(baud_rate) = env::var("AJI_GSM_BAUD_RATE") {
+            self.baud_rate = try!(parse_setting("baud rate", &baud_rate));
+        }
+
+        if let Ok(flow_control) = env::var("AJI_GSM_FLOW_CONTROL") {
+            self.flow_control = try!(parse_setting("flow control", &flow_control));
+        }
+
+        if let Ok(parity) = env::var("AJI_GSM_PARITY") {
+            self.parity = try!(parse_setting("parity", &parity));
+        }
+
+        // e.g. AJI_GSM_TIMEOUTS="SendSMS=90000,ListSMS=30000"
+        if let Ok(timeouts) = env::var("AJI_GSM_TIMEOUTS") {
+            for timeout in timeouts.split(',').filter(|t| !t.trim().is_empty()) {
+                try!(self.set_timeout(timeout));
+            }
+        }
+
+        // AT commands can contain both ';' and ',', so they are
+        // separated with '|'.
+        if let Ok(commands) = env::var("AJI_GSM_INIT_COMMANDS") {
+            self.init_commands = commands.split('|')
+                .map(|c| c.trim().to_string())
+                .filter(|c| !c.is_empty())
+                .collect();
+        }
+
+        if let Ok(path) = env::var("AJI_GSM_CALL_LOG") {
+            self.call_log = Some(path);
+        }
+
+        Ok(())
+    }
+
+    // Overrides a timeout given as `<command type>=<milliseconds>`.
+    pub fn set_timeout(&mut self, setting: &str) -> io::Result<()> {
+        let mut parts = setting.splitn(2, '=');
+        let name = parts.next().unwrap_or("").trim();
+        let millis = parts.next().unwrap_or("");
+
+        // The command types are named the same way in JSON.
+        let command_type: CommandType = try!(serde_json::from_str(&format!("{:?}", name))
+                                             .map_err(|_| invalid_input(format!("unknown command type {:?}", name))));
+
+        self.timeouts.insert(command_type, try!(parse_setting("timeout", millis)));
+        Ok(())
+    }
+
+    pub fn set_flow_control(&mut self, flow_control: &str) -> io::Result<()> {
+        self.flow_control = try!(parse_setting("flow control", flow_control));
+        Ok(())
+    }
+
+    pub fn set_parity(&mut self, parity: &str) -> io::Result<()> {
+        self.parity = try!(parse_setting("parity", parity));
+        Ok(())
+    }
+
+    pub fn set_baud_rate(&mut self, baud_rate: &str) -> io::Result<()> {
+        self.baud_rate = try!(parse_setting("baud rate", baud_rate));
+        Ok(())
+    }
+
+    // The configured timeout for `command_type`, if it differs from
+    // the default.
+    pub fn timeout(&self, command_type: CommandType) -> Option<Duration> {
+        self.timeouts.get(&command_type).map(|&millis| Duration::from_millis(millis))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{FlowControl, ModemConfig};
+    use super::serde_json;
+    use gsm::command::CommandType;
+
+    #[test]
+    fn fills_in_missing_settings() {
+        let mut config: ModemConfig = serde_json::from_str(r#"{
+            "port": "/dev/ttyUSB2",
+            "flow_control": "hardware",
+            "timeouts": { "SendSMS": 90000 },
+            "init_commands": ["AT+CSCLK=0"]
+        }"#).unwrap();
+
+        assert_eq!(config.port, "/dev/ttyUSB2");
+        assert_eq!(config.baud_rate, 115200);
+        assert_eq!(config.flow_control, FlowControl::Hardware);
+        assert_eq!(config.timeout(CommandType::SendSMS), Some(Duration::from_millis(90000)));
+        assert_eq!(config.init_commands, vec!["AT+CSCLK=0".to_string()]);
+
+        config.set_timeout("ListSMS=30000").unwrap();
+        assert_eq!(config.timeout(CommandType::ListSMS), Some(Duration::from_millis(30000)));
+        assert!(config.set_timeout("Bogus=1").is_err());
+    }
+}
@@ -0,0 +1,51 @@
+// Who the modem is and which SIM is in it. The fleet tooling tells
+// devices apart by IMEI and ICCID, and the UI shows the rest on its
+// About screen.
+
+use gsm::command::{CommandResult, Pipeline};
+use gsm::errors::Error;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DeviceInfo {
+    pub manufacturer: String,
+    pub model: String,
+    pub revision: String,
+    pub imei: String,
+    // The SIM fields are missing when there is no SIM, when it is
+    // still locked, or (for the service provider) when the SIM simply
+    // doesn't carry a name.
+    pub imsi: Option<String>,
+    pub iccid: Option<String>,
+    pub service_provider: Option<String>,
+}
+
+// A value that only the SIM can answer for.
+fn optional(response: CommandResult<String>) -> Result<Option<String>, Error> {
+    match response.and_then(|r| r.wait()) {
+        Ok(value) => Ok(Some(value)),
+        Err(Error::CommandFailed(_)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn query_device_info(pipeline: &Pipeline) -> Result<DeviceInfo, Error> {
+    // Everything is queued up front so that the modem answers the
+    // whole batch in one go.
+    let manufacturer = pipeline.manufacturer_identification();
+    let model = pipeline.model_identification();
+    let revision = pipeline.revision_identification();
+    let imei = pipeline.serial_number();
+    let imsi = pipeline.subscriber_identity();
+    let iccid = pipeline.iccid();
+    let service_provider = pipeline.service_provider();
+
+    Ok(DeviceInfo {
+        manufacturer: try!(try!(manufacturer).wait()),
+        model: try!(try!(model).wait()),
+        revision: try!(try!(revision).wait()),
+        imei: try!(try!(imei).wait()),
+        imsi: try!(optional(imsi)),
+        iccid: try!(optional(iccid)),
+        service_provider: try!(optional(service_provider)),
+    })
+}
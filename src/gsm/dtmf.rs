@@ -0,0 +1,104 @@
+// Sends digits into a call as DTMF tones, for IVR menus and voicemail.
+// The tone length is set once with AT+VTD and every tone is its own
+// AT+VTS. A comma in the digits is a pause, as on a phone's dialer:
+// menus usually want a moment to start listening before the next
+// digit.
+
+use std::thread;
+use std::time::Duration;
+
+use gsm::call::CallState;
+use gsm::command::Pipeline;
+use gsm::errors::Error;
+
+// AT+VTD counts in tenths of a second, and only up to 255 of them.
+const TONE_DURATION_UNIT_MS: u32 = 100;
+const MAX_TONE_DURATION_UNITS: u32 = 255;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DtmfOptions {
+    // Rounded to tenths of a second.
+    pub tone_duration_ms: u32,
+    // How long each comma waits.
+    pub pause_ms: u32,
+}
+
+impl Default for DtmfOptions {
+    fn default() -> DtmfOptions {
+        DtmfOptions {
+            tone_duration_ms: 100,
+            pause_ms: 3000,
+        }
+    }
+}
+
+impl DtmfOptions {
+    // The <n> of AT+VTD, at least one tenth so that 0 doesn't select
+    // the modem's own default.
+    fn tone_duration_units(&self) -> u8 {
+        let units = (self.tone_duration_ms + TONE_DURATION_UNIT_MS / 2) / TONE_DURATION_UNIT_MS;
+        units.max(1).min(MAX_TONE_DURATION_UNITS) as u8
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DtmfStep {
+    Tone(char),
+    Pause,
+}
+
+// Splits `digits` into tones and pauses. The tones are 0-9, *, # and
+// A-D (which some menus use); anything else is refused before a single
+// tone has been sent.
+pub fn parse_digits(digits: &str) -> Result<Vec<DtmfStep>, Error> {
+    if digits.is_empty() {
+        return Err(Error::InvalidArgument);
+    }
+
+    digits.chars().map(|c| match c {
+        ',' => Ok(DtmfStep::Pause),
+        c if c.is_digit(10) || "*#ABCD".contains(c) => Ok(DtmfStep::Tone(c)),
+        _ => Err(Error::InvalidArgument),
+    }).collect()
+}
+
+// Plays `digits` into the active call. Fails with NoCall unless a call
+// is connected, since the modem would otherwise either refuse AT+VTS or
+// play the tones into nothing.
+pub fn send_dtmf(pipeline: &Pipeline, digits: &str, options: &DtmfOptions) -> Result<(), Error> {
+    let steps = try!(parse_digits(digits));
+
+    let calls = try!(try!(pipeline.list_calls()).wait());
+    if !calls.iter().any(|c| c.state == CallState::Active) {
+        return Err(Error::NoCall);
+    }
+
+    try!(try!(pipeline.set_tone_duration(options.tone_duration_units())).wait());
+
+    for step in steps.into_iter() {
+        match step {
+            DtmfStep::Tone(tone) => try!(try!(pipeline.dtmf_tone(tone)).wait()),
+            DtmfStep::Pause => thread::sleep(Duration::from_millis(options.pause_ms as u64)),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DtmfOptions, DtmfStep, parse_digits};
+
+    #[test]
+    fn parses_digits_and_pauses() {
+        assert_eq!(parse_digits("1,#A").unwrap(),
+                   vec![DtmfStep::Tone('1'), DtmfStep::Pause, DtmfStep::Tone('#'), DtmfStep::Tone('A')]);
+        assert!(parse_digits("12;ATH").is_err());
+        assert!(parse_digits("a").is_err());
+        assert!(parse_digits("").is_err());
+
+        let options = DtmfOptions { tone_duration_ms: 260, pause_ms: 0 };
+        assert_eq!(options.tone_duration_units(), 3);
+        assert_eq!(DtmfOptions { tone_duration_ms: 0, ..options }.tone_duration_units(), 1);
+    }
+}
@@ -0,0 +1,1253 @@
+// A software stand-in for a SIM800-class modem. It answers the subset
+// of the AT dialect that `command::Pipeline` emits, keeps a scriptable
+// SIM inbox of PDU messages and can inject unsolicited result codes,
+// so the rest of the stack can be exercised on a machine without the
+// radio attached.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::str;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use super::pdu;
+use super::transport::Transport;
+
+// How long the emulator thread blocks on the transport before checking
+// for injected events.
+const EMULATOR_POLL_MS: u64 = 20;
+
+const CTRL_Z: u8 = 0x1a;
+const ESCAPE: u8 = 0x1b;
+
+// CMS error reported for a message index that is not in the inbox.
+const CMS_INVALID_INDEX: u32 = 321;
+
+// CME errors for a locked SIM, a wrong PIN and a phonebook search
+// without results.
+const CME_SIM_PIN_REQUIRED: u32 = 11;
+const CME_INCORRECT_PASSWORD: u32 = 16;
+const CME_NOT_FOUND: u32 = 22;
+
+// Entries in the SIM phonebook.
+const PHONEBOOK_SIZE: u32 = 250;
+
+// Who calls when the emulator rings.
+const CALLER: &'static str = "+15551234567";
+
+const PIN_ATTEMPTS: u8 = 3;
+const PUK_ATTEMPTS: u8 = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ResultCode {
+    Ok = 0,
+    Ring = 2,
+    NoCarrier = 3,
+    Error = 4,
+    Busy = 7,
+}
+
+impl ResultCode {
+    fn verbose(&self) -> &'static str {
+        match *self {
+            ResultCode::Ok => "OK",
+            ResultCode::Ring => "RING",
+            ResultCode::NoCarrier => "NO CARRIER",
+            ResultCode::Error => "ERROR",
+            ResultCode::Busy => "BUSY",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct StoredMessage {
+    // Status as reported by AT+CMGL (0 = received unread, 1 = received
+    // read, 2 = stored unsent, 3 = stored sent).
+    pub status: u8,
+    pub pdu: String,
+}
+
+impl StoredMessage {
+    // The TPDU length reported alongside the PDU excludes the SMSC
+    // information at the front of the PDU.
+    fn tpdu_length(&self) -> usize {
+        let octets = self.pdu.len() / 2;
+        let smsc_length = self.pdu.get(0..2)
+            .and_then(|s| usize::from_str_radix(s, 16).ok())
+            .unwrap_or(0);
+
+        octets.saturating_sub(smsc_length + 1)
+    }
+}
+
+// The one call the emulator can have up at a time.
+#[derive(Clone, Debug)]
+struct EmulatedCall {
+    incoming: bool,
+    number: String,
+    // The <stat> of +CLCC.
+    state: u8,
+}
+
+pub enum Event {
+    // Store a message and announce it with +CMTI.
+    Deliver(String),
+    // Send an unsolicited line verbatim.
+    Unsolicited(String),
+    // Announce an incoming call, or ring again.
+    Ring,
+    // The other side of an outgoing call picks up.
+    RemoteAnswer,
+    // The other side of an outgoing call is busy.
+    RemoteBusy,
+    // The other side hangs up.
+    RemoteHangUp,
+    // Lose the configuration and print the power-on banners, as after
+    // a brown-out.
+    Reboot,
+    // Close the link, as a USB modem does when it is unplugged. Only
+    // a spawned emulator can.
+    Disconnect,
+}
+
+#[derive(Clone)]
+pub struct EmulatorHandle(mpsc::Sender<Event>);
+
+impl EmulatorHandle {
+    pub fn deliver(&self, pdu: String) -> Result<(), mpsc::SendError<Event>> {
+        self.0.send(Event::Deliver(pdu))
+    }
+
+    pub fn unsolicited(&self, line: String) -> Result<(), mpsc::SendError<Event>> {
+        self.0.send(Event::Unsolicited(line))
+    }
+
+    pub fn ring(&self) -> Result<(), mpsc::SendError<Event>> {
+        self.0.send(Event::Ring)
+    }
+
+    pub fn remote_answer(&self) -> Result<(), mpsc::SendError<Event>> {
+        self.0.send(Event::RemoteAnswer)
+    }
+
+    pub fn remote_busy(&self) -> Result<(), mpsc::SendError<Event>> {
+        self.0.send(Event::RemoteBusy)
+    }
+
+    pub fn remote_hang_up(&self) -> Result<(), mpsc::SendError<Event>> {
+        self.0.send(Event::RemoteHangUp)
+    }
+
+    pub fn reboot(&self) -> Result<(), mpsc::SendError<Event>> {
+        self.0.send(Event::Reboot)
+    }
+
+    pub fn disconnect(&self) -> Result<(), mpsc::SendError<Event>> {
+        self.0.send(Event::Disconnect)
+    }
+}
+
+pub struct Emulator {
+    echo: bool,
+    verbose: bool,
+    sms_mode: u8,
+    smsc: String,
+    signal: (u8, u8),
+    operator: String,
+    // 0 for automatic, 1 for manual selection.
+    operator_mode: u8,
+    // Registration status, location area and cell as reported by
+    // AT+CREG? and AT+CGREG?.
+    registration: (u8, u32, u32),
+    inbox: BTreeMap<u32, StoredMessage>,
+    sent: Vec<String>,
+    next_reference: u8,
+
+    // The SIM's PIN lock. The PUK is always 12345678.
+    pin: String,
+    pin_enabled: bool,
+    sim_unlocked: bool,
+    pin_attempts: u8,
+    puk_attempts: u8,
+
+    call: Option<EmulatedCall>,
+    // Whether RING is followed by +CLIP (AT+CLIP=1).
+    caller_id: bool,
+    tones: Vec<char>,
+
+    // Voice call forwarding by <reason>: the number and, for no reply,
+    // how long to ring first.
+    forwarding: BTreeMap<u32, (String, Option<u32>)>,
+    call_waiting: bool,
+    // The <n> of AT+CLIR.
+    clir: u32,
+    // Whether the USSD menu is waiting for a choice.
+    ussd_menu: bool,
+    // The SIM phonebook: number and name by index.
+    phonebook: BTreeMap<u32, (String, String)>,
+    // The character set of AT+CSCS. Phonebook names are sent in it.
+    charset: String,
+    // Unsolicited lines that follow the result code of the command
+    // that caused them, such as the network's answer to AT+CUSD.
+    deferred: Vec<String>,
+
+    // The answers to ATI and AT+CGMM.
+    identification: (String, String),
+
+    // Set while AT+CMGS is waiting for its PDU after the prompt.
+    pending_submit: bool,
+    input: Vec<u8>,
+}
+
+impl Emulator {
+    pub fn new() -> Emulator {
+        // Power-on defaults of a real modem: echo on, verbose codes.
+        Emulator {
+            echo: true,
+            verbose: true,
+            sms_mode: 0,
+            smsc: "+12063130004".to_string(),
+            signal: (20, 0),
+            operator: "T-Mobile".to_string(),
+            operator_mode: 0,
+            registration: (1, 0x1A2B, 0x00C3F1),
+            inbox: BTreeMap::new(),
+            sent: Vec::new(),
+            next_reference: 0,
+            pin: "1234".to_string(),
+            pin_enabled: false,
+            sim_unlocked: true,
+            pin_attempts: PIN_ATTEMPTS,
+            puk_attempts: PUK_ATTEMPTS,
+            call: None,
+            caller_id: false,
+            tones: Vec::new(),
+            forwarding: BTreeMap::new(),
+            call_waiting: false,
+            clir: 0,
+            ussd_menu: false,
+            phonebook: BTreeMap::new(),
+            charset: "IRA".to_string(),
+            deferred: Vec::new(),
+            // The modem on the production board.
+            identification: ("SIM800 R14.18".to_string(), "SIMCOM_SIM800L".to_string()),
+            pending_submit: false,
+            input: Vec::new(),
+        }
+    }
+
+    // Adds a message to the SIM and returns its index.
+    pub fn store(&mut self, message: StoredMessage) -> u32 {
+        let index = self.inbox.keys().next_back().map(|i| i + 1).unwrap_or(1);
+        self.inbox.insert(index, message);
+        index
+    }
+
+    // Loads an inbox script. Each non-empty line that is not a `#`
+    // comment is either `<pdu>` (stored as unread) or
+    // `<status> <pdu>`.
+    pub fn load_inbox(&mut self, path: &str) -> io::Result<()> {
+        let reader = BufReader::new(try!(File::open(path)));
+
+        for line in reader.lines() {
+            let line = try!(line);
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut parts = line.split_whitespace();
+            let message = match (parts.next(), parts.next()) {
+                (Some(pdu), None) => StoredMessage { status: 0, pdu: pdu.to_string() },
+                (Some(status), Some(pdu)) => match status.parse::<u8>() {
+                    Ok(s) if s <= 3 => StoredMessage { status: s, pdu: pdu.to_string() },
+                    _ => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                   format!("bad message status in inbox: {}", line))),
+                },
+                (None, _) => continue,
+            };
+
+            self.store(message);
+        }
+
+        Ok(())
+    }
+
+    pub fn set_signal(&mut self, rssi: u8, ber: u8) {
+        self.signal = (rssi, ber);
+    }
+
+    // Protects the SIM with `pin`, so that it has to be unlocked with
+    // AT+CPIN before it answers SMS commands.
+    pub fn lock_sim(&mut self, pin: String) {
+        self.pin = pin;
+        self.pin_enabled = true;
+        self.sim_unlocked = false;
+    }
+
+    pub fn set_operator(&mut self, operator: String) {
+        self.operator = operator;
+    }
+
+    // Answers ATI with `text` and AT+CGMM with `model`, to pass for
+    // another modem.
+    pub fn set_identification(&mut self, text: String, model: String) {
+        self.identification = (text, model);
+    }
+
+    // DTMF tones played with AT+VTS, in order.
+    pub fn dtmf_tones(&self) -> &[char] {
+        &self.tones
+    }
+
+    // PDUs submitted with AT+CMGS, in the order they were sent.
+    pub fn sent_messages(&self) -> &[String] {
+        &self.sent
+    }
+
+    // Runs the emulator on its own thread, speaking over `transport`.
+    pub fn spawn<T: Transport + 'static>(self, mut transport: T)
+                                         -> io::Result<(EmulatorHandle, thread::JoinHandle<io::Result<()>>)> {
+        let (send, recv) = mpsc::channel::<Event>();
+        try!(transport.set_timeout(Duration::from_millis(EMULATOR_POLL_MS)));
+
+        let mut emulator = self;
+        let handle = try!(thread::Builder::new().name("aji/gsm_emulator".to_string()).spawn(
+            move || {
+                let mut buffer = [0u8; 256];
+
+                loop {
+                    match transport.read(&mut buffer) {
+                        Ok(0) => return Ok(()),
+                        Ok(n) => {
+                            let output = emulator.receive(&buffer[..n]);
+                            try!(transport.write_all(&output));
+                        },
+                        Err(e) => if e.kind() != io::ErrorKind::TimedOut {
+                            return Err(e);
+                        }
+                    }
+
+                    loop {
+                        match recv.try_recv() {
+                            Ok(Event::Disconnect) => return Ok(()),
+                            Ok(event) => {
+                                let output = emulator.handle_event(event);
+                                try!(transport.write_all(&output));
+                            },
+                            Err(mpsc::TryRecvError::Empty) => break,
+                            // Nobody can inject events any more, but
+                            // keep answering commands.
+                            Err(mpsc::TryRecvError::Disconnected) => break,
+                        }
+                    }
+                }
+            }));
+
+        Ok((EmulatorHandle(send), handle))
+    }
+
+    // Feeds bytes written by the host into the emulator and returns
+    // everything the modem would write back.
+    pub fn receive(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut output = Vec::new();
+
+        for &byte in data.iter() {
+            if self.pending_submit {
+                match byte {
+                    CTRL_Z => {
+                        let pdu = String::from_utf8_lossy(&self.input).into_owned();
+                        self.input.clear();
+                        self.pending_submit = false;
+                        self.submit(pdu, &mut output);
+                    },
+                    ESCAPE => {
+                        self.input.clear();
+                        self.pending_submit = false;
+                        self.result(ResultCode::Ok, &mut output);
+                    },
+                    b'\r' | b'\n' => {},
+                    b => self.input.push(b),
+                }
+
+                continue;
+            }
+
+            match byte {
+                b'\r' => {
+                    let line = String::from_utf8_lossy(&self.input).into_owned();
+                    self.input.clear();
+
+                    if self.echo {
+                        output.extend(line.as_bytes());
+                        output.push(b'\r');
+                    }
+
+                    let line = line.trim();
+                    if !line.is_empty() {
+                        self.command(line, &mut output);
+                    }
+                },
+                b'\n' => {},
+                b => self.input.push(b),
+            }
+        }
+
+        output
+    }
+
+    pub fn handle_event(&mut self, event: Event) -> Vec<u8> {
+        let mut output = Vec::new();
+
+        match event {
+            Event::Deliver(pdu) => {
+                let index = self.store(StoredMessage { status: 0, pdu: pdu });
+                self.information(&format!("+CMTI: \"SM\",{}", index), &mut output);
+            },
+            Event::Unsolicited(line) => self.information(&line, &mut output),
+            Event::Ring => {
+                if self.call.is_none() {
+                    self.call = Some(EmulatedCall { incoming: true, number: CALLER.to_string(), state: 4 });
+                }
+
+                self.result(ResultCode::Ring, &mut output);
+                if self.caller_id {
+                    self.information(&format!("+CLIP: \"{}\",145,\"\",0,\"\",0", CALLER), &mut output);
+                }
+            },
+            Event::RemoteAnswer => {
+                if let Some(ref mut call) = self.call {
+                    if !call.incoming {
+                        call.state = 0;
+                    }
+                }
+            },
+            Event::RemoteBusy => {
+                if self.call.as_ref().map_or(false, |c| !c.incoming && c.state != 0) {
+                    self.call = None;
+                    self.result(ResultCode::Busy, &mut output);
+                }
+            },
+            Event::RemoteHangUp => {
+                if self.call.take().is_some() {
+                    self.result(ResultCode::NoCarrier, &mut output);
+                }
+            },
+            Event::Reboot => {
+                self.echo = true;
+                self.verbose = true;
+                self.pending_submit = false;
+                self.input.clear();
+
+                for banner in ["RDY", "Call Ready", "SMS Ready"].iter() {
+                    self.information(banner, &mut output);
+                }
+            },
+            // There is no link to close outside of `spawn`.
+            Event::Disconnect => {},
+        }
+
+        output
+    }
+
+    fn information(&self, text: &str, output: &mut Vec<u8>) {
+        if self.verbose {
+            output.extend(b"\r\n");
+        }
+
+        output.extend(text.as_bytes());
+        output.extend(b"\r\n");
+    }
+
+    fn result(&self, code: ResultCode, output: &mut Vec<u8>) {
+        if self.verbose {
+            output.extend(format!("\r\n{}\r\n", code.verbose()).as_bytes());
+        } else {
+            output.extend(format!("{}\r", code as i32).as_bytes());
+        }
+    }
+
+    fn cms_error(&self, code: u32, output: &mut Vec<u8>) {
+        if self.verbose {
+            output.extend(format!("\r\n+CMS ERROR: {}\r\n", code).as_bytes());
+        } else {
+            output.extend(format!("+CMS ERROR: {}\r\n", code).as_bytes());
+        }
+    }
+
+    fn cme_error(&self, code: u32, output: &mut Vec<u8>) {
+        if self.verbose {
+            output.extend(format!("\r\n+CME ERROR: {}\r\n", code).as_bytes());
+        } else {
+            output.extend(format!("+CME ERROR: {}\r\n", code).as_bytes());
+        }
+    }
+
+    fn sim_state(&self) -> &'static str {
+        if self.sim_unlocked {
+            "READY"
+        } else if self.pin_attempts > 0 {
+            "SIM PIN"
+        } else {
+            "SIM PUK"
+        }
+    }
+
+    // Checks `pin` against the SIM's PIN, using up an attempt if it is
+    // wrong.
+    fn check_pin(&mut self, pin: &str) -> bool {
+        if self.pin_attempts > 0 && pin == self.pin {
+            self.pin_attempts = PIN_ATTEMPTS;
+            true
+        } else {
+            self.pin_attempts = self.pin_attempts.saturating_sub(1);
+            false
+        }
+    }
+
+    // AT+CPIN="<pin>" or AT+CPIN="<puk>","<new pin>".
+    fn enter_pin(&mut self, arguments: &[String]) -> bool {
+        match (arguments.get(0), arguments.get(1)) {
+            (Some(pin), None) if self.sim_state() == "SIM PIN" => {
+                self.sim_unlocked = self.check_pin(pin);
+                self.sim_unlocked
+            },
+            (Some(puk), Some(new_pin)) if self.sim_state() == "SIM PUK" => {
+                if self.puk_attempts > 0 && puk == "12345678" {
+                    self.pin = new_pin.clone();
+                    self.pin_attempts = PIN_ATTEMPTS;
+                    self.puk_attempts = PUK_ATTEMPTS;
+                    self.sim_unlocked = true;
+                    true
+                } else {
+                    self.puk_attempts = self.puk_attempts.saturating_sub(1);
+                    false
+                }
+            },
+            _ => false,
+        }
+    }
+
+    fn command(&mut self, line: &str, output: &mut Vec<u8>) {
+        // Several commands can share a line (AT+CSCS="UCS2";+CPBR=1).
+        // They run in order until one fails, and the line gets a
+        // single result code.
+        let mut last = (String::new(), ResultCode::Ok);
+        for upper in split_commands(&line.to_uppercase()).into_iter() {
+            // A locked SIM only answers the PIN commands.
+            if !self.sim_unlocked && (upper.starts_with("AT+CMG") || upper.starts_with("AT+CIMI") ||
+                                      upper.starts_with("AT+CPB")) {
+                self.cme_error(CME_SIM_PIN_REQUIRED, output);
+                return;
+            }
+
+            let code = self.execute(&upper, output);
+            last = (upper, code);
+            if code != ResultCode::Ok {
+                break;
+            }
+        }
+
+        let (upper, code) = last;
+
+        // AT+CMGS answers with a prompt instead of a result code.
+        if !self.pending_submit {
+            match code {
+                ResultCode::Error if upper.starts_with("AT+CMGR=") => {
+                    self.cms_error(CMS_INVALID_INDEX, output)
+                },
+                ResultCode::Error if upper.starts_with("AT+CPIN=") || upper.starts_with("AT+CLCK=") ||
+                    upper.starts_with("AT+CPWD=") => {
+                    self.cme_error(CME_INCORRECT_PASSWORD, output)
+                },
+                ResultCode::Error if upper.starts_with("AT+CPBF=") => self.cme_error(CME_NOT_FOUND, output),
+                c => self.result(c, output),
+            }
+        }
+
+        for line in self.deferred.drain(..).collect::<Vec<_>>() {
+            self.information(&line, output);
+        }
+    }
+
+    fn execute(&mut self, upper: &str, output: &mut Vec<u8>) -> ResultCode {
+        match upper {
+            "AT" => ResultCode::Ok,
+            "ATE0" => { self.echo = false; ResultCode::Ok },
+            "ATE1" => { self.echo = true; ResultCode::Ok },
+            "ATV0" => { self.verbose = false; ResultCode::Ok },
+            "ATV1" => { self.verbose = true; ResultCode::Ok },
+            "ATH" => {
+                self.call = None;
+                ResultCode::Ok
+            },
+            "ATA" => match self.call {
+                Some(ref mut call) if call.incoming && call.state == 4 => {
+                    call.state = 0;
+                    ResultCode::Ok
+                },
+                _ => ResultCode::NoCarrier,
+            },
+            "AT+CLCC" => {
+                if let Some(call) = self.call.clone() {
+                    self.information(&format!("+CLCC: 1,{},{},0,0,\"{}\",{}", call.incoming as u8, call.state,
+                                              call.number, if call.number.starts_with('+') { 145 } else { 129 }),
+                                     output);
+                }
+
+                // The other phone starts ringing right after dialing.
+                if let Some(ref mut call) = self.call {
+                    if call.state == 2 {
+                        call.state = 3;
+                    }
+                }
+
+                ResultCode::Ok
+            },
+            "AT+CLIP=0" | "AT+CLIP=1" => {
+                self.caller_id = upper == "AT+CLIP=1";
+                ResultCode::Ok
+            },
+            "AT+CCWA=1,2" => {
+                if self.call_waiting {
+                    self.information("+CCWA: 1,1", output);
+                } else {
+                    self.information("+CCWA: 0,7", output);
+                }
+                ResultCode::Ok
+            },
+            "AT+CLIR?" => {
+                // The subscription shows the number unless asked not to.
+                self.information(&format!("+CLIR: {},4", self.clir), output);
+                ResultCode::Ok
+            },
+            // There is only ever one call, so nothing is held when it
+            // is active and vice versa.
+            "AT+CHLD=0" => ResultCode::Ok,
+            "AT+CHLD=1" => {
+                if self.call.as_ref().map_or(false, |c| c.state == 0 || c.state == 1) {
+                    self.call = None;
+                    ResultCode::Ok
+                } else {
+                    ResultCode::Error
+                }
+            },
+            "AT+CHLD=2" => match self.call {
+                Some(ref mut call) if call.state == 0 || call.state == 1 => {
+                    call.state = 1 - call.state;
+                    ResultCode::Ok
+                },
+                _ => ResultCode::Error,
+            },
+            "ATI" => {
+                self.information(&self.identification.0, output);
+                ResultCode::Ok
+            },
+            "AT+CGMM" => {
+                self.information(&self.identification.1, output);
+                ResultCode::Ok
+            },
+            "AT+CGMI" => {
+                self.information("SIMCOM_Ltd", output);
+                ResultCode::Ok
+            },
+            "AT+CGMR" => {
+                self.information("Revision:1418B04SIM800L24", output);
+                ResultCode::Ok
+            },
+            "AT+GSN" => {
+                self.information("869170031234567", output);
+                ResultCode::Ok
+            },
+            "AT+CIMI" => {
+                self.information("310260123456789", output);
+                ResultCode::Ok
+            },
+            "AT+CCID" => {
+                self.information("8901260123456789012", output);
+                ResultCode::Ok
+            },
+            "AT+CSPN?" => {
+                self.information("+CSPN: \"T-Mobile\",0", output);
+                ResultCode::Ok
+            },
+            "AT+CREG?" | "AT+CGREG?" => {
+                let (status, lac, cell_id) = self.registration;
+                let prefix = if upper == "AT+CREG?" { "+CREG" } else { "+CGREG" };
+                self.information(&format!("{}: 2,{},\"{:04X}\",\"{:08X}\"", prefix, status, lac, cell_id), output);
+                ResultCode::Ok
+            },
+            "AT+CREG=2" | "AT+CGREG=2" => ResultCode::Ok,
+            // Always on GSM.
+            "AT+CNSMOD?" => {
+                self.information("+CNSMOD: 0,1", output);
+                ResultCode::Ok
+            },
+            "AT+CPIN?" => {
+                self.information(&format!("+CPIN: {}", self.sim_state()), output);
+                ResultCode::Ok
+            },
+            "AT+CLCK=\"SC\",2" => {
+                self.information(&format!("+CLCK: {}", self.pin_enabled as u8), output);
+                ResultCode::Ok
+            },
+            "AT+SPIC" => {
+                self.information(&format!("+SPIC: {},3,{},10", self.pin_attempts, self.puk_attempts), output);
+                ResultCode::Ok
+            },
+            "AT+CLTS=1" => ResultCode::Ok,
+            "AT+CMGF?" => {
+                self.information(&format!("+CMGF: {}", self.sms_mode), output);
+                ResultCode::Ok
+            },
+            "AT+CSCA?" => {
+                self.information(&format!("+CSCA: \"{}\",145", self.smsc), output);
+                ResultCode::Ok
+            },
+            "AT+CSQ" => {
+                self.information(&format!("+CSQ: {},{}", self.signal.0, self.signal.1), output);
+                ResultCode::Ok
+            },
+            "AT+COPS?" => {
+                self.information(&format!("+COPS: {},0,\"{}\"", self.operator_mode, self.operator), output);
+                ResultCode::Ok
+            },
+            "AT+COPS=?" => {
+                self.information(&format!("+COPS: (2,\"{}\",\"{}\",\"310260\"),,(0-4),(0-2)",
+                                          self.operator, self.operator), output);
+                ResultCode::Ok
+            },
+            "AT+COPS=0" => {
+                self.operator_mode = 0;
+                ResultCode::Ok
+            },
+            "AT+CSCS?" => {
+                self.information(&format!("+CSCS: \"{}\"", self.charset), output);
+                ResultCode::Ok
+            },
+            // The fixed dialing numbers need the PIN2, which nobody
+            // knows.
+            "AT+CPBS=?" => {
+                self.information("+CPBS: (\"SM\",\"FD\")", output);
+                ResultCode::Ok
+            },
+            "AT+CPBS=\"SM\"" => ResultCode::Ok,
+            "AT+CPBS?" => {
+                self.information(&format!("+CPBS: \"SM\",{},{}", self.phonebook.len(), PHONEBOOK_SIZE), output);
+                ResultCode::Ok
+            },
+            "AT+CPBR=?" => {
+                self.information(&format!("+CPBR: (1-{}),40,14", PHONEBOOK_SIZE), output);
+                ResultCode::Ok
+            },
+            _ => self.parameter_command(upper, output),
+        }
+    }
+
+    fn parameter_command(&mut self, upper: &str, output: &mut Vec<u8>) -> ResultCode {
+        if let Some(mode) = parameter(upper, "AT+CMGF=") {
+            if mode > 1 {
+                return ResultCode::Error;
+            }
+
+            self.sms_mode = mode as u8;
+            ResultCode::Ok
+        } else if let Some(status) = parameter(upper, "AT+CMGL=") {
+            if status > 4 {
+                return ResultCode::Error;
+            }
+
+            for (index, message) in self.inbox.iter_mut() {
+                if status != 4 && message.status as u32 != status {
+                    continue;
+                }
+
+                let header = format!("+CMGL: {},{},,{}", index, message.status, message.tpdu_length());
+                if self.verbose {
+                    output.extend(b"\r\n");
+                }
+                output.extend(format!("{}\r\n{}\r\n", header, message.pdu).as_bytes());
+
+                // Listing a message marks it as read.
+                if message.status == 0 {
+                    message.status = 1;
+                }
+            }
+
+            ResultCode::Ok
+        } else if let Some(index) = parameter(upper, "AT+CMGR=") {
+            let response = match self.inbox.get_mut(&index) {
+                Some(message) => {
+                    let response = format!("+CMGR: {},,{}\r\n{}", message.status, message.tpdu_length(), message.pdu);
+                    if message.status == 0 {
+                        message.status = 1;
+                    }
+                    response
+                },
+                None => return ResultCode::Error,
+            };
+
+            self.information(&response, output);
+            ResultCode::Ok
+        } else if let Some(index) = parameter(upper, "AT+CMGD=") {
+            self.inbox.remove(&index);
+            ResultCode::Ok
+        } else if upper.starts_with("AT+CMGS=") {
+            if self.sms_mode != 0 || parameter(upper, "AT+CMGS=").is_none() {
+                return ResultCode::Error;
+            }
+
+            self.pending_submit = true;
+            output.extend(b"\r\n> ");
+            ResultCode::Ok
+        } else if upper.starts_with("ATD") && upper.ends_with(';') {
+            if self.call.is_some() {
+                return ResultCode::Error;
+            }
+
+            // The call is answered, refused or ended with the Remote
+            // events.
+            self.call = Some(EmulatedCall {
+                incoming: false,
+                number: upper[3..upper.len() - 1].to_string(),
+                state: 2,
+            });
+            ResultCode::Ok
+        } else if upper.starts_with("AT+COPS=1,2,") {
+            // Only the one network is in range.
+            match quoted_arguments(upper).first() {
+                Some(numeric) if numeric == "310260" => {
+                    self.operator_mode = 1;
+                    ResultCode::Ok
+                },
+                _ => ResultCode::Error,
+            }
+        } else if upper.starts_with("AT+CPIN=") {
+            if self.enter_pin(&quoted_arguments(&upper["AT+CPIN=".len()..])) {
+                ResultCode::Ok
+            } else {
+                ResultCode::Error
+            }
+        } else if upper.starts_with("AT+CLCK=\"SC\",") {
+            // AT+CLCK="SC",<mode>,"<pin>"
+            let arguments = quoted_arguments(&upper["AT+CLCK=\"SC\",".len()..]);
+            let mode = upper["AT+CLCK=\"SC\",".len()..].chars().next();
+            match (mode, arguments.get(0)) {
+                (Some(mode), Some(pin)) if mode == '0' || mode == '1' => {
+                    if !self.sim_unlocked || !self.check_pin(pin) {
+                        return ResultCode::Error;
+                    }
+
+                    self.pin_enabled = mode == '1';
+                    ResultCode::Ok
+                },
+                _ => ResultCode::Error,
+            }
+        } else if upper.starts_with("AT+CPWD=\"SC\",") {
+            let arguments = quoted_arguments(&upper["AT+CPWD=\"SC\",".len()..]);
+            match (arguments.get(0), arguments.get(1)) {
+                (Some(old_pin), Some(new_pin)) if self.sim_unlocked => {
+                    if !self.check_pin(old_pin) {
+                        return ResultCode::Error;
+                    }
+
+                    self.pin = new_pin.clone();
+                    ResultCode::Ok
+                },
+                _ => ResultCode::Error,
+            }
+        } else if upper.starts_with("AT+VTS=") {
+            let tone = &upper["AT+VTS=".len()..];
+            let connected = self.call.as_ref().map_or(false, |c| c.state == 0);
+
+            // Tones only go into a connected call.
+            if tone.len() != 1 || !"0123456789*#ABCD".contains(tone) || !connected {
+                return ResultCode::Error;
+            }
+
+            self.tones.extend(tone.chars());
+            ResultCode::Ok
+        } else if let Some(tenths) = parameter(upper, "AT+VTD=") {
+            if tenths > 255 {
+                return ResultCode::Error;
+            }
+
+            ResultCode::Ok
+        } else if upper.starts_with("AT+CCFC=") {
+            self.call_forwarding(&upper["AT+CCFC=".len()..], output)
+        } else if upper.starts_with("AT+CCWA=1,") {
+            // AT+CCWA=1,<mode>[,<class>]
+            match upper["AT+CCWA=1,".len()..].split(',').next() {
+                Some("0") => self.call_waiting = false,
+                Some("1") => self.call_waiting = true,
+                _ => return ResultCode::Error,
+            }
+
+            ResultCode::Ok
+        } else if let Some(presentation) = parameter(upper, "AT+CLIR=") {
+            if presentation > 2 {
+                return ResultCode::Error;
+            }
+
+            self.clir = presentation;
+            ResultCode::Ok
+        } else if upper.starts_with("AT+CUSD=") {
+            self.ussd(&upper["AT+CUSD=".len()..])
+        } else if upper.starts_with("AT+CSCS=") {
+            match quoted_arguments(upper).first() {
+                Some(charset) if charset == "IRA" || charset == "GSM" || charset == "UCS2" => {
+                    self.charset = charset.clone();
+                    ResultCode::Ok
+                },
+                _ => ResultCode::Error,
+            }
+        } else if upper.starts_with("AT+CPBR=") {
+            let mut range = upper["AT+CPBR=".len()..].split(',').map(|i| i.parse::<u32>().ok());
+            let (first, last) = match (range.next(), range.next()) {
+                (Some(Some(first)), None) => (first, first),
+                (Some(Some(first)), Some(Some(last))) if first <= last => (first, last),
+                _ => return ResultCode::Error,
+            };
+
+            let entries: Vec<_> = self.phonebook.range(first..last + 1).map(|(&i, e)| (i, e.clone())).collect();
+            for (index, entry) in entries.into_iter() {
+                self.phonebook_entry("+CPBR", index, &entry, output);
+            }
+
+            ResultCode::Ok
+        } else if upper.starts_with("AT+CPBF=") {
+            let name = match quoted_arguments(upper).first().and_then(|n| self.decode_text(n)) {
+                Some(name) => name.to_uppercase(),
+                None => return ResultCode::Error,
+            };
+
+            let entries: Vec<_> = self.phonebook.iter()
+                .filter(|&(_, &(_, ref n))| n.to_uppercase().starts_with(&name))
+                .map(|(&i, e)| (i, e.clone()))
+                .collect();
+            if entries.is_empty() {
+                return ResultCode::Error;
+            }
+
+            for (index, entry) in entries.into_iter() {
+                self.phonebook_entry("+CPBF", index, &entry, output);
+            }
+
+            ResultCode::Ok
+        } else if upper.starts_with("AT+CPBW=") {
+            self.write_phonebook(&upper["AT+CPBW=".len()..])
+        } else if upper.starts_with("AT+CNMI=") {
+            ResultCode::Ok
+        } else if let Some(mode) = parameter(upper, "AT+CMEE=") {
+            if mode > 2 {
+                return ResultCode::Error;
+            }
+
+            ResultCode::Ok
+        } else {
+            ResultCode::Error
+        }
+    }
+
+    // <reason>,<mode>[,"<number>",<type>[,<class>[,<subaddr>,<satype>[,<time>]]]]
+    fn call_forwarding(&mut self, parameters: &str, output: &mut Vec<u8>) -> ResultCode {
+        let fields: Vec<&str> = parameters.split(',').collect();
+        let reason = match fields[0].parse::<u32>() {
+            Ok(reason) if reason <= 3 => reason,
+            _ => return ResultCode::Error,
+        };
+
+        match fields.get(1).cloned() {
+            Some("2") => {
+                let status = match self.forwarding.get(&reason) {
+                    Some(&(ref number, Some(time))) => format!("+CCFC: 1,1,\"{}\",145,,,{}", number, time),
+                    Some(&(ref number, None)) => format!("+CCFC: 1,1,\"{}\",145", number),
+                    None => "+CCFC: 0,7".to_string(),
+                };
+
+                self.information(&status, output);
+                ResultCode::Ok
+            },
+            Some("3") => {
+                let number = match quoted_arguments(parameters).first() {
+                    Some(number) if !number.is_empty() => number.clone(),
+                    _ => return ResultCode::Error,
+                };
+
+                let time = fields.get(7).and_then(|t| t.parse().ok());
+                self.forwarding.insert(reason, (number, time));
+                ResultCode::Ok
+            },
+            Some("4") => {
+                self.forwarding.remove(&reason);
+                ResultCode::Ok
+            },
+            _ => ResultCode::Error,
+        }
+    }
+
+    // <n>[,"<str>"[,<dcs>]]. *100# answers with the balance and *111#
+    // opens a menu; UCS2 answers are sent as hex.
+    fn ussd(&mut self, parameters: &str) -> ResultCode {
+        let request = quoted_arguments(parameters).into_iter().next();
+
+        let (status, message, dcs) = match (parameters.chars().next(), request) {
+            (Some('2'), _) => {
+                self.ussd_menu = false;
+                return ResultCode::Ok;
+            },
+            (Some('0'), None) | (Some('1'), None) => return ResultCode::Ok,
+            (Some('1'), Some(request)) => match (self.ussd_menu, request.as_ref()) {
+                (false, "*100#") | (true, "1") => (0, "Your balance is 5.00 USD".to_string(), 15),
+                (false, "*111#") => (1, "1 Balance 2 Top up".to_string(), 15),
+                // "Top up: ¥500"
+                (true, "2") => (0, "0054006F0070002000750070003A002000A5003500300030".to_string(), 72),
+                (true, _) => (1, "Invalid choice. 1 Balance 2 Top up".to_string(), 15),
+                (false, _) => {
+                    self.deferred.push("+CUSD: 4".to_string());
+                    return ResultCode::Ok;
+                },
+            },
+            _ => return ResultCode::Error,
+        };
+
+        self.ussd_menu = status == 1;
+        self.deferred.push(format!("+CUSD: {},\"{}\",{}", status, message, dcs));
+        ResultCode::Ok
+    }
+
+    fn decode_text(&self, text: &str) -> Option<String> {
+        if self.charset == "UCS2" {
+            pdu::decode_ucs2(text).ok()
+        } else {
+            Some(text.to_string())
+        }
+    }
+
+    fn phonebook_entry(&self, prefix: &str, index: u32, entry: &(String, String), output: &mut Vec<u8>) {
+        // Like a SIMCom modem, the numbers are converted along with
+        // the names.
+        let &(ref number, ref name) = entry;
+        let (encoded_number, name) = if self.charset == "UCS2" {
+            (pdu::encode_ucs2(number), pdu::encode_ucs2(name))
+        } else {
+            (number.clone(), name.clone())
+        };
+
+        self.information(&format!("{}: {},\"{}\",{},\"{}\"", prefix, index, encoded_number,
+                                  if number.starts_with('+') { 145 } else { 129 }, name), output);
+    }
+
+    // <index> to delete, or [<index>],"<number>",<type>,"<name>".
+    fn write_phonebook(&mut self, parameters: &str) -> ResultCode {
+        let index = match parameters.split(',').next() {
+            Some("") => (1..PHONEBOOK_SIZE + 1).find(|i| !self.phonebook.contains_key(i)),
+            Some(index) => index.parse::<u32>().ok().and_then(|i| {
+                if i >= 1 && i <= PHONEBOOK_SIZE { Some(i) } else { None }
+            }),
+            None => None,
+        };
+
+        let index = match index {
+            Some(index) => index,
+            None => return ResultCode::Error,
+        };
+
+        let arguments = quoted_arguments(parameters);
+        let number = arguments.get(0).map(|n| self.decode_text(n));
+        match (number, arguments.get(1).and_then(|n| self.decode_text(n))) {
+            (None, _) => { self.phonebook.remove(&index); },
+            (Some(Some(number)), Some(name)) => { self.phonebook.insert(index, (number, name)); },
+            (Some(_), _) => return ResultCode::Error,
+        }
+
+        ResultCode::Ok
+    }
+
+    fn submit(&mut self, pdu: String, output: &mut Vec<u8>) {
+        let reference = self.next_reference;
+        self.next_reference = self.next_reference.wrapping_add(1);
+        self.sent.push(pdu);
+
+        self.information(&format!("+CMGS: {}", reference), output);
+        self.result(ResultCode::Ok, output);
+    }
+}
+
+fn parameter(command: &str, prefix: &str) -> Option<u32> {
+    if command.starts_with(prefix) {
+        command[prefix.len()..].trim().parse::<u32>().ok()
+    } else {
+        None
+    }
+}
+
+// Splits AT+A;+B into AT+A and AT+B. The ; that ends a dial string
+// stays where it is.
+fn split_commands(line: &str) -> Vec<String> {
+    let mut commands = Vec::new();
+    let mut quoted = false;
+    let mut start = 0;
+
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ';' if !quoted && line[i + 1..].starts_with('+') => {
+                commands.push(line[start..i].to_string());
+                start = i + 1;
+            },
+            _ => {},
+        }
+    }
+
+    commands.push(line[start..].to_string());
+
+    // Every command after the first is written without the AT.
+    for command in commands.iter_mut().skip(1) {
+        *command = format!("AT{}", command);
+    }
+
+    commands
+}
+
+// The quoted strings in a parameter list, in order.
+fn quoted_arguments(parameters: &str) -> Vec<String> {
+    parameters.split('"')
+        .enumerate()
+        .filter(|&(i, _)| i % 2 == 1)
+        .map(|(_, argument)| argument.to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Emulator, Event, StoredMessage};
+
+    const PDU: &'static str = "07911326040000F0040B911346610089F60000208062917314080CC8F71D14969741F977FD07";
+
+    // +15551234567 in UCS2.
+    const UCS2_NUMBER: &'static str = "002B00310035003500350031003200330034003500360037";
+
+    fn send(emulator: &mut Emulator, data: &str) -> String {
+        String::from_utf8(emulator.receive(data.as_bytes())).unwrap()
+    }
+
+    #[test]
+    fn echoes_until_disabled() {
+        let mut emulator = Emulator::new();
+        assert_eq!(send(&mut emulator, "AT\r"), "AT\r\r\nOK\r\n");
+        assert_eq!(send(&mut emulator, "ATE0\r"), "ATE0\r\r\nOK\r\n");
+        assert_eq!(send(&mut emulator, "ATV0\r"), "0\r");
+        assert_eq!(send(&mut emulator, "AT+FOO\r"), "4\r");
+    }
+
+    #[test]
+    fn lists_the_inbox() {
+        let mut emulator = Emulator::new();
+        emulator.store(StoredMessage { status: 0, pdu: PDU.to_string() });
+        send(&mut emulator, "ATE0\rATV0\r");
+
+        assert_eq!(send(&mut emulator, "AT+CMGL=4\r"), format!("+CMGL: 1,0,,30\r\n{}\r\n0\r", PDU));
+        assert_eq!(send(&mut emulator, "AT+CMGL=0\r"), "0\r");
+        assert_eq!(send(&mut emulator, "AT+CMGR=2\r"), "+CMS ERROR: 321\r\n");
+    }
+
+    #[test]
+    fn submits_after_prompt() {
+        let mut emulator = Emulator::new();
+        send(&mut emulator, "ATE0\rATV0\r");
+
+        assert_eq!(send(&mut emulator, "AT+CMGS=3\r"), "\r\n> ");
+        assert_eq!(send(&mut emulator, "000000\u{1a}"), "+CMGS: 0\r\n0\r");
+        assert_eq!(emulator.sent_messages(), &["000000".to_string()]);
+    }
+
+    #[test]
+    fn announces_delivered_messages() {
+        let mut emulator = Emulator::new();
+        let output = emulator.handle_event(Event::Deliver(PDU.to_string()));
+        assert_eq!(String::from_utf8(output).unwrap(), "\r\n+CMTI: \"SM\",1\r\n");
+    }
+
+    #[test]
+    fn unlocks_the_sim() {
+        let mut emulator = Emulator::new();
+        emulator.lock_sim("1234".to_string());
+        send(&mut emulator, "ATE0\rATV0\r");
+
+        assert_eq!(send(&mut emulator, "AT+CMGL=4\r"), "+CME ERROR: 11\r\n");
+        assert_eq!(send(&mut emulator, "AT+CPIN=\"0000\"\r"), "+CME ERROR: 16\r\n");
+        assert_eq!(send(&mut emulator, "AT+SPIC\r"), "+SPIC: 2,3,10,10\r\n0\r");
+        assert_eq!(send(&mut emulator, "AT+CPIN=\"1234\"\r"), "0\r");
+        assert_eq!(send(&mut emulator, "AT+CPIN?\r"), "+CPIN: READY\r\n0\r");
+    }
+
+    #[test]
+    fn places_and_answers_calls() {
+        let mut emulator = Emulator::new();
+        send(&mut emulator, "ATE0\rATV0\rAT+CLIP=1\r");
+
+        assert_eq!(send(&mut emulator, "ATD+15550000000;\r"), "0\r");
+        assert_eq!(send(&mut emulator, "AT+CLCC\r"), "+CLCC: 1,0,2,0,0,\"+15550000000\",145\r\n0\r");
+        assert_eq!(send(&mut emulator, "AT+CLCC\r"), "+CLCC: 1,0,3,0,0,\"+15550000000\",145\r\n0\r");
+        assert_eq!(String::from_utf8(emulator.handle_event(Event::RemoteBusy)).unwrap(), "7\r");
+        assert_eq!(send(&mut emulator, "AT+CLCC\r"), "0\r");
+
+        assert_eq!(String::from_utf8(emulator.handle_event(Event::Ring)).unwrap(),
+                   "2\r+CLIP: \"+15551234567\",145,\"\",0,\"\",0\r\n");
+        assert_eq!(send(&mut emulator, "ATA\r"), "0\r");
+        assert_eq!(send(&mut emulator, "AT+CLCC\r"), "+CLCC: 1,1,0,0,0,\"+15551234567\",145\r\n0\r");
+        assert_eq!(send(&mut emulator, "AT+VTS=5\r"), "0\r");
+        assert_eq!(emulator.dtmf_tones(), &['5']);
+        assert_eq!(String::from_utf8(emulator.handle_event(Event::RemoteHangUp)).unwrap(), "3\r");
+        assert_eq!(send(&mut emulator, "ATA\r"), "3\r");
+    }
+
+    #[test]
+    fn stores_supplementary_services() {
+        let mut emulator = Emulator::new();
+        send(&mut emulator, "ATE0\rATV0\r");
+
+        assert_eq!(send(&mut emulator, "AT+CCFC=2,2\r"), "+CCFC: 0,7\r\n0\r");
+        assert_eq!(send(&mut emulator, "AT+CCFC=2,3,\"+15550000000\",145,1,,,20\r"), "0\r");
+        assert_eq!(send(&mut emulator, "AT+CCFC=2,2\r"), "+CCFC: 1,1,\"+15550000000\",145,,,20\r\n0\r");
+        assert_eq!(send(&mut emulator, "AT+CCFC=2,4\r"), "0\r");
+        assert_eq!(send(&mut emulator, "AT+CCFC=2,2\r"), "+CCFC: 0,7\r\n0\r");
+
+        assert_eq!(send(&mut emulator, "AT+CCWA=1,1,1\r"), "0\r");
+        assert_eq!(send(&mut emulator, "AT+CCWA=1,2\r"), "+CCWA: 1,1\r\n0\r");
+        assert_eq!(send(&mut emulator, "AT+CLIR=1\r"), "0\r");
+        assert_eq!(send(&mut emulator, "AT+CLIR?\r"), "+CLIR: 1,4\r\n0\r");
+    }
+
+    #[test]
+    fn answers_ussd_after_the_result_code() {
+        let mut emulator = Emulator::new();
+        send(&mut emulator, "ATE0\rATV0\r");
+
+        assert_eq!(send(&mut emulator, "AT+CUSD=1,\"*111#\",15\r"), "0\r+CUSD: 1,\"1 Balance 2 Top up\",15\r\n");
+        assert_eq!(send(&mut emulator, "AT+CUSD=1,\"1\",15\r"), "0\r+CUSD: 0,\"Your balance is 5.00 USD\",15\r\n");
+        assert_eq!(send(&mut emulator, "AT+CUSD=1,\"*111#\",15\rAT+CUSD=2\r"),
+                   "0\r+CUSD: 1,\"1 Balance 2 Top up\",15\r\n0\r");
+        assert_eq!(send(&mut emulator, "AT+CUSD=1,\"1\",15\r"), "0\r+CUSD: 4\r\n");
+    }
+
+    #[test]
+    fn chains_phonebook_commands() {
+        let mut emulator = Emulator::new();
+        send(&mut emulator, "ATE0\rATV0\r");
+
+        let write = format!("AT+CSCS=\"UCS2\";+CPBS=\"SM\";+CPBW=,\"{}\",145,\"004A006F00EB006C\";+CSCS=\"IRA\"\r",
+                            UCS2_NUMBER);
+        assert_eq!(send(&mut emulator, &write), "0\r");
+        assert_eq!(send(&mut emulator, "AT+CPBR=1,10\r"), "+CPBR: 1,\"+15551234567\",145,\"Joël\"\r\n0\r");
+        assert_eq!(send(&mut emulator, "AT+CSCS=\"UCS2\";+CPBF=\"004A\";+CSCS=\"IRA\"\r"),
+                   format!("+CPBF: 1,\"{}\",145,\"004A006F00EB006C\"\r\n0\r", UCS2_NUMBER));
+
+        // The line stops at the failed search, in UCS2.
+        assert_eq!(send(&mut emulator, "AT+CSCS=\"UCS2\";+CPBF=\"0042\";+CSCS=\"IRA\"\r"), "+CME ERROR: 22\r\n");
+        assert_eq!(send(&mut emulator, "AT+CSCS?\r"), "+CSCS: \"UCS2\"\r\n0\r");
+    }
+}
@@ -1,5 +1,25 @@
+use gsm::framing::FinalResult;
+
 #[derive(Debug)]
 pub enum Error {
     ParseError,
-    LoadError
+    LoadError,
+    // The serial thread is gone, so the command was never answered.
+    Disconnected,
+    // The modem went away and the port is being reopened, so the
+    // command was dropped. Worth trying again later.
+    Unavailable,
+    // The modem didn't finish the command in time.
+    Timeout,
+    // The modem answered with ERROR, +CME ERROR, BUSY, ...
+    CommandFailed(FinalResult),
+    // The attached modem has no command for this.
+    Unsupported,
+    // The caller passed something the modem can't be given, e.g. a
+    // PIN with letters in it.
+    InvalidArgument,
+    // There is no call to answer, hang up or send tones to.
+    NoCall,
+    // There is no USSD session to reply to or cancel (any more).
+    NoSession,
 }
@@ -0,0 +1,188 @@
+// The modem never says how long a response is going to be. Instead,
+// every command ends with a final result code (OK, ERROR, ...) or, for
+// commands that take a payload, a `> ` prompt. This module recognises
+// those markers in both the numeric (ATV0) and verbose (ATV1) result
+// code modes so that the event loop can tell when a command is done.
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum FinalResult {
+    Ok,
+    Connect,
+    NoCarrier,
+    Error,
+    NoDialtone,
+    Busy,
+    NoAnswer,
+    CmeError(u32),
+    CmsError(u32),
+    // The modem is waiting for the rest of the command (e.g. the PDU
+    // of AT+CMGS).
+    Prompt,
+}
+
+impl FinalResult {
+    pub fn is_success(&self) -> bool {
+        match *self {
+            FinalResult::Ok | FinalResult::Connect | FinalResult::Prompt => true,
+            _ => false,
+        }
+    }
+}
+
+fn parse_error_code(line: &str, prefix: &str) -> Option<u32> {
+    if line.starts_with(prefix) {
+        line[prefix.len()..].trim().parse::<u32>().ok()
+    } else {
+        None
+    }
+}
+
+// Recognises a complete line as a final result code.
+pub fn parse_final_result(line: &str) -> Option<FinalResult> {
+    match line {
+        "0" | "OK" => return Some(FinalResult::Ok),
+        "1" | "CONNECT" => return Some(FinalResult::Connect),
+        "3" | "NO CARRIER" => return Some(FinalResult::NoCarrier),
+        "4" | "ERROR" => return Some(FinalResult::Error),
+        "6" | "NO DIALTONE" => return Some(FinalResult::NoDialtone),
+        "7" | "BUSY" => return Some(FinalResult::Busy),
+        "8" | "NO ANSWER" => return Some(FinalResult::NoAnswer),
+        _ => {},
+    }
+
+    if line.starts_with("CONNECT ") {
+        return Some(FinalResult::Connect);
+    }
+
+    // Verbose extended errors (AT+CMEE=2) carry text rather than a
+    // number, which still ends the command.
+    if line.starts_with("+CME ERROR:") {
+        return Some(parse_error_code(line, "+CME ERROR:")
+                    .map(FinalResult::CmeError)
+                    .unwrap_or(FinalResult::Error));
+    }
+
+    if line.starts_with("+CMS ERROR:") {
+        return Some(parse_error_code(line, "+CMS ERROR:")
+                    .map(FinalResult::CmsError)
+                    .unwrap_or(FinalResult::Error));
+    }
+
+    None
+}
+
+// Longer than any line a modem sends (a full PDU in hex is around 350
+// characters). Anything longer is noise that never got a terminator.
+const MAX_LINE_LENGTH: usize = 2048;
+
+// Input that can't be turned into a line. The bytes are dropped and
+// the accumulator carries on with whatever follows them.
+#[derive(Debug, PartialEq)]
+pub enum FramingError {
+    InvalidUtf8(Vec<u8>),
+    LineTooLong(usize),
+}
+
+// Collects raw bytes from the modem and hands them back one complete
+// line at a time. Lines may be ended by CR, LF or both, and empty
+// lines (the framing around verbose responses) are skipped.
+pub struct LineAccumulator {
+    buffer: Vec<u8>,
+}
+
+impl LineAccumulator {
+    pub fn new() -> LineAccumulator {
+        LineAccumulator {
+            buffer: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    // Removes the next complete line.
+    pub fn next_line(&mut self) -> Option<Result<String, FramingError>> {
+        loop {
+            let end = match self.buffer.iter().position(|&b| b == b'\r' || b == b'\n') {
+                Some(end) => end,
+                None if self.buffer.len() > MAX_LINE_LENGTH => {
+                    let length = self.buffer.len();
+                    self.buffer.clear();
+                    return Some(Err(FramingError::LineTooLong(length)));
+                },
+                None => return None,
+            };
+
+            let line: Vec<u8> = self.buffer.drain(..end + 1).take(end).collect();
+            if line.len() > MAX_LINE_LENGTH {
+                return Some(Err(FramingError::LineTooLong(line.len())));
+            }
+
+            // A reboot or line noise often leaves NUL bytes behind,
+            // which would otherwise be glued onto the next line.
+            let line: Vec<u8> = line.into_iter().filter(|&b| b != 0).collect();
+            if line.is_empty() {
+                continue;
+            }
+
+            return Some(String::from_utf8(line).map_err(|e| FramingError::InvalidUtf8(e.into_bytes())));
+        }
+    }
+
+    // The payload prompt is not followed by a line terminator, so it
+    // has to be recognised in whatever is left over after splitting
+    // lines. Consumes the prompt if it is there.
+    pub fn take_prompt(&mut self) -> bool {
+        if self.buffer == b">" || self.buffer == b"> " {
+            self.buffer.clear();
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FinalResult, FramingError, LineAccumulator, MAX_LINE_LENGTH, parse_final_result};
+
+    #[test]
+    fn recognises_both_result_code_modes() {
+        assert_eq!(parse_final_result("0"), Some(FinalResult::Ok));
+        assert_eq!(parse_final_result("OK"), Some(FinalResult::Ok));
+        assert_eq!(parse_final_result("4"), Some(FinalResult::Error));
+        assert_eq!(parse_final_result("BUSY"), Some(FinalResult::Busy));
+        assert_eq!(parse_final_result("+CME ERROR: 10"), Some(FinalResult::CmeError(10)));
+        assert_eq!(parse_final_result("+CMS ERROR: 321"), Some(FinalResult::CmsError(321)));
+        assert_eq!(parse_final_result("+CME ERROR: SIM not inserted"), Some(FinalResult::Error));
+        assert_eq!(parse_final_result("+CSQ: 20,0"), None);
+        assert_eq!(parse_final_result("2"), None);
+    }
+
+    #[test]
+    fn splits_lines() {
+        let mut lines = LineAccumulator::new();
+        lines.push(b"\r\n+CSQ: 20,0\r\n\r\nO");
+        assert_eq!(lines.next_line(), Some(Ok("+CSQ: 20,0".to_string())));
+        assert_eq!(lines.next_line(), None);
+
+        lines.push(b"K\r\n> ");
+        assert_eq!(lines.next_line(), Some(Ok("OK".to_string())));
+        assert_eq!(lines.next_line(), None);
+        assert!(lines.take_prompt());
+    }
+
+    #[test]
+    fn recovers_from_bad_input() {
+        let mut lines = LineAccumulator::new();
+        lines.push(b"\x00\x00\r\n\xff\xfeRDY\r\nOK\r\n");
+        assert_eq!(lines.next_line(), Some(Err(FramingError::InvalidUtf8(b"\xff\xfeRDY".to_vec()))));
+        assert_eq!(lines.next_line(), Some(Ok("OK".to_string())));
+
+        lines.push(&[b'A'; MAX_LINE_LENGTH + 1]);
+        assert_eq!(lines.next_line(), Some(Err(FramingError::LineTooLong(MAX_LINE_LENGTH + 1))));
+        lines.push(b"0\r");
+        assert_eq!(lines.next_line(), Some(Ok("0".to_string())));
+    }
+}
@@ -1,50 +1,148 @@
+extern crate libc;
 extern crate serial;
 
 pub mod sms;
 mod pdu;
 pub mod command;
-mod responses;
-mod errors;
-
-use std::io::{self, BufRead, BufReader};
+pub mod responses;
+pub mod errors;
+pub mod framing;
+pub mod transport;
+pub mod urc;
+pub mod emulator;
+mod poller;
+pub mod supervisor;
+pub mod recording;
+pub mod config;
+pub mod profile;
+pub mod device;
+pub mod sim;
+pub mod network;
+pub mod signal;
+pub mod operator;
+pub mod call;
+pub mod dtmf;
+pub mod call_log;
+pub mod supplementary;
+pub mod ussd;
+pub mod phonebook;
+
+use std::io;
 use std::str;
-use std::sync::mpsc;
+use std::sync::{Arc, mpsc};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+// Reads only happen once poll(2) says the port is readable, so this
+// just keeps a spurious wake-up from blocking the event thread.
+const PORT_TIMEOUT_MS: u64 = 100;
+
+// Written instead of a payload to make the modem leave the `> `
+// prompt without sending anything.
+const ESCAPE: u8 = 0x1b;
+
+type SerialThreadResult = io::Result<()>;
+
+// Queues commands for the serial thread and wakes it up to write them.
+#[derive(Clone)]
+pub struct ModemPipe {
+    sender: mpsc::Sender<command::RawCommand>,
+    waker: poller::Waker,
+    profile: profile::ProfileHandle,
+}
 
-const GSM_SERIAL_PORT: &'static str = "/dev/ttyAMA0";
+impl ModemPipe {
+    // The profile of the attached modem, for building vendor commands.
+    pub fn profile(&self) -> Arc<profile::ModemProfile> {
+        self.profile.get()
+    }
 
-// This is the amount of time that the event thread spends waiting for
-// responses from the GSM radio. This will bound how long it takes for
-// a command to actually get send to the module.
-const PORT_TIMEOUT_MS: u64 = 1000;
+    pub fn send(&self, cmd: command::RawCommand) -> Result<(), mpsc::SendError<command::RawCommand>> {
+        try!(self.sender.send(cmd));
+        self.waker.wake();
+        Ok(())
+    }
+}
 
-// How long for the event loop thread to sleep in between reads. This
-// will bound the input/output speed of the device from this layer
-// since the driver will only read one line at a time for now.
-const EVT_THREAD_SLEEP_MS: u64 = 10;
+// A command that has been written to the modem and is waiting for its
+// final result code.
+struct InFlightCommand {
+    command: command::RawCommand,
+    echo: String,
+    lines: Vec<String>,
+    sent_at: Instant,
+}
 
-type SerialThreadResult = Result<(), self::serial::Error>;
+impl InFlightCommand {
+    fn new(command: command::RawCommand) -> InFlightCommand {
+        let echo = String::from_utf8_lossy(&command.render()).trim().to_string();
 
-pub type ModemPipe = mpsc::Sender<command::RawCommand>;
+        InFlightCommand {
+            command: command,
+            echo: echo,
+            lines: Vec::new(),
+            sent_at: Instant::now(),
+        }
+    }
+
+    fn timed_out(&self) -> bool {
+        self.sent_at.elapsed() > self.command.timeout()
+    }
+
+    fn time_remaining(&self) -> Duration {
+        let elapsed = self.sent_at.elapsed();
+        if elapsed < self.command.timeout() {
+            self.command.timeout() - elapsed
+        } else {
+            Duration::from_millis(0)
+        }
+    }
+
+    fn complete(self, result: Option<framing::FinalResult>) {
+        if let Some(sender) = self.command.get_callback() {
+            sender.send(command::RawResponse {
+                lines: self.lines,
+                result: result,
+                refused: false,
+            }).ok();
+        }
+    }
+}
 
-#[derive(Debug)]
 struct SerialModem {
     thread_handler: thread::JoinHandle<SerialThreadResult>,
-    command_sender: ModemPipe
+    command_sender: ModemPipe,
+    urcs: urc::UrcDispatcher,
+    health: supervisor::HealthMonitor,
+    // Signalled whenever the port has been opened again.
+    sessions: Option<mpsc::Receiver<()>>,
 }
 
 impl SerialModem {
-    pub fn new(serial_port: &str) -> io::Result<SerialModem> {
-        let serial_port_str: String = String::from(serial_port);
-
+    pub fn new<T: transport::Transport + 'static>(transport: T, connector: Option<supervisor::Connector<T>>,
+                                                  config: config::ModemConfig) -> io::Result<SerialModem> {
         let (send, recv) = mpsc::channel::<command::RawCommand>();
+        let (waker, poller) = try!(poller::new());
+
+        let urcs = urc::UrcDispatcher::new();
+        let health = supervisor::HealthMonitor::new();
+        let supervisor = supervisor::Supervisor::new(connector, health.clone());
+        let profile = profile::ProfileHandle::new();
+        let (new_session, sessions) = mpsc::channel();
 
-        let handle = try!(SerialModem::start_listener(recv, serial_port_str));
+        let handle = try!(SerialModem::start_listener(recv, poller, urcs.clone(), supervisor, config,
+                                                      profile.clone(), new_session, transport));
 
         let phone = SerialModem {
             thread_handler: handle,
-            command_sender: send,
+            command_sender: ModemPipe {
+                sender: send,
+                waker: waker,
+                profile: profile,
+            },
+            urcs: urcs,
+            health: health,
+            sessions: Some(sessions),
         };
 
         Ok(phone)
@@ -58,120 +156,354 @@ impl SerialModem {
         self.command_sender.send(cmd)
     }
 
-    fn start_listener(receiver: mpsc::Receiver<command::RawCommand>,
-                      serial_port: String) -> io::Result<thread::JoinHandle<SerialThreadResult>> {
+    fn start_listener<T: transport::Transport + 'static>(receiver: mpsc::Receiver<command::RawCommand>,
+                                                        mut poller: poller::Poller,
+                                                        urcs: urc::UrcDispatcher,
+                                                        mut supervisor: supervisor::Supervisor<T>,
+                                                        config: config::ModemConfig,
+                                                        profile: profile::ProfileHandle,
+                                                        new_session: mpsc::Sender<()>,
+                                                        mut port: T) -> io::Result<thread::JoinHandle<SerialThreadResult>> {
         // Create a reader thread to catch all responses from the
         // serial port
         thread::Builder::new().name("aji/gsm_evt".to_string()).spawn(
             move || {
-                // TODO: [hleath 2017-09-30] Use Async IO.
+                let mut queue = command::CommandQueue::new();
+                let mut in_flight: Option<InFlightCommand> = None;
+                let banners = urcs.subscribe(&[urc::UrcKind::PowerOn]);
 
-                // TODO: [hleath 2017-09-24] This thread really
-                // shouldn't ever return with an error. It should
-                // attempt to recover or take the program down.
+                loop {
+                    let result = Self::run_session(&mut port, &receiver, &mut poller, &mut queue, &mut in_flight,
+                                                   &urcs, &banners, &supervisor, &config, &profile);
 
-                // Open the serial port
-                let mut port = match serial::open(&serial_port) {
-                    Ok(port) => port,
-                    Err(e) => return Err(e),
-                };
+                    // Whatever the modem was doing is lost with the
+                    // session.
+                    if let Some(pending) = in_flight.take() {
+                        pending.command.refuse();
+                    }
 
-                try!(Self::configure_serial_port(&mut port));
+                    match result {
+                        Ok(()) => return Ok(()),
+                        Err(e) => {
+                            println!("Lost the modem: {:?}", e);
+
+                            // Nobody waits on a modem that isn't there,
+                            // not even for the time it takes to find
+                            // out whether it comes back.
+                            queue.clear();
+
+                            // Serial ports are opened for exclusive
+                            // use, so the old one has to be closed
+                            // before the device can be opened again.
+                            drop(port);
+                            port = match supervisor.reconnect(|delay| Self::refuse_commands(&receiver, delay)) {
+                                Some(port) => port,
+                                None => return Err(e),
+                            };
+
+                            // A result the modem sends late, for a
+                            // command that was given up on, would
+                            // otherwise finish the first command of the
+                            // new session. If the port is already
+                            // broken, the session finds out.
+                            Self::discard_input(&mut port).ok();
+
+                            // The modem in the socket may not be the
+                            // one that left, so it gets the generic
+                            // configuration until it has been
+                            // identified again.
+                            profile.set(Arc::new(profile::Generic));
+                            new_session.send(()).ok();
+                        }
+                    }
+                }
+            })
+    }
 
-                let mut reader = BufReader::new(port);
+    // Refuses every command that is sent within `duration`, failing it
+    // with Error::Unavailable.
+    fn refuse_commands(receiver: &mpsc::Receiver<command::RawCommand>, duration: Duration) {
+        let until = Instant::now() + duration;
 
-                let mut response = String::new();
-                let mut cmd: Option<command::RawCommand> = None;
+        loop {
+            let now = Instant::now();
+            if now >= until {
+                return;
+            }
 
-                loop {
-                    if cmd.is_none() {
-                        // First try to get a command from the command
-                        // channel:
-                        match receiver.try_recv() {
-                            Ok(recv_cmd) => {
-                                try!(Self::write_command_to_serial_port(reader.get_mut(), &recv_cmd));
-                                cmd = Some(recv_cmd);
-                            },
-                            Err(mpsc::TryRecvError::Empty) => {}, // Nothing to do
-                            Err(mpsc::TryRecvError::Disconnected) => {
-                                return Ok(())
-                            }
+            match receiver.recv_timeout(until - now) {
+                Ok(command) => command.refuse(),
+                Err(mpsc::RecvTimeoutError::Timeout) => return,
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    thread::sleep(until - now);
+                    return;
+                },
+            }
+        }
+    }
+
+    // Reads and drops whatever the modem has already sent.
+    fn discard_input<T: transport::Transport>(port: &mut T) -> io::Result<()> {
+        try!(port.set_timeout(Duration::from_millis(PORT_TIMEOUT_MS)));
+
+        let mut chunk = [0u8; 256];
+        loop {
+            match port.read(&mut chunk) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "transport closed")),
+                Ok(num_bytes) => println!("discarded {:?} from the last session",
+                                          String::from_utf8_lossy(&chunk[..num_bytes])),
+                Err(ref e) if e.kind() == io::ErrorKind::TimedOut => return Ok(()),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Talks to the modem over one open port. Returns Ok once nobody
+    // can send commands any more, or an error if the port has to be
+    // reopened.
+    fn run_session<T: transport::Transport>(port: &mut T,
+                                            receiver: &mpsc::Receiver<command::RawCommand>,
+                                            poller: &mut poller::Poller,
+                                            queue: &mut command::CommandQueue,
+                                            in_flight: &mut Option<InFlightCommand>,
+                                            urcs: &urc::UrcDispatcher,
+                                            banners: &mpsc::Receiver<urc::Urc>,
+                                            supervisor: &supervisor::Supervisor<T>,
+                                            config: &config::ModemConfig,
+                                            profile: &profile::ProfileHandle) -> io::Result<()> {
+        try!(port.set_timeout(Duration::from_millis(PORT_TIMEOUT_MS)));
+
+        let mut lines = framing::LineAccumulator::new();
+        let mut urc_parser = urc::UrcParser::new();
+
+        // The modem may have been power cycled while the port was
+        // closed, so configure it before anything else is sent.
+        queue.prepend(Self::init_sequence(config, &*profile.get()));
+
+        loop {
+            // First move everything from the command channel into the
+            // queue, so that a later interactive command can overtake
+            // earlier background ones:
+            loop {
+                match receiver.try_recv() {
+                    Ok(recv_cmd) => queue.push(recv_cmd),
+                    Err(mpsc::TryRecvError::Empty) => break, // Nothing to do
+                    Err(mpsc::TryRecvError::Disconnected) => {
+                        if queue.is_empty() && in_flight.is_none() {
+                            return Ok(())
                         }
+                        break;
                     }
+                }
+            }
 
-                    match Self::try_read_from_serial_port(&mut reader) {
-                        Ok(data) => {
-                            if data.len() > 0 {
-                                if data[0] != 10 {
-                                    response = String::new();
-                                }
+            while in_flight.is_none() {
+                match queue.pop() {
+                    // Nobody is waiting for this one any more.
+                    Some(ref next) if next.is_cancelled() => continue,
+                    Some(mut next) => {
+                        // The configuration has the last word on timeouts,
+                        // then the vendor.
+                        let command_type = *next.command_type();
+                        if let Some(timeout) = config.timeout(command_type).or_else(|| profile.get().timeout(command_type)) {
+                            next.set_timeout(timeout);
+                        }
 
-                                response += &String::from_utf8(data).expect("Invalid UTF-8")
-                            }
-                        },
+                        try!(Self::write_to_serial_port(port, &next.render()));
+                        *in_flight = Some(InFlightCommand::new(next));
+                    },
+                    None => break,
+                }
+            }
 
-                        Err(e) => if e.kind() == io::ErrorKind::TimedOut {
-                            // Send response back to the Command.
-                            if response.len() > 0 {
-                                match cmd.and_then(|f| f.get_callback() ) {
-                                    Some((command_type, sender)) => sender.send((command_type, response)).ok(),
-                                    None => {
-                                        println!("received unsolicited response {}", response);
-                                        None
-                                    },
-                                };
-                            }
-
-                            response = String::new();
-                            cmd = None
-
-                            // Without a processing response, there is
-                            // nothing to do during a timeout.
-                        } else {
-                            println!("got other error {}", e);
+            // Sleep until the modem sends something, a command is
+            // queued, or the command in flight times out.
+            let timeout = in_flight.as_ref().map(|pending| pending.time_remaining());
+            let ready = try!(poller.wait(port.as_raw_fd(), timeout));
+
+            if ready.port {
+                match Self::try_read_from_serial_port(port, &mut lines) {
+                    Ok(()) => {},
+                    // Without data from the modem, there is nothing to
+                    // do during a timeout.
+                    Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {},
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                    // The port said it was readable but can't be read:
+                    // it has been closed or the device went away.
+                    Err(e) => return Err(e),
+                }
+            }
+
+            while let Some(line) = lines.next_line() {
+                match line {
+                    Ok(line) => {
+                        supervisor.modem_answered();
+                        *in_flight = Self::handle_line(in_flight.take(), line, &mut urc_parser, urcs, &*profile.get());
+                    },
+                    // Garbage on the line doesn't end the command in
+                    // flight; its timeout still applies if the result
+                    // code was lost.
+                    Err(e) => println!("dropped modem output: {:?}", e),
+                }
+            }
+
+            // The payload prompt has no line terminator, so look for
+            // it in whatever is left over.
+            if lines.take_prompt() {
+                *in_flight = match in_flight.take() {
+                    Some(mut pending) => match pending.command.take_continuation() {
+                        // The modem answers the escape with a result
+                        // code, which finishes the command as usual.
+                        Some(_) if pending.command.is_cancelled() => {
+                            try!(Self::write_to_serial_port(port, &[ESCAPE]));
+                            Some(pending)
+                        },
+                        Some(payload) => {
+                            try!(Self::write_to_serial_port(port, &payload));
+                            Some(pending)
+                        },
+                        None => {
+                            pending.complete(Some(framing::FinalResult::Prompt));
+                            None
                         }
-                    };
+                    },
+                    None => None,
+                };
+            }
+
+            // A power-on banner means the modem rebooted and forgot
+            // its configuration.
+            if banners.try_recv().is_ok() {
+                while banners.try_recv().is_ok() {}
+                supervisor.modem_restarted();
+                queue.prepend(Self::init_sequence(config, &*profile.get()));
+            }
+
+            let timed_out = match *in_flight {
+                Some(ref pending) => pending.timed_out(),
+                None => false,
+            };
 
-                    // Commands that begin with a line feed (10) are
-                    // part of the same response. And a carriage
-                    // return (13) is at the end of each line.
+            if timed_out {
+                if let Some(pending) = in_flight.take() {
+                    *in_flight = try!(Self::handle_timeout(port, pending));
 
-                    thread::sleep(Duration::from_millis(EVT_THREAD_SLEEP_MS));
+                    if in_flight.is_none() && supervisor.command_timed_out() {
+                        return Err(io::Error::new(io::ErrorKind::TimedOut, "modem stopped answering"));
+                    }
                 }
-            })
+            }
+        }
     }
 
-    fn configure_serial_port<T: serial::SerialPort>(port: &mut T) -> io::Result<()> {
-        // Configure the port
-        try!(port.reconfigure(&|settings| {
-            try!(settings.set_baud_rate(serial::Baud115200));
-            Ok(())
-        }));
+    // The standard configuration, then the vendor's settings, then
+    // the ones from the configuration.
+    fn init_sequence(config: &config::ModemConfig, profile: &profile::ModemProfile) -> Vec<command::RawCommand> {
+        let mut extra = profile.init_commands();
+        extra.extend(config.init_commands.iter().cloned());
 
-        try!(port.set_timeout(Duration::from_millis(PORT_TIMEOUT_MS)));
-        Ok(())
+        command::init_sequence(&extra)
     }
 
-    fn try_read_from_serial_port<T: serial::SerialPort>(reader: &mut BufReader<T>) -> io::Result<Vec<u8>> {
-        let mut response_buffer: Vec<u8> = Vec::new();
+    // Either writes the command again or gives up on it, so that a
+    // modem that never answers can't hold up the rest of the queue.
+    fn handle_timeout<T: transport::Transport>(port: &mut T, mut pending: InFlightCommand) -> io::Result<Option<InFlightCommand>> {
+        println!("no result code for {:?}", String::from_utf8_lossy(&command::redact(pending.echo.as_bytes())));
 
-        match reader.read_until(b'\r', &mut response_buffer) {
-            Ok(num_bytes) => {
-                // Trim off any excess \r
-                if response_buffer[num_bytes - 1] == b'\r' {
-                    response_buffer = response_buffer[0..num_bytes - 1].to_vec()
+        // The modem may still be sitting at the payload prompt, where
+        // anything written next would become part of the message.
+        if pending.command.has_payload() {
+            try!(Self::write_to_serial_port(port, &[ESCAPE]));
+        }
+
+        if !pending.command.is_cancelled() && pending.command.take_retry() {
+            try!(Self::write_to_serial_port(port, &pending.command.render()));
+            return Ok(Some(InFlightCommand::new(pending.command)));
+        }
+
+        // Tell the caller that the command timed out.
+        pending.complete(None);
+        Ok(None)
+    }
+
+    fn handle_line(in_flight: Option<InFlightCommand>, line: String,
+                   urc_parser: &mut urc::UrcParser, urcs: &urc::UrcDispatcher,
+                   profile: &profile::ModemProfile) -> Option<InFlightCommand> {
+        // A URC is any line that arrives without a command in flight,
+        // or one whose prefix doesn't belong to the command in flight.
+        // NO CARRIER, BUSY and NO ANSWER only finish ATD and ATA; during
+        // any other command they are about a call that is already up.
+        let unsolicited = urc_parser.awaiting_pdu() || match in_flight {
+            Some(ref pending) => match framing::parse_final_result(&line) {
+                Some(framing::FinalResult::NoCarrier) | Some(framing::FinalResult::Busy) |
+                Some(framing::FinalResult::NoAnswer) => match *pending.command.command_type() {
+                    command::CommandType::Dial | command::CommandType::Answer => false,
+                    _ => true,
+                },
+                Some(_) => false,
+                None => {
+                    urc::unsolicited_prefix(&line).or_else(|| profile.unsolicited_prefix(&line)).map_or(false, |prefix| {
+                        pending.command.response_prefix().map_or(true, |p| p != prefix)
+                    })
+                },
+            },
+            None => true,
+        };
+
+        if unsolicited {
+            let code = match urc_parser.feed(&line) {
+                Ok(code) => code,
+                Err(_) => profile.parse_urc(&line),
+            };
+
+            match code {
+                Some(code) => if !urcs.dispatch(&code) {
+                    println!("received unsolicited response {:?}", code);
+                },
+                None => if !urc_parser.awaiting_pdu() {
+                    println!("received unsolicited response {}", line);
+                },
+            }
+
+            return in_flight;
+        }
+
+        match in_flight {
+            Some(mut pending) => {
+                if let Some(result) = framing::parse_final_result(&line) {
+                    pending.complete(Some(result));
+                    return None;
+                }
+
+                // Drop the echo of the command itself.
+                if line != pending.echo {
+                    pending.lines.push(line);
                 }
 
-                Ok(response_buffer)
+                Some(pending)
+            },
+            None => None,
+        }
+    }
+
+    fn try_read_from_serial_port<T: transport::Transport>(port: &mut T, lines: &mut framing::LineAccumulator) -> io::Result<()> {
+        let mut chunk = [0u8; 256];
+
+        match port.read(&mut chunk) {
+            // Sockets and pipes (unlike the serial port) can be closed
+            // by the other side.
+            Ok(0) => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "transport closed")),
+            Ok(num_bytes) => {
+                lines.push(&chunk[..num_bytes]);
+                Ok(())
             },
             Err(e) => Err(e)
         }
     }
 
-    fn write_command_to_serial_port<T: serial::SerialPort>(port: &mut T, cmd: &command::RawCommand) -> io::Result<()> {
-        let sending_bytes = cmd.render();
-        println!("Going to send {:?}.", sending_bytes);
-        try!(port.write(sending_bytes.as_ref()));
+    fn write_to_serial_port<T: transport::Transport>(port: &mut T, bytes: &[u8]) -> io::Result<()> {
+        try!(port.write_all(bytes));
 
         Ok(())
     }
@@ -185,39 +517,144 @@ impl SerialModem {
 pub struct Radio {
     phone: SerialModem,
     pub sms: sms::MessagingManager,
+    pub network: network::NetworkManager,
+    pub signal: signal::SignalSampler,
+    pub operators: operator::OperatorScanner,
+    pub calls: call::CallManager,
+    pub call_log: call_log::CallLogger,
+    pub ussd: ussd::UssdManager,
 }
 
 #[derive(Clone)]
 pub struct RadioClient {
     pub phone: ModemPipe,
     pub sms: sms::MessagingPipe,
+    pub urc: urc::UrcDispatcher,
+    pub health: supervisor::HealthMonitor,
+    pub network: network::NetworkMonitor,
+    pub signal: signal::SignalMonitor,
+    pub operators: operator::OperatorPipe,
+    pub calls: call::CallPipe,
+    pub call_log: call_log::CallLog,
+    pub ussd: ussd::UssdPipe,
 }
 
 impl Radio {
-    pub fn new() -> Result<Radio, errors::Error> {
-        match SerialModem::new(GSM_SERIAL_PORT) {
-            Ok(phone) => {
-                // Set the correct parameters for the phone
-                let configuration_pipeline = command::Pipeline::new(phone.command_sender.clone());
+    // Opens the modem described by `config`. The port is opened again
+    // if it goes away.
+    pub fn new(config: &config::ModemConfig) -> Result<Radio, errors::Error> {
+        let port_config = config.clone();
 
-                // To make things easier to parse, turn off command
-                // echo and set the result code to the short codes.
-                configuration_pipeline.set_command_echo(false).unwrap();
-                configuration_pipeline.set_result_code_mode(command::ResultCodeMode::ShortCode).unwrap();
+        Radio::new_with_connector(config, move || transport::open_serial_port(&port_config))
+    }
 
-                configuration_pipeline.set_sms_mode(command::SMSMode::PDUMode).unwrap();
+    // Opens the modem on a different serial device, e.g. the pty of
+    // the modem emulator, with the default settings otherwise.
+    pub fn new_with_port(serial_port: &str) -> Result<Radio, errors::Error> {
+        Radio::new(&config::ModemConfig {
+            port: serial_port.to_string(),
+            ..config::ModemConfig::default()
+        })
+    }
 
-                // Sleep to ensure that the changes take effect
-                thread::sleep(Duration::from_millis(1000));
+    // Builds a Radio over any byte stream that reaches a modem. If the
+    // stream fails there is no way to get it back. As with a connector,
+    // the port settings of `config` don't apply.
+    pub fn new_with_transport<T: transport::Transport + 'static>(transport: T,
+                                                                config: &config::ModemConfig) -> Result<Radio, errors::Error> {
+        Radio::start(transport, None, config)
+    }
+
+    // Builds a Radio that calls `connect` to open the modem, and again
+    // whenever the transport fails. Only the command settings of
+    // `config` apply; the port settings are up to `connect`.
+    pub fn new_with_connector<T, F>(config: &config::ModemConfig, mut connect: F) -> Result<Radio, errors::Error>
+        where T: transport::Transport + 'static, F: FnMut() -> io::Result<T> + Send + 'static {
+        match connect() {
+            Ok(transport) => Radio::start(transport, Some(Box::new(connect)), config),
+            Err(e) => {
+                println!("Error opening modem {}: {:?}", config.port, e);
+                Err(errors::Error::LoadError)
+            }
+        }
+    }
+
+    fn start<T: transport::Transport + 'static>(transport: T, connector: Option<supervisor::Connector<T>>,
+                                                config: &config::ModemConfig) -> Result<Radio, errors::Error> {
+        match SerialModem::new(transport, connector, config.clone()) {
+            Ok(mut phone) => {
+                // The serial thread configures the phone before it
+                // sends anything else, so by the time the attention
+                // command is answered the configuration is done.
+                let configuration_pipeline = command::Pipeline::new(phone.command_sender.clone());
 
                 // Ensure that the phone is working before returning to caller.
-                Radio::synchronous_attention_internal(&configuration_pipeline);
+                if let Err(e) = Radio::synchronous_attention_internal(&configuration_pipeline) {
+                    println!("Modem did not answer after configuration: {:?}", e);
+                    return Err(e);
+                }
+
+                // Until now the modem has been treated as a generic
+                // one. Its vendor settings follow the configuration
+                // the serial thread already sent.
+                Radio::apply_profile(&configuration_pipeline, &phone.command_sender.profile);
+
+                // The same goes for every modem the port is opened to
+                // later on. Like the managers, this thread lives as
+                // long as the serial thread.
+                if let Some(sessions) = phone.sessions.take() {
+                    let pipeline = command::Pipeline::new(phone.command_sender.clone());
+                    let profile = phone.command_sender.profile.clone();
+
+                    let spawned = thread::Builder::new().name("aji/profile".to_string()).spawn(move || {
+                        for _ in sessions.iter() {
+                            Radio::apply_profile(&pipeline, &profile);
+                        }
+                    });
+                    if let Err(e) = spawned {
+                        println!("Could not start the profile thread: {:?}", e);
+                    }
+                }
+
+                // A locked SIM refuses every SMS command. The
+                // MessagingManager keeps retrying, so messages show up
+                // once someone enters the PIN through the RadioClient.
+                match sim::query_sim_state(&configuration_pipeline) {
+                    Ok(sim::SimState::Ready) => {},
+                    Ok(state) => println!("SIM is not ready ({:?}); messages are unavailable until it is", state),
+                    Err(e) => println!("Could not read the SIM state: {:?}", e),
+                }
 
                 // Immediately start a MessagingManager for this phone
                 let sms_pipeline = command::Pipeline::new(phone.command_sender.clone());
+                let sms = sms::MessagingManager::new(sms_pipeline, phone.urcs.subscribe(&[urc::UrcKind::NewMessage]));
+
+                let network_pipeline = command::Pipeline::with_priority(phone.command_sender.clone(),
+                                                                        command::Priority::Background);
+                let network = network::NetworkManager::new(network_pipeline, phone.urcs.subscribe(&[
+                    urc::UrcKind::NetworkRegistration, urc::UrcKind::GprsRegistration, urc::UrcKind::SystemMode,
+                    urc::UrcKind::PowerOn,
+                ]));
+
+                let signal = signal::SignalSampler::new(command::Pipeline::with_priority(phone.command_sender.clone(),
+                                                                                         command::Priority::Background));
+
+                let operators = operator::OperatorScanner::new(command::Pipeline::new(phone.command_sender.clone()));
+
+                let calls = call::CallManager::new(command::Pipeline::new(phone.command_sender.clone()), &phone.urcs);
+                let call_log = call_log::CallLogger::new(&calls.get_pipe(), config.call_log.clone());
+
+                let ussd = ussd::UssdManager::new(command::Pipeline::new(phone.command_sender.clone()), &phone.urcs);
+
                 Ok(Radio {
                     phone: phone,
-                    sms: sms::MessagingManager::new(sms_pipeline),
+                    sms: sms,
+                    network: network,
+                    signal: signal,
+                    operators: operators,
+                    calls: calls,
+                    call_log: call_log,
+                    ussd: ussd,
                 })
             },
             Err(e) => {
@@ -231,26 +668,190 @@ impl Radio {
         RadioClient {
             phone: self.phone.get_pipe(),
             sms: self.sms.get_pipe(),
+            urc: self.phone.urcs.clone(),
+            health: self.phone.health.clone(),
+            network: self.network.get_monitor(),
+            signal: self.signal.get_monitor(),
+            operators: self.operators.get_pipe(),
+            calls: self.calls.get_pipe(),
+            call_log: self.call_log.get_log(),
+            ussd: self.ussd.get_pipe(),
         }
     }
 
-    pub fn synchronous_attention(&self) {
+    // Switches to the profile of the modem, if it is a known one, and
+    // sends its vendor settings.
+    fn apply_profile(pipeline: &command::Pipeline, profile: &profile::ProfileHandle) {
+        if let Some(detected) = Radio::detect_profile(pipeline) {
+            println!("Detected {} modem", detected.name());
+            profile.set(detected.clone());
+
+            for command in detected.init_commands() {
+                if let Err(e) = pipeline.configure(&command).and_then(|r| r.wait()) {
+                    println!("Modem rejected {:?}: {:?}", command, e);
+                }
+            }
+        }
+    }
+
+    // Asks the modem who it is, first with ATI and then with AT+CGMM
+    // for modems whose ATI text doesn't name the model.
+    fn detect_profile(pipeline: &command::Pipeline) -> Option<Arc<profile::ModemProfile>> {
+        let identification = pipeline.identify().and_then(|r| r.wait()).map(|lines| lines.join("\n"));
+        if let Some(detected) = identification.ok().and_then(|text| profile::detect(&text)) {
+            return Some(detected);
+        }
+
+        pipeline.model_identification().and_then(|r| r.wait()).ok().and_then(|model| profile::detect(&model))
+    }
+
+    pub fn device_info(&self) -> Result<device::DeviceInfo, errors::Error> {
+        device::query_device_info(&command::Pipeline::new(self.phone.command_sender.clone()))
+    }
+
+    pub fn synchronous_attention(&self) -> Result<(), errors::Error> {
         // TODO: Clean this up, we shouldn't need to create a new
         // struct just to send a simple command.
         let temp_pipeline = command::Pipeline::new(self.phone.command_sender.clone());
         Radio::synchronous_attention_internal(&temp_pipeline)
     }
 
-    fn synchronous_attention_internal(pipeline: &command::Pipeline) {
-        let (send, recv) = mpsc::channel();
-        pipeline.attention(Some(send)).unwrap();
-
-        // Wait 5 seconds to get a response from the attention
-        recv.recv_timeout(Duration::from_millis(5000)).unwrap();
+    fn synchronous_attention_internal(pipeline: &command::Pipeline) -> Result<(), errors::Error> {
+        // The serial thread enforces the timeout (and retries) for
+        // the attention command.
+        try!(pipeline.attention()).wait()
     }
 
     pub fn shutdown(self) {
         self.sms.exit();
+        self.network.exit();
+        self.signal.exit();
+        self.operators.exit();
+        self.calls.exit();
+        self.call_log.exit();
+        self.ussd.exit();
         self.phone.exit();
     }
 }
+
+impl RadioClient {
+    fn pipeline(&self) -> command::Pipeline {
+        command::Pipeline::new(self.phone.clone())
+    }
+
+    // Reads the modem and SIM identification. The SIM fields are left
+    // empty if the SIM can't be read.
+    pub fn device_info(&self) -> Result<device::DeviceInfo, errors::Error> {
+        device::query_device_info(&self.pipeline())
+    }
+
+    // What the SIM is waiting for, so that the UI knows whether to ask
+    // for the PIN or the PUK.
+    pub fn sim_status(&self) -> Result<sim::SimStatus, errors::Error> {
+        sim::query_sim_status(&self.pipeline())
+    }
+
+    pub fn enter_pin(&self, pin: &str) -> Result<(), errors::Error> {
+        try!(self.pipeline().enter_pin(pin)).wait()
+    }
+
+    pub fn enter_puk(&self, puk: &str, new_pin: &str) -> Result<(), errors::Error> {
+        try!(self.pipeline().enter_puk(puk, new_pin)).wait()
+    }
+
+    // Turns the PIN request at power on on or off. Needs the current
+    // PIN either way.
+    pub fn set_pin_lock(&self, enabled: bool, pin: &str) -> Result<(), errors::Error> {
+        try!(self.pipeline().set_pin_lock(enabled, pin)).wait()
+    }
+
+    pub fn change_pin(&self, old_pin: &str, new_pin: &str) -> Result<(), errors::Error> {
+        try!(self.pipeline().change_pin(old_pin, new_pin)).wait()
+    }
+
+    pub fn current_operator(&self) -> Result<operator::CurrentOperator, errors::Error> {
+        try!(self.pipeline().current_operator()).wait()
+    }
+
+    // 2G, 3G or 4G, for the indicator next to the signal bars. None
+    // without service.
+    pub fn network_generation(&self) -> Option<network::Generation> {
+        self.network.generation()
+    }
+
+    // Lets the modem pick the network again.
+    pub fn select_operator_automatically(&self) -> Result<(), errors::Error> {
+        try!(self.pipeline().select_operator_automatically()).wait()
+    }
+
+    // Pins the network with the given MCC and MNC (as listed by a
+    // scan). Fails if the modem can't register on it.
+    pub fn select_operator(&self, numeric: &str,
+                           access_technology: Option<operator::AccessTechnology>) -> Result<(), errors::Error> {
+        try!(self.pipeline().select_operator(numeric, access_technology)).wait()
+    }
+
+    // Plays `digits` into the active call, pausing at every comma.
+    // Returns once the last tone has played.
+    pub fn send_dtmf(&self, digits: &str, options: &dtmf::DtmfOptions) -> Result<(), errors::Error> {
+        dtmf::send_dtmf(&self.pipeline(), digits, options)
+    }
+
+    // Where voice calls are forwarded to, for each reason. Asks the
+    // network, so it takes a few seconds.
+    pub fn call_forwarding(&self) -> Result<Vec<supplementary::CallForwarding>, errors::Error> {
+        supplementary::query_call_forwarding(&self.pipeline())
+    }
+
+    // Forwards voice calls to `number` for `reason`, or stops forwarding
+    // them with None.
+    pub fn set_call_forwarding(&self, reason: supplementary::ForwardingReason, number: Option<&str>,
+                               no_reply_time: Option<u8>) -> Result<(), errors::Error> {
+        try!(self.pipeline().set_call_forwarding(reason, number, no_reply_time)).wait()
+    }
+
+    pub fn call_waiting(&self) -> Result<bool, errors::Error> {
+        try!(self.pipeline().call_waiting()).wait()
+    }
+
+    pub fn set_call_waiting(&self, enabled: bool) -> Result<(), errors::Error> {
+        try!(self.pipeline().set_call_waiting(enabled)).wait()
+    }
+
+    pub fn caller_id_restriction(&self) -> Result<supplementary::CallerIdRestriction, errors::Error> {
+        try!(self.pipeline().caller_id_restriction()).wait()
+    }
+
+    // Whether our number is shown on the calls we make from now on.
+    pub fn set_caller_id_presentation(&self,
+                                      presentation: supplementary::CallerIdPresentation) -> Result<(), errors::Error> {
+        try!(self.pipeline().set_caller_id_presentation(presentation)).wait()
+    }
+
+    // The phonebooks the modem has (the SIM's contacts are in "SM"),
+    // and how full each one is.
+    pub fn phonebook_storages(&self) -> Result<Vec<phonebook::PhonebookStorage>, errors::Error> {
+        phonebook::list_storages(&self.pipeline())
+    }
+
+    pub fn phonebook_entries(&self, storage: &str) -> Result<Vec<phonebook::PhonebookEntry>, errors::Error> {
+        phonebook::read_entries(&self.pipeline(), storage)
+    }
+
+    // The entries whose name starts with `name`.
+    pub fn find_phonebook_entries(&self, storage: &str,
+                                  name: &str) -> Result<Vec<phonebook::PhonebookEntry>, errors::Error> {
+        phonebook::find_entries(&self.pipeline(), storage, name)
+    }
+
+    // Stores an entry at `index`, or in the first free slot without
+    // one.
+    pub fn write_phonebook_entry(&self, storage: &str, index: Option<u32>, number: &str,
+                                 name: &str) -> Result<(), errors::Error> {
+        phonebook::write_entry(&self.pipeline(), storage, index, number, name)
+    }
+
+    pub fn delete_phonebook_entry(&self, storage: &str, index: u32) -> Result<(), errors::Error> {
+        phonebook::delete_entry(&self.pipeline(), storage, index)
+    }
+}
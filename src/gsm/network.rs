@@ -0,0 +1,320 @@
+extern crate chrono;
+
+// Keeps track of whether the modem is registered on a network. The
+// modem reports registration changes with +CREG (circuit switched:
+// calls and SMS) and +CGREG (packet data) once AT+CREG=2/AT+CGREG=2
+// are set (see command::init_sequence). The manager queries both at
+// startup and whenever the modem reboots, and applies the URCs in
+// between.
+//
+// It also follows the radio access technology in use (GSM, EDGE,
+// LTE, ...) for the 2G/3G/4G indicator. SIMCom modems report it with
+// AT+CNSMOD, and send +CNSMOD URCs when it changes if the profile turns
+// them on; on other modems it is read from the <AcT> of AT+COPS?.
+
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use self::chrono::prelude::*;
+
+use gsm::command::Pipeline;
+use gsm::errors::Error;
+use gsm::operator::AccessTechnology;
+use gsm::supervisor;
+use gsm::urc::{Registration, Urc};
+
+// The URCs should keep the state current, but a modem that has just
+// been reconfigured (or drops a URC) is caught by querying again every
+// so often.
+const REGISTRATION_QUERY_INTERVAL_MS: u64 = 60000;
+
+// How many access technology changes are remembered.
+const MAX_MODE_HISTORY: usize = 20;
+
+// The <stat> of +CNSMOD.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum NetworkMode {
+    NoService, // 0
+    Gsm, // 1
+    Gprs, // 2
+    Edge, // 3
+    Wcdma, // 4
+    Hsdpa, // 5
+    Hsupa, // 6
+    Hspa, // 7
+    Lte, // 8
+    TdsCdma, // 9
+    TdsHsdpa, // 10
+    TdsHsupa, // 11
+    TdsHspa, // 12
+    Cdma, // 13
+    Evdo, // 14
+    Hybrid, // 15 (CDMA and EVDO)
+    OneXLte, // 16
+    Ehrpd, // 23
+    HybridEhrpd, // 24 (CDMA and eHRPD)
+    Other(u8),
+}
+
+impl NetworkMode {
+    pub fn from_code(code: u8) -> NetworkMode {
+        match code {
+            0 => NetworkMode::NoService,
+            1 => NetworkMode::Gsm,
+            2 => NetworkMode::Gprs,
+            3 => NetworkMode::Edge,
+            4 => NetworkMode::Wcdma,
+            5 => NetworkMode::Hsdpa,
+            6 => NetworkMode::Hsupa,
+            7 => NetworkMode::Hspa,
+            8 => NetworkMode::Lte,
+            9 => NetworkMode::TdsCdma,
+            10 => NetworkMode::TdsHsdpa,
+            11 => NetworkMode::TdsHsupa,
+            12 => NetworkMode::TdsHspa,
+            13 => NetworkMode::Cdma,
+            14 => NetworkMode::Evdo,
+            15 => NetworkMode::Hybrid,
+            16 => NetworkMode::OneXLte,
+            23 => NetworkMode::Ehrpd,
+            24 => NetworkMode::HybridEhrpd,
+            other => NetworkMode::Other(other),
+        }
+    }
+
+    // The closest mode for the <AcT> reported by AT+COPS?.
+    pub fn from_access_technology(act: AccessTechnology) -> NetworkMode {
+        match act {
+            AccessTechnology::Gsm | AccessTechnology::GsmCompact => NetworkMode::Gsm,
+            AccessTechnology::GsmEgprs => NetworkMode::Edge,
+            AccessTechnology::Utran => NetworkMode::Wcdma,
+            AccessTechnology::UtranHsdpa => NetworkMode::Hsdpa,
+            AccessTechnology::UtranHsupa => NetworkMode::Hsupa,
+            AccessTechnology::UtranHsdpaHsupa => NetworkMode::Hspa,
+            AccessTechnology::EUtran => NetworkMode::Lte,
+            AccessTechnology::Other(code) => NetworkMode::Other(code),
+        }
+    }
+
+    pub fn generation(&self) -> Option<Generation> {
+        match *self {
+            NetworkMode::Gsm | NetworkMode::Gprs | NetworkMode::Edge | NetworkMode::Cdma => Some(Generation::TwoG),
+            NetworkMode::Wcdma | NetworkMode::Hsdpa | NetworkMode::Hsupa | NetworkMode::Hspa |
+            NetworkMode::TdsCdma | NetworkMode::TdsHsdpa | NetworkMode::TdsHsupa | NetworkMode::TdsHspa |
+            NetworkMode::Evdo | NetworkMode::Hybrid | NetworkMode::Ehrpd | NetworkMode::HybridEhrpd => Some(Generation::ThreeG),
+            NetworkMode::Lte | NetworkMode::OneXLte => Some(Generation::FourG),
+            NetworkMode::NoService | NetworkMode::Other(_) => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum Generation {
+    #[serde(rename = "2G")]
+    TwoG,
+    #[serde(rename = "3G")]
+    ThreeG,
+    #[serde(rename = "4G")]
+    FourG,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ModeChange {
+    pub mode: NetworkMode,
+    pub at: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct NetworkState {
+    // None until the modem has answered.
+    pub network: Option<Registration>,
+    pub gprs: Option<Registration>,
+    pub mode: Option<NetworkMode>,
+    // None without service (or before the first answer).
+    pub generation: Option<Generation>,
+    // The most recent access technology changes, oldest first.
+    pub mode_history: Vec<ModeChange>,
+}
+
+impl NetworkState {
+    // Whether calls and SMS can get through.
+    pub fn is_registered(&self) -> bool {
+        self.network.as_ref().map_or(false, |r| r.status.is_registered())
+    }
+}
+
+// A shared view of the registration state.
+#[derive(Clone)]
+pub struct NetworkMonitor(Arc<Mutex<NetworkState>>);
+
+impl NetworkMonitor {
+    fn new() -> NetworkMonitor {
+        NetworkMonitor(Arc::new(Mutex::new(NetworkState {
+            network: None,
+            gprs: None,
+            mode: None,
+            generation: None,
+            mode_history: Vec::new(),
+        })))
+    }
+
+    pub fn state(&self) -> NetworkState {
+        self.0.lock().unwrap().clone()
+    }
+
+    pub fn is_registered(&self) -> bool {
+        self.0.lock().unwrap().is_registered()
+    }
+
+    // For the 2G/3G/4G indicator.
+    pub fn generation(&self) -> Option<Generation> {
+        self.0.lock().unwrap().generation
+    }
+
+    fn set_network(&self, registration: Registration) {
+        let mut state = self.0.lock().unwrap();
+        if state.network.as_ref() != Some(&registration) {
+            println!("network registration: {:?}", registration);
+        }
+        state.network = Some(registration);
+    }
+
+    fn set_gprs(&self, registration: Registration) {
+        self.0.lock().unwrap().gprs = Some(registration);
+    }
+
+    fn set_mode(&self, mode: NetworkMode) {
+        let mut state = self.0.lock().unwrap();
+        if state.mode == Some(mode) {
+            return;
+        }
+
+        state.mode = Some(mode);
+        state.generation = mode.generation();
+        state.mode_history.push(ModeChange {
+            mode: mode,
+            at: Utc::now(),
+        });
+
+        if state.mode_history.len() > MAX_MODE_HISTORY {
+            state.mode_history.remove(0);
+        }
+    }
+}
+
+pub struct NetworkManager {
+    monitor: NetworkMonitor,
+    join_handle: thread::JoinHandle<Result<(), ()>>,
+}
+
+impl NetworkManager {
+    // `urcs` must carry the NetworkRegistration, GprsRegistration,
+    // SystemMode and PowerOn URCs.
+    pub fn new(pipeline: Pipeline, urcs: mpsc::Receiver<Urc>) -> NetworkManager {
+        let monitor = NetworkMonitor::new();
+
+        NetworkManager {
+            join_handle: NetworkManager::start_daemon(pipeline, urcs, monitor.clone()).unwrap(),
+            monitor: monitor,
+        }
+    }
+
+    pub fn get_monitor(&self) -> NetworkMonitor {
+        self.monitor.clone()
+    }
+
+    pub fn exit(self) {
+        println!("exited network manager {:?}", self.join_handle.join());
+    }
+
+    fn query(pipeline: &Pipeline, monitor: &NetworkMonitor) -> Result<(), Error> {
+        let network = pipeline.network_registration();
+        let gprs = pipeline.gprs_registration();
+
+        monitor.set_network(try!(try!(network).wait()));
+
+        // Modems without packet data refuse AT+CGREG.
+        match gprs.and_then(|r| r.wait()) {
+            Ok(registration) => monitor.set_gprs(registration),
+            Err(Error::Disconnected) => return Err(Error::Disconnected),
+            Err(Error::Unavailable) => return Err(Error::Unavailable),
+            Err(_) => {},
+        }
+
+        NetworkManager::query_mode(pipeline, monitor)
+    }
+
+    fn query_mode(pipeline: &Pipeline, monitor: &NetworkMonitor) -> Result<(), Error> {
+        match pipeline.network_system_mode().and_then(|r| r.wait()) {
+            Ok(system_mode) => {
+                monitor.set_mode(system_mode.mode);
+                return Ok(());
+            },
+            Err(Error::Disconnected) => return Err(Error::Disconnected),
+            Err(Error::Unavailable) => return Err(Error::Unavailable),
+            Err(_) => {},
+        }
+
+        // Modems without AT+CNSMOD (or that refuse it) usually report
+        // the technology along with the operator.
+        let operator = try!(try!(pipeline.current_operator()).wait());
+        match (operator.name, operator.access_technology) {
+            (Some(_), Some(act)) => monitor.set_mode(NetworkMode::from_access_technology(act)),
+            (None, _) => monitor.set_mode(NetworkMode::NoService),
+            (Some(_), None) => {},
+        }
+
+        Ok(())
+    }
+
+    fn start_daemon(pipeline: Pipeline, urcs: mpsc::Receiver<Urc>,
+                    monitor: NetworkMonitor) -> io::Result<thread::JoinHandle<Result<(), ()>>> {
+        thread::Builder::new().name("aji/network".to_string()).spawn(
+            move || {
+                let mut query_needed = true;
+                let mut query_interval = REGISTRATION_QUERY_INTERVAL_MS;
+
+                loop {
+                    if query_needed {
+                        query_needed = false;
+                        query_interval = REGISTRATION_QUERY_INTERVAL_MS;
+
+                        match NetworkManager::query(&pipeline, &monitor) {
+                            Ok(_) => {},
+                            Err(Error::Disconnected) => return Err(()),
+                            Err(Error::Unavailable) => {
+                                println!("modem is reconnecting, querying the network registration again soon");
+                                query_interval = supervisor::RETRY_DELAY_MS;
+                            },
+                            Err(e) => println!("could not query the network registration: {:?}", e),
+                        }
+                    }
+
+                    match urcs.recv_timeout(Duration::from_millis(query_interval)) {
+                        Ok(Urc::NetworkRegistration(registration)) => {
+                            monitor.set_network(registration);
+
+                            // A new cell may well use a different
+                            // technology.
+                            match NetworkManager::query_mode(&pipeline, &monitor) {
+                                Err(Error::Disconnected) => return Err(()),
+                                Err(Error::Unavailable) => query_interval = supervisor::RETRY_DELAY_MS,
+                                _ => {},
+                            }
+                        },
+                        Ok(Urc::SystemMode(mode)) => monitor.set_mode(mode),
+                        Ok(Urc::GprsRegistration(registration)) => monitor.set_gprs(registration),
+                        // The modem starts out unregistered after a
+                        // reboot, and may not announce it.
+                        Ok(Urc::PowerOn(_)) => query_needed = true,
+                        Ok(_) => {},
+                        Err(mpsc::RecvTimeoutError::Timeout) => query_needed = true,
+                        Err(mpsc::RecvTimeoutError::Disconnected) => return Err(()),
+                    }
+                }
+            })
+    }
+}
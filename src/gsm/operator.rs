@@ -0,0 +1,266 @@
+extern crate chrono;
+
+// Which network the modem is on and which ones it could use. Listing
+// the networks in range (AT+COPS=?) makes the modem search every band
+// and can take minutes, during which it answers nothing else, so scans
+// only run when somebody asks for one, on their own thread, and the
+// result is kept for whoever wants to show it. Roaming users pin a
+// network with manual selection; automatic selection hands the choice
+// back to the modem.
+
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use self::chrono::prelude::*;
+
+use gsm::command::Pipeline;
+use gsm::errors::Error;
+use gsm::supervisor;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum SelectionMode {
+    Automatic, // 0
+    Manual, // 1
+    Deregistered, // 2
+    // Manual, falling back to automatic if the network is unavailable.
+    ManualAutomatic, // 4
+}
+
+impl SelectionMode {
+    pub fn from_code(code: u8) -> Result<SelectionMode, Error> {
+        match code {
+            0 => Ok(SelectionMode::Automatic),
+            1 => Ok(SelectionMode::Manual),
+            2 => Ok(SelectionMode::Deregistered),
+            4 => Ok(SelectionMode::ManualAutomatic),
+            _ => Err(Error::ParseError),
+        }
+    }
+}
+
+// The <AcT> parameter of +COPS and +CREG.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum AccessTechnology {
+    Gsm, // 0
+    GsmCompact, // 1
+    Utran, // 2
+    GsmEgprs, // 3
+    UtranHsdpa, // 4
+    UtranHsupa, // 5
+    UtranHsdpaHsupa, // 6
+    EUtran, // 7
+    Other(u8),
+}
+
+impl AccessTechnology {
+    pub fn from_code(code: u8) -> AccessTechnology {
+        match code {
+            0 => AccessTechnology::Gsm,
+            1 => AccessTechnology::GsmCompact,
+            2 => AccessTechnology::Utran,
+            3 => AccessTechnology::GsmEgprs,
+            4 => AccessTechnology::UtranHsdpa,
+            5 => AccessTechnology::UtranHsupa,
+            6 => AccessTechnology::UtranHsdpaHsupa,
+            7 => AccessTechnology::EUtran,
+            other => AccessTechnology::Other(other),
+        }
+    }
+
+    pub fn code(&self) -> u8 {
+        match *self {
+            AccessTechnology::Gsm => 0,
+            AccessTechnology::GsmCompact => 1,
+            AccessTechnology::Utran => 2,
+            AccessTechnology::GsmEgprs => 3,
+            AccessTechnology::UtranHsdpa => 4,
+            AccessTechnology::UtranHsupa => 5,
+            AccessTechnology::UtranHsdpaHsupa => 6,
+            AccessTechnology::EUtran => 7,
+            AccessTechnology::Other(code) => code,
+        }
+    }
+}
+
+// An operator name in one of the three formats of +COPS.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum OperatorName {
+    Long(String), // 0
+    Short(String), // 1
+    // MCC followed by the 2 or 3 digit MNC, e.g. 310260.
+    Numeric(String), // 2
+}
+
+impl OperatorName {
+    pub fn from_format(format: u8, name: String) -> Result<OperatorName, Error> {
+        match format {
+            0 => Ok(OperatorName::Long(name)),
+            1 => Ok(OperatorName::Short(name)),
+            2 => Ok(OperatorName::Numeric(name)),
+            _ => Err(Error::ParseError),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CurrentOperator {
+    pub mode: SelectionMode,
+    // None while the modem isn't registered.
+    pub name: Option<OperatorName>,
+    pub access_technology: Option<AccessTechnology>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum OperatorStatus {
+    Unknown, // 0
+    Available, // 1
+    Current, // 2
+    Forbidden, // 3
+}
+
+impl OperatorStatus {
+    pub fn from_code(code: u8) -> Result<OperatorStatus, Error> {
+        match code {
+            0 => Ok(OperatorStatus::Unknown),
+            1 => Ok(OperatorStatus::Available),
+            2 => Ok(OperatorStatus::Current),
+            3 => Ok(OperatorStatus::Forbidden),
+            _ => Err(Error::ParseError),
+        }
+    }
+}
+
+// A network found by a scan.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Operator {
+    pub status: OperatorStatus,
+    pub long_name: String,
+    pub short_name: String,
+    pub numeric: String,
+    pub access_technology: Option<AccessTechnology>,
+}
+
+impl Operator {
+    // The mobile country code.
+    pub fn mcc(&self) -> Option<&str> {
+        self.numeric.get(..3)
+    }
+
+    // The mobile network code.
+    pub fn mnc(&self) -> Option<&str> {
+        self.numeric.get(3..)
+    }
+}
+
+// MCC and MNC, 5 or 6 digits in all.
+pub fn is_valid_numeric(numeric: &str) -> bool {
+    (numeric.len() == 5 || numeric.len() == 6) && numeric.chars().all(|c| c.is_digit(10))
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct OperatorScan {
+    pub operators: Vec<Operator>,
+    // When the operators were listed, None before the first scan.
+    pub scanned_at: Option<DateTime<Utc>>,
+    pub scanning: bool,
+}
+
+enum Request {
+    Scan,
+}
+
+// Asks for scans and reads their results.
+#[derive(Clone)]
+pub struct OperatorPipe {
+    sender: mpsc::Sender<Request>,
+    scan: Arc<Mutex<OperatorScan>>,
+}
+
+impl OperatorPipe {
+    // Starts a scan unless one is already running. The result shows up
+    // in `latest_scan`.
+    pub fn scan(&self) {
+        let mut scan = self.scan.lock().unwrap();
+        if !scan.scanning {
+            scan.scanning = true;
+            self.sender.send(Request::Scan).ok();
+        }
+    }
+
+    pub fn latest_scan(&self) -> OperatorScan {
+        self.scan.lock().unwrap().clone()
+    }
+}
+
+pub struct OperatorScanner {
+    pipe: OperatorPipe,
+    join_handle: thread::JoinHandle<Result<(), ()>>,
+}
+
+impl OperatorScanner {
+    pub fn new(pipeline: Pipeline) -> OperatorScanner {
+        let (send, recv) = mpsc::channel();
+        let scan = Arc::new(Mutex::new(OperatorScan {
+            operators: Vec::new(),
+            scanned_at: None,
+            scanning: false,
+        }));
+
+        OperatorScanner {
+            join_handle: OperatorScanner::start_daemon(pipeline, recv, scan.clone()).unwrap(),
+            pipe: OperatorPipe {
+                sender: send,
+                scan: scan,
+            },
+        }
+    }
+
+    pub fn get_pipe(&self) -> OperatorPipe {
+        self.pipe.clone()
+    }
+
+    pub fn exit(self) {
+        println!("exited operator scanner {:?}", self.join_handle.join());
+    }
+
+    fn start_daemon(pipeline: Pipeline, requests: mpsc::Receiver<Request>,
+                    scan: Arc<Mutex<OperatorScan>>) -> io::Result<thread::JoinHandle<Result<(), ()>>> {
+        thread::Builder::new().name("aji/operators".to_string()).spawn(
+            move || {
+                loop {
+                    match requests.recv() {
+                        Ok(Request::Scan) => {},
+                        Err(_) => return Err(()),
+                    }
+
+                    // The scan stays under way while the modem is
+                    // being reconnected.
+                    let result = loop {
+                        match pipeline.scan_operators().and_then(|r| r.wait()) {
+                            Err(Error::Unavailable) => {
+                                println!("modem is reconnecting, scanning for operators again soon");
+                                thread::sleep(Duration::from_millis(supervisor::RETRY_DELAY_MS));
+                            },
+                            result => break result,
+                        }
+                    };
+
+                    let mut scan = scan.lock().unwrap();
+                    scan.scanning = false;
+
+                    match result {
+                        Ok(operators) => {
+                            scan.operators = operators;
+                            scan.scanned_at = Some(Utc::now());
+                        },
+                        Err(Error::Disconnected) => return Err(()),
+                        // The previous result (if any) is kept.
+                        Err(e) => println!("operator scan failed: {:?}", e),
+                    }
+                }
+            })
+    }
+}
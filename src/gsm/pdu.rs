@@ -703,6 +703,46 @@ fn parse_utf16(data: &[u8], length: usize) -> IResult<&[u8], String> {
     }
 }
 
+pub fn is_hex(data: &str) -> bool {
+    data.len() % 2 == 0 && data.chars().all(|c| c.is_digit(16))
+}
+
+// Unpacks GSM 7-bit text given as hex, the way it appears in a PDU or
+// in a USSD reply. `septets` is the number of characters packed in.
+pub fn decode_gsm_alphabet(hex: &str, septets: usize) -> Result<String, Error> {
+    if !is_hex(hex) || septets > hex.len() / 2 * 8 / 7 {
+        return Err(Error::ParseError);
+    }
+
+    match parse_gsm_alphabet(hex.as_bytes(), septets) {
+        IResult::Done(_, text) => Ok(text),
+        _ => Err(Error::ParseError),
+    }
+}
+
+// Decodes UCS2 text given as hex, four digits per character.
+pub fn decode_ucs2(hex: &str) -> Result<String, Error> {
+    if !is_hex(hex) || hex.len() % 4 != 0 {
+        return Err(Error::ParseError);
+    }
+
+    match parse_utf16(hex.as_bytes(), hex.len() / 2) {
+        IResult::Done(_, text) => Ok(text),
+        _ => Err(Error::ParseError),
+    }
+}
+
+// The reverse of `decode_ucs2`.
+pub fn encode_ucs2(text: &str) -> String {
+    let mut output = Vec::new();
+    for unit in text.encode_utf16() {
+        u8_to_hex((unit >> 8) as u8, &mut output);
+        u8_to_hex((unit & 0b11111111) as u8, &mut output);
+    }
+
+    String::from_utf8(output).unwrap()
+}
+
 #[cfg(test)]
 mod test {
     // TODO: Write some tests so that I don't have to worry so much
@@ -0,0 +1,174 @@
+// The phonebooks on the SIM (and in the modem): the contacts a user
+// brings along in their SIM, the fixed dialing numbers, the last
+// numbers dialed and so on. AT+CPBS picks the storage that the other
+// commands work on.
+//
+// Names are exchanged in UCS2, since the modem's default character set
+// can't represent most of them. The character set and the selected
+// storage are settings of the whole modem, so every command is sent in
+// one line together with the storage it works on and, if it has a name
+// in it, the switch to UCS2 and back, e.g.
+// AT+CSCS="UCS2";+CPBS="SM";+CPBR=1,50;+CSCS="IRA", and nothing else
+// can run in between. Some modems convert the numbers to UCS2 as well
+// (see ModemProfile::ucs2_phonebook_numbers).
+
+use gsm::command::{CommandResult, Pipeline};
+use gsm::errors::Error;
+use gsm::framing::FinalResult;
+use gsm::pdu;
+
+// The contacts on the SIM.
+pub const SIM_STORAGE: &'static str = "SM";
+
+// How many entries are read per AT+CPBR, so that a full SIM doesn't
+// run into the command timeout.
+const READ_CHUNK_SIZE: u32 = 50;
+
+// +CME ERROR: 22, for a search without results or an empty range.
+const CME_NOT_FOUND: u32 = 22;
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PhonebookStorage {
+    // e.g. "SM" for the SIM, "FD" for the fixed dialing numbers.
+    pub storage: String,
+    // None if the storage can't be opened, e.g. without the PIN2.
+    pub used: Option<u32>,
+    pub total: Option<u32>,
+}
+
+// The answer to AT+CPBR=?.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PhonebookRange {
+    pub first: u32,
+    pub last: u32,
+    pub number_length: Option<u32>,
+    pub name_length: Option<u32>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PhonebookEntry {
+    pub index: u32,
+    pub number: String,
+    // 145 for international numbers, 129 otherwise.
+    pub number_type: u8,
+    pub name: String,
+}
+
+// Two capital letters, as every storage in 27.007 is named.
+pub fn is_valid_storage(storage: &str) -> bool {
+    storage.len() == 2 && storage.chars().all(|c| c >= 'A' && c <= 'Z')
+}
+
+// A name as the modem sends it in UCS2. Anything else is kept as it
+// came.
+pub fn decode_name(name: &str) -> String {
+    pdu::decode_ucs2(name).unwrap_or_else(|_| name.to_string())
+}
+
+// Names such as IRA, GSM or 8859-1, which end up in a quoted argument.
+pub fn is_valid_character_set(charset: &str) -> bool {
+    !charset.is_empty() && charset.len() <= 16 && charset.chars().all(|c| c.is_alphanumeric() || c == '-')
+}
+
+// `command` on `storage`.
+pub fn in_storage(storage: &str, command: &str) -> String {
+    format!("AT+CPBS=\"{}\";{}", storage, command)
+}
+
+// `command` on `storage`, with the character set switched to UCS2
+// around it.
+pub fn in_ucs2(storage: &str, command: &str, charset: &str) -> String {
+    format!("AT+CSCS=\"UCS2\";+CPBS=\"{}\";{};+CSCS=\"{}\"", storage, command, charset)
+}
+
+// Runs a command built with `in_ucs2`. If it fails the rest of the line
+// is skipped, so the character set is put back separately.
+fn run_in_ucs2<T, F>(pipeline: &Pipeline, command: F) -> Result<T, Error>
+    where F: FnOnce(&str) -> CommandResult<T> {
+    let charset = try!(try!(pipeline.character_set()).wait());
+
+    let result = command(&charset).and_then(|r| r.wait());
+    if result.is_err() {
+        pipeline.set_character_set(&charset).and_then(|r| r.wait()).ok();
+    }
+
+    result
+}
+
+fn or_none_found(result: Result<Vec<PhonebookEntry>, Error>) -> Result<Vec<PhonebookEntry>, Error> {
+    match result {
+        Err(Error::CommandFailed(FinalResult::CmeError(CME_NOT_FOUND))) => Ok(Vec::new()),
+        result => result,
+    }
+}
+
+// Every storage the modem has, and how full it is. Leaves the last
+// one selected.
+pub fn list_storages(pipeline: &Pipeline) -> Result<Vec<PhonebookStorage>, Error> {
+    let names = try!(try!(pipeline.phonebook_storages()).wait());
+
+    let mut storages = Vec::new();
+    for name in names.into_iter() {
+        let status = pipeline.phonebook_status(&name).and_then(|r| r.wait());
+
+        let (used, total) = match status {
+            Ok(status) => (status.used, status.total),
+            Err(Error::Disconnected) => return Err(Error::Disconnected),
+            Err(Error::Unavailable) => return Err(Error::Unavailable),
+            Err(_) => (None, None),
+        };
+
+        storages.push(PhonebookStorage {
+            storage: name,
+            used: used,
+            total: total,
+        });
+    }
+
+    Ok(storages)
+}
+
+// Every entry in `storage`, in index order.
+pub fn read_entries(pipeline: &Pipeline, storage: &str) -> Result<Vec<PhonebookEntry>, Error> {
+    let range = try!(try!(pipeline.phonebook_range(storage)).wait());
+
+    let mut entries = Vec::new();
+    let mut first = range.first;
+    while first <= range.last {
+        let last = (first + READ_CHUNK_SIZE - 1).min(range.last);
+        let chunk = run_in_ucs2(pipeline, |charset| pipeline.read_phonebook(storage, first, last, charset));
+        entries.extend(try!(or_none_found(chunk)).into_iter());
+        first = last + 1;
+    }
+
+    Ok(entries)
+}
+
+// The entries in `storage` whose name starts with `name`.
+pub fn find_entries(pipeline: &Pipeline, storage: &str, name: &str) -> Result<Vec<PhonebookEntry>, Error> {
+    or_none_found(run_in_ucs2(pipeline, |charset| pipeline.find_phonebook(storage, name, charset)))
+}
+
+// Stores an entry at `index`, or in the first free slot.
+pub fn write_entry(pipeline: &Pipeline, storage: &str, index: Option<u32>, number: &str,
+                   name: &str) -> Result<(), Error> {
+    run_in_ucs2(pipeline, |charset| pipeline.write_phonebook(storage, index, number, name, charset))
+}
+
+pub fn delete_entry(pipeline: &Pipeline, storage: &str, index: u32) -> Result<(), Error> {
+    try!(pipeline.delete_phonebook(storage, index)).wait()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decode_name, in_storage, in_ucs2};
+
+    #[test]
+    fn decodes_ucs2_fields() {
+        assert_eq!(decode_name("004A006F00EB006C"), "Joël");
+        assert_eq!(decode_name("Bob"), "Bob");
+
+        assert_eq!(in_storage("FD", "+CPBS?"), "AT+CPBS=\"FD\";+CPBS?");
+        assert_eq!(in_ucs2("SM", "+CPBR=1,50", "IRA"), "AT+CSCS=\"UCS2\";+CPBS=\"SM\";+CPBR=1,50;+CSCS=\"IRA\"");
+    }
+}
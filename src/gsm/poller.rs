@@ -0,0 +1,164 @@
+// The serial thread sleeps in poll(2) until there is something for it
+// to do: the modem has sent bytes, a command has been queued, or the
+// command in flight has run out of time. Queued commands can't be
+// polled directly (they arrive on an mpsc channel), so every send is
+// followed by a byte on a socket pair that the thread watches next to
+// the port.
+
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::libc;
+
+// Wakes the serial thread up. Dropping every Waker is how the thread
+// learns that nobody can queue commands any more.
+#[derive(Clone, Debug)]
+pub struct Waker(Arc<UnixStream>);
+
+impl Waker {
+    pub fn wake(&self) {
+        // A full socket buffer means the thread has plenty of wake-ups
+        // to process already, so a failed write loses nothing.
+        (&*self.0).write(&[1]).ok();
+    }
+}
+
+pub struct Poller {
+    wake_reader: UnixStream,
+    // Set once every Waker is gone. A closed socket is always
+    // readable, so it has to stop being polled.
+    closed: bool,
+}
+
+// What poll(2) reported.
+#[derive(Debug, Default)]
+pub struct Readiness {
+    pub port: bool,
+    pub woken: bool,
+}
+
+pub fn new() -> io::Result<(Waker, Poller)> {
+    let (writer, reader) = try!(UnixStream::pair());
+    try!(writer.set_nonblocking(true));
+    try!(reader.set_nonblocking(true));
+
+    Ok((Waker(Arc::new(writer)), Poller {
+        wake_reader: reader,
+        closed: false,
+    }))
+}
+
+fn to_millis(timeout: Duration) -> libc::c_int {
+    let millis = timeout.as_secs() * 1000 + ((timeout.subsec_nanos() + 999_999) / 1_000_000) as u64;
+    if millis > libc::c_int::max_value() as u64 {
+        libc::c_int::max_value()
+    } else {
+        millis as libc::c_int
+    }
+}
+
+impl Poller {
+    // Blocks until `port` is readable, a Waker fires or `timeout`
+    // passes. Without a timeout this waits for as long as it takes.
+    pub fn wait(&mut self, port: RawFd, timeout: Option<Duration>) -> io::Result<Readiness> {
+        let mut fds = [
+            libc::pollfd { fd: port, events: libc::POLLIN, revents: 0 },
+            libc::pollfd { fd: self.wake_reader.as_raw_fd(), events: libc::POLLIN, revents: 0 },
+        ];
+        let count = if self.closed { 1 } else { 2 };
+        let millis = timeout.map_or(-1, to_millis);
+
+        let ready = unsafe { libc::poll(fds.as_mut_ptr(), count, millis) };
+        if ready < 0 {
+            let error = io::Error::last_os_error();
+            return if error.kind() == io::ErrorKind::Interrupted {
+                Ok(Readiness::default())
+            } else {
+                Err(error)
+            };
+        }
+
+        let mut readiness = Readiness {
+            // Errors and hang-ups count as readable so that the read
+            // that follows reports them.
+            port: fds[0].revents != 0,
+            woken: false,
+        };
+
+        if count == 2 && fds[1].revents != 0 {
+            readiness.woken = true;
+            self.drain();
+        }
+
+        Ok(readiness)
+    }
+
+    fn drain(&mut self) {
+        let mut buffer = [0u8; 64];
+
+        loop {
+            match self.wake_reader.read(&mut buffer) {
+                Ok(0) => {
+                    self.closed = true;
+                    return;
+                },
+                Ok(_) => {},
+                Err(_) => return,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn wakes_up_for_a_waker() {
+        let (waker, mut poller) = super::new().unwrap();
+        let (port, _modem) = UnixStream::pair().unwrap();
+        let started = Instant::now();
+
+        let waking = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            waker.wake();
+            waker
+        });
+
+        let ready = poller.wait(port.as_raw_fd(), Some(Duration::from_secs(10))).unwrap();
+        assert!(ready.woken);
+        assert!(!ready.port);
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        // The wake-up was used up.
+        let ready = poller.wait(port.as_raw_fd(), Some(Duration::from_millis(10))).unwrap();
+        assert!(!ready.woken);
+
+        // The last Waker going away wakes the thread once, and then
+        // no more.
+        drop(waking.join().unwrap());
+        assert!(poller.wait(port.as_raw_fd(), Some(Duration::from_secs(10))).unwrap().woken);
+        assert!(!poller.wait(port.as_raw_fd(), Some(Duration::from_millis(10))).unwrap().woken);
+    }
+
+    #[test]
+    fn reports_a_readable_port() {
+        let (_waker, mut poller) = super::new().unwrap();
+        let (port, mut modem) = UnixStream::pair().unwrap();
+
+        let ready = poller.wait(port.as_raw_fd(), Some(Duration::from_millis(10))).unwrap();
+        assert!(!ready.port);
+
+        modem.write_all(b"\r\nOK\r\n").unwrap();
+        let ready = poller.wait(port.as_raw_fd(), None).unwrap();
+        assert!(ready.port);
+        assert!(!ready.woken);
+    }
+}
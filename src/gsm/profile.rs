@@ -0,0 +1,360 @@
+// Every modem speaks the 27.005/27.007 basics, but the details differ
+// between vendors: which command reports the radio access technology,
+// how long a network scan or an SMS submission may take, what the
+// modem prints when it boots and which settings it needs before it
+// behaves. A ModemProfile captures those differences. The profile is
+// picked at startup from the modem's answer to ATI (or AT+CGMM), so
+// the same binary runs on every board revision.
+
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use gsm::command::CommandType;
+use gsm::network::NetworkMode;
+use gsm::responses::{parse_number, strip_prefix};
+use gsm::urc::Urc;
+
+pub trait ModemProfile: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    // Timeout for commands of `command_type`, if the vendor needs
+    // something other than the default.
+    fn timeout(&self, _command_type: CommandType) -> Option<Duration> {
+        None
+    }
+
+    // Vendor settings, sent after the standard configuration.
+    fn init_commands(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    // The query for the radio access technology in use, for modems
+    // that have one (AT+CNSMOD? on SIMCom).
+    fn system_mode_query(&self) -> Option<&'static str> {
+        None
+    }
+
+    // The command that reads the SIM's ICCID.
+    fn iccid_command(&self) -> &'static str {
+        "AT+CCID"
+    }
+
+    // The query for the remaining PIN and PUK attempts, for modems
+    // that have one.
+    fn pin_attempts_query(&self) -> Option<&'static str> {
+        None
+    }
+
+    // Whether phonebook numbers are converted to UCS2 along with the
+    // names while AT+CSCS is UCS2, rather than left as they are.
+    fn ucs2_phonebook_numbers(&self) -> bool {
+        false
+    }
+
+    // Vendor-specific unsolicited lines, on top of the standard ones
+    // in urc.rs. Returns the prefix that `line` starts with.
+    fn unsolicited_prefix(&self, _line: &str) -> Option<&'static str> {
+        None
+    }
+
+    fn parse_urc(&self, _line: &str) -> Option<Urc> {
+        None
+    }
+}
+
+fn seconds(seconds: u64) -> Option<Duration> {
+    Some(Duration::from_secs(seconds))
+}
+
+// Used until the modem has been identified, and for modems that
+// don't match any of the profiles below.
+pub struct Generic;
+
+impl ModemProfile for Generic {
+    fn name(&self) -> &'static str {
+        "generic"
+    }
+}
+
+// With AT+CNSMOD=1, SIMCom modems announce a change of access
+// technology with `+CNSMOD: <stat>`. The answer to AT+CNSMOD? has the
+// same prefix but two parameters, and is claimed by the command in
+// flight before it gets here.
+fn simcom_unsolicited_prefix(line: &str) -> Option<&'static str> {
+    strip_prefix(line, "+CNSMOD").map(|_| "+CNSMOD")
+}
+
+fn simcom_parse_urc(line: &str) -> Option<Urc> {
+    strip_prefix(line, "+CNSMOD")
+        .and_then(|stat| parse_number(stat).ok())
+        .map(|code| Urc::SystemMode(NetworkMode::from_code(code)))
+}
+
+// GSM only, so there is no access technology to query.
+pub struct Sim800;
+
+impl ModemProfile for Sim800 {
+    fn name(&self) -> &'static str {
+        "SIMCom SIM800"
+    }
+
+    fn init_commands(&self) -> Vec<String> {
+        // Report the network time with *PSUTTZ.
+        vec!["AT+CLTS=1".to_string()]
+    }
+
+    fn pin_attempts_query(&self) -> Option<&'static str> {
+        Some("AT+SPIC")
+    }
+
+    fn ucs2_phonebook_numbers(&self) -> bool {
+        true
+    }
+
+    fn unsolicited_prefix(&self, line: &str) -> Option<&'static str> {
+        simcom_unsolicited_prefix(line)
+    }
+
+    fn parse_urc(&self, line: &str) -> Option<Urc> {
+        simcom_parse_urc(line)
+    }
+}
+
+pub struct Sim7600;
+
+impl ModemProfile for Sim7600 {
+    fn name(&self) -> &'static str {
+        "SIMCom SIM7600"
+    }
+
+    fn timeout(&self, command_type: CommandType) -> Option<Duration> {
+        match command_type {
+            // A manual selection answers once the LTE attach is
+            // done or has failed.
+            CommandType::SetOperator => seconds(30),
+            _ => None,
+        }
+    }
+
+    fn init_commands(&self) -> Vec<String> {
+        // Network time, and +CNSMOD whenever the access technology
+        // changes (LTE to WCDMA and back is common).
+        vec!["AT+CLTS=1".to_string(), "AT+CNSMOD=1".to_string()]
+    }
+
+    fn system_mode_query(&self) -> Option<&'static str> {
+        Some("AT+CNSMOD?")
+    }
+
+    fn pin_attempts_query(&self) -> Option<&'static str> {
+        Some("AT+SPIC")
+    }
+
+    fn ucs2_phonebook_numbers(&self) -> bool {
+        true
+    }
+
+    fn unsolicited_prefix(&self, line: &str) -> Option<&'static str> {
+        simcom_unsolicited_prefix(line)
+    }
+
+    fn parse_urc(&self, line: &str) -> Option<Urc> {
+        simcom_parse_urc(line)
+    }
+
+    fn iccid_command(&self) -> &'static str {
+        "AT+CICCID"
+    }
+}
+
+// Quectel modems announce the end of SIM initialisation with +QIND
+// rather than SIMCom's "SMS Ready".
+fn quectel_unsolicited_prefix(line: &str) -> Option<&'static str> {
+    if line.starts_with("+QIND:") {
+        Some("+QIND")
+    } else if line == "POWERED DOWN" {
+        Some("POWERED DOWN")
+    } else {
+        None
+    }
+}
+
+fn quectel_parse_urc(line: &str) -> Option<Urc> {
+    match line {
+        "+QIND: SMS DONE" => Some(Urc::PowerOn("SMS DONE".to_string())),
+        "POWERED DOWN" => Some(Urc::UnderVoltage { power_down: true }),
+        _ => None,
+    }
+}
+
+pub struct QuectelEc25;
+
+impl ModemProfile for QuectelEc25 {
+    fn name(&self) -> &'static str {
+        "Quectel EC25"
+    }
+
+    fn timeout(&self, command_type: CommandType) -> Option<Duration> {
+        match command_type {
+            CommandType::SendSMS => seconds(120),
+            // Both search the network, for up to three minutes.
+            CommandType::ScanOperators | CommandType::SetOperator => seconds(180),
+            _ => None,
+        }
+    }
+
+    fn init_commands(&self) -> Vec<String> {
+        // The EC25 sends URCs to its USB ports by default.
+        vec!["AT+QURCCFG=\"urcport\",\"uart1\"".to_string()]
+    }
+
+    fn iccid_command(&self) -> &'static str {
+        "AT+QCCID"
+    }
+
+    fn pin_attempts_query(&self) -> Option<&'static str> {
+        Some("AT+QPINC=\"SC\"")
+    }
+
+    fn unsolicited_prefix(&self, line: &str) -> Option<&'static str> {
+        quectel_unsolicited_prefix(line)
+    }
+
+    fn parse_urc(&self, line: &str) -> Option<Urc> {
+        quectel_parse_urc(line)
+    }
+}
+
+pub struct QuectelM95;
+
+impl ModemProfile for QuectelM95 {
+    fn name(&self) -> &'static str {
+        "Quectel M95"
+    }
+
+    fn timeout(&self, command_type: CommandType) -> Option<Duration> {
+        match command_type {
+            CommandType::ScanOperators | CommandType::SetOperator => seconds(180),
+            _ => None,
+        }
+    }
+
+    fn iccid_command(&self) -> &'static str {
+        "AT+QCCID"
+    }
+
+    fn pin_attempts_query(&self) -> Option<&'static str> {
+        Some("AT+QPINC=\"SC\"")
+    }
+
+    fn unsolicited_prefix(&self, line: &str) -> Option<&'static str> {
+        quectel_unsolicited_prefix(line)
+    }
+
+    fn parse_urc(&self, line: &str) -> Option<Urc> {
+        quectel_parse_urc(line)
+    }
+}
+
+pub struct UbloxSara;
+
+impl ModemProfile for UbloxSara {
+    fn name(&self) -> &'static str {
+        "u-blox SARA"
+    }
+
+    fn timeout(&self, command_type: CommandType) -> Option<Duration> {
+        match command_type {
+            CommandType::SendSMS => seconds(180),
+            CommandType::ScanOperators | CommandType::SetOperator => seconds(180),
+            _ => None,
+        }
+    }
+
+    fn init_commands(&self) -> Vec<String> {
+        // Power saving puts the UART to sleep between commands.
+        vec!["AT+UPSV=0".to_string()]
+    }
+
+    fn pin_attempts_query(&self) -> Option<&'static str> {
+        Some("AT+UPINCNT")
+    }
+}
+
+// Picks the profile for a modem from its identification text (the
+// response to ATI or AT+CGMM).
+pub fn detect(identification: &str) -> Option<Arc<ModemProfile>> {
+    let text = identification.to_uppercase();
+
+    if text.contains("SIM800") {
+        Some(Arc::new(Sim800))
+    } else if text.contains("SIM7600") {
+        Some(Arc::new(Sim7600))
+    } else if text.contains("EC25") {
+        Some(Arc::new(QuectelEc25))
+    } else if text.contains("M95") {
+        Some(Arc::new(QuectelM95))
+    } else if text.contains("SARA") {
+        Some(Arc::new(UbloxSara))
+    } else {
+        None
+    }
+}
+
+// The profile of the modem that is currently attached, shared between
+// the serial thread and everything that builds commands.
+#[derive(Clone)]
+pub struct ProfileHandle(Arc<RwLock<Arc<ModemProfile>>>);
+
+impl ProfileHandle {
+    pub fn new() -> ProfileHandle {
+        ProfileHandle(Arc::new(RwLock::new(Arc::new(Generic))))
+    }
+
+    pub fn get(&self) -> Arc<ModemProfile> {
+        self.0.read().unwrap().clone()
+    }
+
+    pub fn set(&self, profile: Arc<ModemProfile>) {
+        *self.0.write().unwrap() = profile;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{ModemProfile, QuectelEc25, Sim800, Sim7600, detect};
+    use gsm::command::CommandType;
+    use gsm::network::NetworkMode;
+    use gsm::urc::Urc;
+
+    #[test]
+    fn detects_vendor_from_identification() {
+        assert_eq!(detect("SIM800 R14.18").unwrap().name(), "SIMCom SIM800");
+        assert_eq!(detect("Manufacturer: SIMCOM INCORPORATED\nModel: SIMCOM_SIM7600E-H").unwrap().name(), "SIMCom SIM7600");
+        assert_eq!(detect("Quectel\nEC25\nRevision: EC25EFAR06A03M4G").unwrap().name(), "Quectel EC25");
+        assert_eq!(detect("Quectel_Ltd\nQuectel_M95\nRevision: M95FAR02A08").unwrap().name(), "Quectel M95");
+        assert_eq!(detect("SARA-R410M-02B").unwrap().name(), "u-blox SARA");
+        assert!(detect("TELIT LE910").is_none());
+    }
+
+    #[test]
+    fn parses_simcom_system_mode_changes() {
+        assert_eq!(Sim7600.unsolicited_prefix("+CNSMOD: 8"), Some("+CNSMOD"));
+        assert_eq!(Sim7600.parse_urc("+CNSMOD: 8"), Some(Urc::SystemMode(NetworkMode::Lte)));
+        assert_eq!(Sim7600.unsolicited_prefix("+CREG: 1"), None);
+
+        assert_eq!(Sim7600.system_mode_query(), Some("AT+CNSMOD?"));
+        assert_eq!(Sim800.system_mode_query(), None);
+    }
+
+    #[test]
+    fn extends_network_search_timeouts() {
+        assert_eq!(QuectelEc25.timeout(CommandType::SetOperator), Some(Duration::from_secs(180)));
+        assert_eq!(QuectelEc25.timeout(CommandType::ScanOperators), Some(Duration::from_secs(180)));
+        // AT+COPS? only reads the current operator.
+        assert_eq!(QuectelEc25.timeout(CommandType::OperatorSelect), None);
+        assert_eq!(Sim7600.timeout(CommandType::OperatorSelect), None);
+    }
+}
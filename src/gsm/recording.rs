@@ -0,0 +1,315 @@
+extern crate chrono;
+
+// Bugs in the GSM stack are usually reported as "the inbox looks
+// garbled" on a device that nobody can attach a debugger to. Wrapping
+// the transport in a RecordingTransport saves every byte that crosses
+// the serial line, and a ReplayTransport feeds such a session back
+// into the stack on a workstation to reproduce what happened.
+//
+// A session file has one entry per line:
+//
+//   # session <start time, RFC 3339>
+//   <milliseconds since start> <R|W> <bytes in hex>
+//
+// where R is data read from the modem and W is data written to it.
+
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::str;
+use std::time::{Duration, Instant};
+
+use self::chrono::prelude::*;
+
+use super::command;
+use super::transport::Transport;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Direction {
+    Read,
+    Write,
+}
+
+#[derive(Debug, PartialEq)]
+struct Entry {
+    direction: Direction,
+    data: Vec<u8>,
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+fn from_hex(data: &str) -> Option<Vec<u8>> {
+    if data.len() % 2 != 0 {
+        return None;
+    }
+
+    data.as_bytes().chunks(2)
+        .map(|pair| str::from_utf8(pair).ok().and_then(|b| u8::from_str_radix(b, 16).ok()))
+        .collect()
+}
+
+fn invalid_data(line: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("bad session entry {:?}", line))
+}
+
+fn parse_entry(line: &str) -> io::Result<Option<Entry>> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if fields.len() != 3 || fields[0].parse::<u64>().is_err() {
+        return Err(invalid_data(line));
+    }
+
+    let direction = match fields[1] {
+        "R" => Direction::Read,
+        "W" => Direction::Write,
+        _ => return Err(invalid_data(line)),
+    };
+
+    match from_hex(fields[2]) {
+        Some(data) => Ok(Some(Entry { direction: direction, data: data })),
+        None => Err(invalid_data(line)),
+    }
+}
+
+// Passes everything through to `inner` and logs it to a session file.
+pub struct RecordingTransport<T: Transport> {
+    inner: T,
+    log: File,
+    started: Instant,
+}
+
+impl<T: Transport> RecordingTransport<T> {
+    // Appends to `path`, so that the sessions of a port that had to be
+    // reopened end up in the same file.
+    pub fn create<P: AsRef<Path>>(inner: T, path: P) -> io::Result<RecordingTransport<T>> {
+        let mut log = try!(OpenOptions::new().create(true).append(true).open(path));
+        try!(writeln!(log, "# session {}", Utc::now().to_rfc3339()));
+
+        Ok(RecordingTransport {
+            inner: inner,
+            log: log,
+            started: Instant::now(),
+        })
+    }
+
+    fn record(&mut self, direction: Direction, data: &[u8]) -> io::Result<()> {
+        let elapsed = self.started.elapsed();
+        let millis = elapsed.as_secs() * 1000 + (elapsed.subsec_nanos() / 1_000_000) as u64;
+        let tag = if direction == Direction::Read { "R" } else { "W" };
+
+        writeln!(self.log, "{} {} {}", millis, tag, to_hex(data))
+    }
+}
+
+impl<T: Transport> Read for RecordingTransport<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = try!(self.inner.read(buf));
+        if count > 0 {
+            try!(self.record(Direction::Read, &buf[..count]));
+        }
+
+        Ok(count)
+    }
+}
+
+impl<T: Transport> Write for RecordingTransport<T> {
+    // PINs and passwords stay out of the session file.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let count = try!(self.inner.write(buf));
+        try!(self.record(Direction::Write, &command::redact(&buf[..count])));
+
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        try!(self.log.flush());
+        self.inner.flush()
+    }
+}
+
+impl<T: Transport> AsRawFd for RecordingTransport<T> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+impl<T: Transport> Transport for RecordingTransport<T> {
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.inner.set_timeout(timeout)
+    }
+}
+
+// Plays the modem's side of a recorded session. Everything the modem
+// sent up to the next write in the recording is made readable, and
+// the rest is held back until the stack writes as many bytes as were
+// recorded. Writes that differ from the recording are reported, since
+// that means the stack no longer behaves the way it did on the device.
+pub struct ReplayTransport {
+    entries: VecDeque<Entry>,
+    written: Vec<u8>,
+    // The stack reads from `reader`; the replayed data goes in
+    // through `feeder`. A socket gives the event loop something to
+    // poll.
+    reader: UnixStream,
+    feeder: UnixStream,
+}
+
+impl ReplayTransport {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<ReplayTransport> {
+        let file = try!(File::open(path));
+        ReplayTransport::from_reader(BufReader::new(file))
+    }
+
+    pub fn from_reader<R: BufRead>(session: R) -> io::Result<ReplayTransport> {
+        let mut entries = VecDeque::new();
+
+        for line in session.lines() {
+            if let Some(entry) = try!(parse_entry(&try!(line))) {
+                entries.push_back(entry);
+            }
+        }
+
+        let (reader, feeder) = try!(UnixStream::pair());
+
+        let mut transport = ReplayTransport {
+            entries: entries,
+            written: Vec::new(),
+            reader: reader,
+            feeder: feeder,
+        };
+
+        try!(transport.advance());
+        Ok(transport)
+    }
+
+    // Whether every entry of the session has been played back.
+    pub fn is_finished(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn advance(&mut self) -> io::Result<()> {
+        loop {
+            let direction = match self.entries.front() {
+                Some(entry) => entry.direction,
+                None => return Ok(()),
+            };
+
+            match direction {
+                Direction::Read => {
+                    let entry = self.entries.pop_front().unwrap();
+                    try!(self.feeder.write_all(&entry.data));
+                },
+                Direction::Write => {
+                    let expected = self.entries.front().unwrap().data.len();
+                    if self.written.len() < expected {
+                        return Ok(());
+                    }
+
+                    let entry = self.entries.pop_front().unwrap();
+                    let actual: Vec<u8> = self.written.drain(..expected).collect();
+                    if actual != entry.data {
+                        println!("replay diverged: recorded {:?}, wrote {:?}",
+                                 String::from_utf8_lossy(&entry.data), String::from_utf8_lossy(&actual));
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Read for ReplayTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.reader.read(buf) {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                Err(io::Error::new(io::ErrorKind::TimedOut, "read timed out"))
+            },
+            r => r,
+        }
+    }
+}
+
+impl Write for ReplayTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.written.extend_from_slice(buf);
+        try!(self.advance());
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsRawFd for ReplayTransport {
+    fn as_raw_fd(&self) -> RawFd {
+        self.reader.as_raw_fd()
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.reader.set_read_timeout(Some(timeout))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::{Read, Write};
+
+    use gsm::transport;
+    use super::{RecordingTransport, ReplayTransport};
+
+    #[test]
+    fn holds_responses_until_the_command_is_written() {
+        let session = "# session 2017-10-01T12:00:00+00:00\n\
+                       0 R 0D0A52445900\n\
+                       10 W 41540D\n\
+                       15 R 300D\n";
+        let mut replay = ReplayTransport::from_reader(session.as_bytes()).unwrap();
+        let mut buffer = [0u8; 16];
+
+        assert_eq!(replay.read(&mut buffer).unwrap(), 6);
+        assert!(!replay.is_finished());
+
+        replay.write_all(b"AT").unwrap();
+        assert!(!replay.is_finished());
+        replay.write_all(b"\r").unwrap();
+        assert!(replay.is_finished());
+
+        assert_eq!(replay.read(&mut buffer).unwrap(), 2);
+        assert_eq!(&buffer[..2], b"0\r");
+    }
+
+    #[test]
+    fn keeps_pins_out_of_the_session() {
+        let path = env::temp_dir().join("ajidamal-recording-test.log");
+        fs::remove_file(&path).ok();
+
+        let (host, _modem) = transport::memory_pair().unwrap();
+        let mut recording = RecordingTransport::create(host, &path).unwrap();
+        recording.write_all(b"AT+CPIN=\"1234\"\r").unwrap();
+        recording.write_all(b"AT+CSQ\r").unwrap();
+        recording.flush().unwrap();
+
+        let mut session = String::new();
+        File::open(&path).unwrap().read_to_string(&mut session).unwrap();
+        fs::remove_file(&path).ok();
+
+        // "1234" in hex.
+        assert!(!session.contains("31323334"));
+        assert!(session.contains(" W 41542B4350494E3D2A2A2A2A2A2A0D\n"));
+        assert!(session.contains(" W 41542B4353510D\n"));
+    }
+}
@@ -1,26 +1,21 @@
 use std::str;
 
 use gsm;
+use gsm::call::{CallDirection, CallEntry, CallState};
 use gsm::errors::Error;
-use gsm::pdu::parse_pdu;
+use gsm::network::NetworkMode;
+use gsm::operator::{AccessTechnology, CurrentOperator, Operator, OperatorName, OperatorStatus, SelectionMode};
+use gsm::pdu::{self, parse_pdu};
+use gsm::phonebook::{self, PhonebookEntry, PhonebookRange, PhonebookStorage};
+use gsm::sim::{PinAttempts, SimState};
+use gsm::supplementary::{CLASS_VOICE, CallerIdPresentation, CallerIdRestriction, ClirProvisioning, ForwardingRule};
+use gsm::urc::{self, Registration, Urc};
 
-use nom;
+use nom::IResult;
 
-#[derive(Debug, PartialEq)]
-pub enum ResponseCode {
-    Ok = 0
-}
-
-fn parse_response_code(data: &[u8]) -> Result<ResponseCode, super::errors::Error> {
-    if data.len() > 1 {
-        Err(Error::ParseError)
-    } else {
-        match data[0] {
-            b'0' => Ok(ResponseCode::Ok),
-            _ => Err(Error::ParseError),
-        }
-    }
-}
+// Typed results for the commands in `command::Pipeline`. Each parser
+// gets the information lines of a successful response (the final
+// result code has already been stripped off by the event loop).
 
 #[derive(Debug)]
 pub enum MessageStatus {
@@ -46,41 +41,25 @@ fn parse_message_status(data: &[u8]) -> Result<MessageStatus, Error> {
 
 #[derive(Debug)]
 pub struct SMS {
-    index: Option<u32>,
-    status: MessageStatus,
+    pub index: Option<u32>,
+    pub status: MessageStatus,
     pub message: gsm::pdu::Message,
 }
 
-#[derive(Debug)]
-pub struct ReadSMSResponse {
-    sms: SMS,
-    code: ResponseCode,
-}
-
-named!(pub parse_read_sms_response<ReadSMSResponse>, do_parse!(
+named!(pub parse_read_sms_response<SMS>, do_parse!(
     tag_s!("+CMGR: ") >>
     status: map_res!(take_until_and_consume!(","), parse_message_status) >>
     alpha: take_until_and_consume!(",") >>
     length: take_until_and_consume!("\n") >>
     pdu: parse_pdu >>
     tag!("\n") >>
-    code: map_res!(call!(nom::rest), parse_response_code) >>
-    (ReadSMSResponse {
-        sms: SMS {
-            index: None,
-            status: status,
-            message: pdu,
-        },
-        code: code,
+    (SMS {
+        index: None,
+        status: status,
+        message: pdu,
     })
 ));
 
-#[derive(Debug)]
-pub struct ListSMSResponse {
-    pub sms: Vec<SMS>,
-    pub code: ResponseCode,
-}
-
 fn hex_to_u32(data: &[u8]) -> Result<u32, Error> {
     str::from_utf8(data).or(Err(Error::ParseError)).and_then(|s| {
         u32::from_str_radix(s, 16).or(Err(Error::ParseError))
@@ -102,11 +81,743 @@ named!(pub parse_individual_sms_from_list<SMS>, do_parse!(
     })
 ));
 
-named!(pub parse_list_sms_response<ListSMSResponse>, do_parse!(
-    smses: many1!(parse_individual_sms_from_list) >>
-    code: map_res!(call!(nom::rest), parse_response_code) >>
-    (ListSMSResponse {
-        sms: smses,
-        code: ResponseCode::Ok,
+// Runs one of the nom message parsers over a header line and the PDU
+// line that follows it.
+fn parse_sms_entry(header: &str, pdu: &str,
+                   parser: fn(&[u8]) -> IResult<&[u8], SMS>) -> Result<SMS, Error> {
+    let entry = format!("{}\n{}\n", header, pdu);
+
+    match parser(entry.as_bytes()) {
+        IResult::Done(_, sms) => Ok(sms),
+        a => {
+            println!("received error parsing the sms message {:?}", a);
+            println!("response: {}", entry);
+            Err(Error::ParseError)
+        }
+    }
+}
+
+pub fn parse_sms_list(lines: &[String]) -> Result<Vec<SMS>, Error> {
+    // Each message is a +CMGL header followed by the PDU on its own
+    // line. An empty inbox has no lines at all.
+    if lines.len() % 2 != 0 {
+        return Err(Error::ParseError);
+    }
+
+    lines.chunks(2).map(|entry| {
+        parse_sms_entry(&entry[0], &entry[1], parse_individual_sms_from_list)
+    }).collect()
+}
+
+pub fn parse_read_sms(lines: &[String]) -> Result<SMS, Error> {
+    if lines.len() != 2 {
+        return Err(Error::ParseError);
+    }
+
+    parse_sms_entry(&lines[0], &lines[1], parse_read_sms_response)
+}
+
+pub fn parse_empty(_lines: &[String]) -> Result<(), Error> {
+    Ok(())
+}
+
+pub fn parse_lines(lines: &[String]) -> Result<Vec<String>, Error> {
+    Ok(lines.to_vec())
+}
+
+// A response that is a single value, with or without the command's
+// prefix (AT+CGMM answers `SIM800` on some modems and `+CGMM: SIM800`
+// on others).
+pub fn parse_single_value(lines: &[String]) -> Result<String, Error> {
+    let line = match lines.iter().find(|l| !l.trim().is_empty()) {
+        Some(line) => line.trim(),
+        None => return Err(Error::ParseError),
+    };
+
+    let value = if line.starts_with('+') {
+        match line.find(':') {
+            Some(i) => line[i + 1..].trim(),
+            None => line,
+        }
+    } else {
+        line
+    };
+
+    Ok(unquote(value).to_string())
+}
+
+// AT+CGMR: SIMCom prints `Revision:1418B04SIM800L24`, others just the
+// revision or `+CGMR: <revision>`.
+pub fn parse_revision(lines: &[String]) -> Result<String, Error> {
+    let value = try!(parse_single_value(lines));
+
+    if value.starts_with("Revision:") {
+        Ok(value["Revision:".len()..].trim().to_string())
+    } else {
+        Ok(value)
+    }
+}
+
+// +CSPN: "<spn>",<display mode>
+pub fn parse_service_provider(lines: &[String]) -> Result<String, Error> {
+    let parameters = try!(find_parameters(lines, "+CSPN"));
+    match parameters.first() {
+        Some(name) => Ok(unquote(name).to_string()),
+        None => Err(Error::ParseError),
+    }
+}
+
+// +CPIN: <code>
+pub fn parse_sim_state(lines: &[String]) -> Result<SimState, Error> {
+    let parameters = try!(find_parameters(lines, "+CPIN"));
+    match parameters.first() {
+        Some(code) => Ok(SimState::from_code(unquote(code))),
+        None => Err(Error::ParseError),
+    }
+}
+
+// +CLCK: <status>
+pub fn parse_facility_lock(lines: &[String]) -> Result<bool, Error> {
+    let parameters = try!(find_parameters(lines, "+CLCK"));
+    match parameters.first() {
+        Some(status) => Ok(try!(parse_number::<u8>(status)) == 1),
+        None => Err(Error::ParseError),
+    }
+}
+
+// The vendor counters come in two shapes:
+//
+//   +SPIC: <pin1>,<pin2>,<puk1>,<puk2>        (SIMCom)
+//   +UPINCNT: <pin1>,<pin2>,<puk1>,<puk2>     (u-blox)
+//   +QPINC: "SC",<pin1>,<puk1>                (Quectel)
+pub fn parse_pin_attempts(lines: &[String]) -> Result<PinAttempts, Error> {
+    let line = match lines.iter().find(|l| l.starts_with('+')) {
+        Some(line) => line,
+        None => return Err(Error::ParseError),
+    };
+
+    let data = match line.find(':') {
+        Some(i) => &line[i + 1..],
+        None => return Err(Error::ParseError),
+    };
+
+    let parameters = split_parameters(data.trim());
+    let (pin, puk) = match parameters.first() {
+        Some(facility) if facility.starts_with('"') => (parameters.get(1), parameters.get(2)),
+        _ => (parameters.get(0), parameters.get(2)),
+    };
+
+    match (pin, puk) {
+        (Some(pin), Some(puk)) => Ok(PinAttempts {
+            pin: try!(parse_number(pin)),
+            puk: try!(parse_number(puk)),
+        }),
+        _ => Err(Error::ParseError),
+    }
+}
+
+// Finds the parameters of the first line carrying `prefix`. A bare
+// `+XXX:` line has none, which no caller can make sense of.
+fn find_parameters<'a>(lines: &'a [String], prefix: &str) -> Result<Vec<&'a str>, Error> {
+    match lines.iter().filter_map(|line| strip_prefix(line, prefix)).next().map(split_parameters) {
+        Some(ref parameters) if parameters.is_empty() => Err(Error::ParseError),
+        Some(parameters) => Ok(parameters),
+        None => Err(Error::ParseError),
+    }
+}
+
+// 27.007 uses 99 for "not known or not detectable".
+const SIGNAL_UNKNOWN: u8 = 99;
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SignalQuality {
+    pub rssi: u8,
+    pub ber: u8,
+}
+
+impl SignalQuality {
+    // rssi 0 is -113 dBm or less and 31 is -51 dBm or more, in steps
+    // of 2 dBm.
+    pub fn dbm(&self) -> Option<i32> {
+        if self.rssi > 31 {
+            None
+        } else {
+            Some(-113 + 2 * self.rssi as i32)
+        }
+    }
+
+    // Bars for the status bar, from 0 to 4. A phone without a reading
+    // shows no bars.
+    pub fn bars(&self) -> u8 {
+        match self.dbm() {
+            None => 0,
+            Some(dbm) if dbm < -107 => 0,
+            Some(dbm) if dbm < -97 => 1,
+            Some(dbm) if dbm < -87 => 2,
+            Some(dbm) if dbm < -77 => 3,
+            Some(_) => 4,
+        }
+    }
+
+    // The bit error rate class (0-7), only known during a call.
+    pub fn bit_error_rate(&self) -> Option<u8> {
+        if self.ber == SIGNAL_UNKNOWN {
+            None
+        } else {
+            Some(self.ber)
+        }
+    }
+}
+
+pub fn parse_signal_quality(lines: &[String]) -> Result<SignalQuality, Error> {
+    let parameters = try!(find_parameters(lines, "+CSQ"));
+    if parameters.len() != 2 {
+        return Err(Error::ParseError);
+    }
+
+    Ok(SignalQuality {
+        rssi: try!(parse_number(parameters[0])),
+        ber: try!(parse_number(parameters[1])),
     })
-));
+}
+
+// +COPS: <mode>[,<format>,<oper>[,<AcT>]]
+pub fn parse_current_operator(lines: &[String]) -> Result<CurrentOperator, Error> {
+    let parameters = try!(find_parameters(lines, "+COPS"));
+    if parameters.is_empty() {
+        return Err(Error::ParseError);
+    }
+
+    let name = match (parameters.get(1), parameters.get(2)) {
+        (Some(format), Some(name)) => {
+            Some(try!(OperatorName::from_format(try!(parse_number(format)), unquote(name).to_string())))
+        },
+        _ => None,
+    };
+
+    let access_technology = match parameters.get(3) {
+        Some(act) => Some(AccessTechnology::from_code(try!(parse_number(act)))),
+        None => None,
+    };
+
+    Ok(CurrentOperator {
+        mode: try!(SelectionMode::from_code(try!(parse_number(parameters[0])))),
+        name: name,
+        access_technology: access_technology,
+    })
+}
+
+// +COPS: (<stat>,"<long>","<short>","<numeric>"[,<AcT>]),...,,(<modes>),(<formats>)
+//
+// The operators come first; the supported modes and formats after the
+// empty parameter are of no interest.
+pub fn parse_operator_list(lines: &[String]) -> Result<Vec<Operator>, Error> {
+    let parameters = try!(find_parameters(lines, "+COPS"));
+    let mut operators = Vec::new();
+
+    for entry in parameters.iter().take_while(|p| !p.is_empty()) {
+        if !entry.starts_with('(') || !entry.ends_with(')') {
+            return Err(Error::ParseError);
+        }
+
+        let fields = split_parameters(&entry[1..entry.len() - 1]);
+        if fields.len() < 4 {
+            // The list of supported modes, when there are no
+            // operators at all.
+            if fields.iter().any(|f| f.contains('-')) {
+                break;
+            }
+
+            return Err(Error::ParseError);
+        }
+
+        let access_technology = match fields.get(4) {
+            Some(act) => Some(AccessTechnology::from_code(try!(parse_number(act)))),
+            None => None,
+        };
+
+        operators.push(Operator {
+            status: try!(OperatorStatus::from_code(try!(parse_number(fields[0])))),
+            long_name: unquote(fields[1]).to_string(),
+            short_name: unquote(fields[2]).to_string(),
+            numeric: unquote(fields[3]).to_string(),
+            access_technology: access_technology,
+        });
+    }
+
+    Ok(operators)
+}
+
+// +CREG: <n>,<stat>[,<lac>,<ci>[,<act>]]
+//
+// The same as the URC, with the reporting mode in front.
+fn parse_registration_query(lines: &[String], prefix: &str) -> Result<Registration, Error> {
+    let parameters = try!(find_parameters(lines, prefix));
+    if parameters.len() < 2 {
+        return Err(Error::ParseError);
+    }
+
+    urc::parse_registration(&parameters[1..])
+}
+
+pub fn parse_network_registration(lines: &[String]) -> Result<Registration, Error> {
+    parse_registration_query(lines, "+CREG")
+}
+
+pub fn parse_gprs_registration(lines: &[String]) -> Result<Registration, Error> {
+    parse_registration_query(lines, "+CGREG")
+}
+
+fn strip_parentheses(data: &str) -> Result<&str, Error> {
+    if !data.starts_with('(') || !data.ends_with(')') {
+        return Err(Error::ParseError);
+    }
+
+    Ok(&data[1..data.len() - 1])
+}
+
+// +CSCS: "<charset>"
+pub fn parse_character_set(lines: &[String]) -> Result<String, Error> {
+    let parameters = try!(find_parameters(lines, "+CSCS"));
+
+    Ok(unquote(parameters[0]).to_string())
+}
+
+// +CPBS: ("SM","FD",...)
+pub fn parse_phonebook_storages(lines: &[String]) -> Result<Vec<String>, Error> {
+    let parameters = try!(find_parameters(lines, "+CPBS"));
+    let list = try!(strip_parentheses(parameters[0]));
+
+    Ok(split_parameters(list).into_iter()
+       .map(|storage| unquote(storage).to_string())
+       .filter(|storage| !storage.is_empty())
+       .collect())
+}
+
+// +CPBS: "<storage>"[,<used>,<total>]
+pub fn parse_phonebook_status(lines: &[String]) -> Result<PhonebookStorage, Error> {
+    let parameters = try!(find_parameters(lines, "+CPBS"));
+
+    let (used, total) = if parameters.len() >= 3 {
+        (Some(try!(parse_number(parameters[1]))), Some(try!(parse_number(parameters[2]))))
+    } else {
+        (None, None)
+    };
+
+    Ok(PhonebookStorage {
+        storage: unquote(parameters[0]).to_string(),
+        used: used,
+        total: total,
+    })
+}
+
+// +CPBR: (<first>-<last>)[,<nlength>,<tlength>]
+pub fn parse_phonebook_range(lines: &[String]) -> Result<PhonebookRange, Error> {
+    let parameters = try!(find_parameters(lines, "+CPBR"));
+    let range = try!(strip_parentheses(parameters[0]));
+
+    let (first, last) = match range.find('-') {
+        Some(i) => (try!(parse_number(&range[..i])), try!(parse_number(&range[i + 1..]))),
+        None => return Err(Error::ParseError),
+    };
+
+    let length = |i: usize| parameters.get(i).and_then(|l| parse_number(l).ok());
+
+    Ok(PhonebookRange {
+        first: first,
+        last: last,
+        number_length: length(1),
+        name_length: length(2),
+    })
+}
+
+// +CPBR: <index>,"<number>",<type>,"<name>" (or +CPBF: for a search),
+// one line per entry, with the names in UCS2 and the numbers as they
+// are.
+pub fn parse_phonebook_entries(lines: &[String]) -> Result<Vec<PhonebookEntry>, Error> {
+    phonebook_entries(lines, false)
+}
+
+// The same, from a modem that converts the numbers to UCS2 as well
+// (see ModemProfile::ucs2_phonebook_numbers).
+pub fn parse_ucs2_phonebook_entries(lines: &[String]) -> Result<Vec<PhonebookEntry>, Error> {
+    phonebook_entries(lines, true)
+}
+
+fn phonebook_entries(lines: &[String], ucs2_numbers: bool) -> Result<Vec<PhonebookEntry>, Error> {
+    let mut entries = Vec::new();
+
+    for data in lines.iter().filter_map(|line| strip_prefix(line, "+CPBR").or_else(|| strip_prefix(line, "+CPBF"))) {
+        let parameters = split_parameters(data);
+        if parameters.len() < 4 {
+            return Err(Error::ParseError);
+        }
+
+        let number = unquote(parameters[1]);
+        entries.push(PhonebookEntry {
+            index: try!(parse_number(parameters[0])),
+            number: if ucs2_numbers { try!(pdu::decode_ucs2(number)) } else { number.to_string() },
+            number_type: try!(parse_number(parameters[2])),
+            name: phonebook::decode_name(unquote(parameters[3])),
+        });
+    }
+
+    Ok(entries)
+}
+
+// The network's reply, if the modem waited for it before answering
+// AT+CUSD.
+pub fn parse_ussd(lines: &[String]) -> Result<Option<Urc>, Error> {
+    match lines.iter().filter_map(|line| strip_prefix(line, "+CUSD")).next() {
+        Some(data) => urc::parse_ussd(&split_parameters(data)).map(Some),
+        None => Ok(None),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SystemMode {
+    // Whether the modem sends +CNSMOD when the mode changes.
+    pub reporting: bool,
+    pub mode: NetworkMode,
+}
+
+// +CNSMOD: <n>,<stat>
+pub fn parse_system_mode(lines: &[String]) -> Result<SystemMode, Error> {
+    let parameters = try!(find_parameters(lines, "+CNSMOD"));
+    if parameters.len() != 2 {
+        return Err(Error::ParseError);
+    }
+
+    Ok(SystemMode {
+        reporting: try!(parse_number::<u8>(parameters[0])) == 1,
+        mode: NetworkMode::from_code(try!(parse_number(parameters[1]))),
+    })
+}
+
+// +CLCC: <id>,<dir>,<stat>,<mode>,<mpty>[,<number>,<type>[,<alpha>]]
+//
+// One line per call, and no lines at all without a call.
+pub fn parse_call_list(lines: &[String]) -> Result<Vec<CallEntry>, Error> {
+    let mut calls = Vec::new();
+
+    for data in lines.iter().filter_map(|line| strip_prefix(line, "+CLCC")) {
+        let parameters = split_parameters(data);
+        if parameters.len() < 5 {
+            return Err(Error::ParseError);
+        }
+
+        let direction = match try!(parse_number::<u8>(parameters[1])) {
+            0 => CallDirection::Outgoing,
+            1 => CallDirection::Incoming,
+            _ => return Err(Error::ParseError),
+        };
+
+        let number = parameters.get(5).map(|n| unquote(n).to_string()).and_then(|n| {
+            if n.is_empty() { None } else { Some(n) }
+        });
+
+        calls.push(CallEntry {
+            id: try!(parse_number(parameters[0])),
+            direction: direction,
+            state: try!(CallState::from_code(try!(parse_number(parameters[2])))),
+            multiparty: try!(parse_number::<u8>(parameters[4])) == 1,
+            number: number,
+        });
+    }
+
+    Ok(calls)
+}
+
+// +CCFC: <status>,<class>[,<number>,<type>[,<subaddr>,<satype>[,<time>]]]
+//
+// One line per class that has the service, or a single line for all of
+// them when it is off.
+pub fn parse_call_forwarding(lines: &[String]) -> Result<Vec<ForwardingRule>, Error> {
+    let mut rules = Vec::new();
+
+    for data in lines.iter().filter_map(|line| strip_prefix(line, "+CCFC")) {
+        let parameters = split_parameters(data);
+        if parameters.len() < 2 {
+            return Err(Error::ParseError);
+        }
+
+        let number = parameters.get(2).map(|n| unquote(n).to_string()).and_then(|n| {
+            if n.is_empty() { None } else { Some(n) }
+        });
+
+        let no_reply_time = match parameters.get(6) {
+            Some(time) if !time.is_empty() => Some(try!(parse_number(time))),
+            _ => None,
+        };
+
+        rules.push(ForwardingRule {
+            enabled: try!(parse_number::<u8>(parameters[0])) == 1,
+            class: try!(parse_number(parameters[1])),
+            number: number,
+            no_reply_time: no_reply_time,
+        });
+    }
+
+    if rules.is_empty() {
+        return Err(Error::ParseError);
+    }
+
+    Ok(rules)
+}
+
+// +CCWA: <status>,<class>, one line per class as for +CCFC.
+pub fn parse_call_waiting(lines: &[String]) -> Result<bool, Error> {
+    let mut enabled = None;
+
+    for data in lines.iter().filter_map(|line| strip_prefix(line, "+CCWA")) {
+        let parameters = split_parameters(data);
+        if parameters.len() != 2 {
+            return Err(Error::ParseError);
+        }
+
+        let status: u8 = try!(parse_number(parameters[0]));
+        let class: u8 = try!(parse_number(parameters[1]));
+        enabled = Some(enabled.unwrap_or(false) || (status == 1 && class & CLASS_VOICE != 0));
+    }
+
+    enabled.ok_or(Error::ParseError)
+}
+
+// +CLIR: <n>,<m>
+pub fn parse_caller_id_restriction(lines: &[String]) -> Result<CallerIdRestriction, Error> {
+    let parameters = try!(find_parameters(lines, "+CLIR"));
+    if parameters.len() != 2 {
+        return Err(Error::ParseError);
+    }
+
+    Ok(CallerIdRestriction {
+        presentation: try!(CallerIdPresentation::from_code(try!(parse_number(parameters[0])))),
+        provisioning: try!(ClirProvisioning::from_code(try!(parse_number(parameters[1])))),
+    })
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct MessageReference(pub u8);
+
+pub fn parse_message_reference(lines: &[String]) -> Result<MessageReference, Error> {
+    let parameters = try!(find_parameters(lines, "+CMGS"));
+    match parameters.first() {
+        Some(mr) => Ok(MessageReference(try!(parse_number(mr)))),
+        None => Err(Error::ParseError),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ServiceCenter {
+    pub number: String,
+    pub number_type: Option<u8>,
+}
+
+// +CSCA: "<sca>"[,<tosca>]
+pub fn parse_service_center(lines: &[String]) -> Result<ServiceCenter, Error> {
+    let parameters = try!(find_parameters(lines, "+CSCA"));
+    if parameters.is_empty() {
+        return Err(Error::ParseError);
+    }
+
+    let number_type = match parameters.get(1) {
+        Some(t) => Some(try!(parse_number(t))),
+        None => None,
+    };
+
+    Ok(ServiceCenter {
+        number: unquote(parameters[0]).to_string(),
+        number_type: number_type,
+    })
+}
+
+// Returns the parameters of an information response such as
+// `+CSQ: 20,0` if the line carries the given prefix.
+pub fn strip_prefix<'a>(line: &'a str, prefix: &str) -> Option<&'a str> {
+    if line.starts_with(prefix) && line[prefix.len()..].starts_with(':') {
+        Some(line[prefix.len() + 1..].trim())
+    } else {
+        None
+    }
+}
+
+// Splits a parameter list on the commas that are not inside quotes or
+// parentheses. Quotes are left in place so that callers can tell an
+// empty string from an omitted parameter.
+pub fn split_parameters(data: &str) -> Vec<&str> {
+    let mut parameters = Vec::new();
+    let mut quoted = false;
+    let mut depth = 0;
+    let mut start = 0;
+
+    for (i, c) in data.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '(' if !quoted => depth += 1,
+            ')' if !quoted && depth > 0 => depth -= 1,
+            ',' if !quoted && depth == 0 => {
+                parameters.push(data[start..i].trim());
+                start = i + 1;
+            },
+            _ => {},
+        }
+    }
+
+    if !data.is_empty() {
+        parameters.push(data[start..].trim());
+    }
+
+    parameters
+}
+
+pub fn unquote(data: &str) -> &str {
+    data.trim_matches('"')
+}
+
+pub fn parse_number<T: str::FromStr>(data: &str) -> Result<T, Error> {
+    unquote(data).trim().parse::<T>().or(Err(Error::ParseError))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{SignalQuality, parse_call_forwarding, parse_call_list, parse_call_waiting, parse_character_set,
+                parse_current_operator, parse_operator_list, parse_phonebook_entries, parse_phonebook_range,
+                parse_phonebook_status, parse_phonebook_storages, parse_pin_attempts, parse_revision,
+                parse_network_registration, parse_service_provider, parse_signal_quality, parse_single_value,
+                parse_sim_state, parse_sms_list, parse_ucs2_phonebook_entries, split_parameters};
+    use gsm::call::{CallDirection, CallState};
+    use gsm::operator::{AccessTechnology, CurrentOperator, OperatorName, OperatorStatus, SelectionMode};
+    use gsm::sim::{PinAttempts, SimState};
+    use gsm::urc::RegistrationStatus;
+
+    fn lines(data: &[&str]) -> Vec<String> {
+        data.iter().map(|l| l.to_string()).collect()
+    }
+
+    #[test]
+    fn splits_quoted_parameters() {
+        assert_eq!(split_parameters("0,\"a,b\",(1,2),"), vec!["0", "\"a,b\"", "(1,2)", ""]);
+        assert!(split_parameters("").is_empty());
+    }
+
+    #[test]
+    fn parses_sms_list() {
+        let pdu = "07911326040000F0040B911346610089F60000208062917314080CC8F71D14969741F977FD07";
+        let sms = parse_sms_list(&lines(&["+CMGL: 1,1,,30", pdu])).unwrap();
+        assert_eq!(sms.len(), 1);
+        assert_eq!(sms[0].index, Some(1));
+        assert_eq!(sms[0].message.user_data.data, "How are you?");
+
+        assert!(parse_sms_list(&[]).unwrap().is_empty());
+        assert!(parse_sms_list(&lines(&["+CMGL: 1,1,,30"])).is_err());
+    }
+
+    #[test]
+    fn parses_information_responses() {
+        assert_eq!(parse_signal_quality(&lines(&["+CSQ: 20,0"])).unwrap(),
+                   SignalQuality { rssi: 20, ber: 0 });
+        assert_eq!(parse_current_operator(&lines(&["+COPS: 0,0,\"T-Mobile\",7"])).unwrap(),
+                   CurrentOperator { mode: SelectionMode::Automatic,
+                                     name: Some(OperatorName::Long("T-Mobile".to_string())),
+                                     access_technology: Some(AccessTechnology::EUtran) });
+        assert_eq!(parse_current_operator(&lines(&["+COPS: 0"])).unwrap().name, None);
+        assert!(parse_signal_quality(&lines(&["+COPS: 0"])).is_err());
+
+        let signal = parse_signal_quality(&lines(&["+CSQ: 20,99"])).unwrap();
+        assert_eq!((signal.dbm(), signal.bars(), signal.bit_error_rate()), (Some(-73), 4, None));
+        let signal = parse_signal_quality(&lines(&["+CSQ: 99,99"])).unwrap();
+        assert_eq!((signal.dbm(), signal.bars()), (None, 0));
+        assert_eq!(parse_signal_quality(&lines(&["+CSQ: 0,0"])).unwrap().dbm(), Some(-113));
+
+        let registration = parse_network_registration(&lines(&["+CREG: 2,1,\"1A2B\",\"00C3F1\""])).unwrap();
+        assert_eq!(registration.status, RegistrationStatus::RegisteredHome);
+        assert_eq!(registration.cell_id, Some(0xC3F1));
+        assert_eq!(parse_network_registration(&lines(&["+CREG: 2,2"])).unwrap().status, RegistrationStatus::Searching);
+    }
+
+    #[test]
+    fn rejects_bare_prefixes() {
+        assert!(parse_character_set(&lines(&["+CSCS:"])).is_err());
+        assert!(parse_signal_quality(&lines(&["+CSQ: "])).is_err());
+        assert!(parse_phonebook_status(&lines(&["+CPBS:"])).is_err());
+        assert_eq!(parse_character_set(&lines(&["+CSCS: \"IRA\""])).unwrap(), "IRA");
+    }
+
+    #[test]
+    fn parses_operator_list() {
+        let operators = parse_operator_list(&lines(&[
+            "+COPS: (2,\"T-Mobile\",\"TMO\",\"310260\",7),(3,\"AT&T\",\"AT&T\",\"310410\",2),,(0-4),(0-2)"
+        ])).unwrap();
+
+        assert_eq!(operators.len(), 2);
+        assert_eq!(operators[0].status, OperatorStatus::Current);
+        assert_eq!((operators[0].mcc(), operators[0].mnc()), (Some("310"), Some("260")));
+        assert_eq!(operators[1].access_technology, Some(AccessTechnology::Utran));
+        assert!(parse_operator_list(&lines(&["+COPS: ,,(0-4),(0-2)"])).unwrap().is_empty());
+    }
+
+    #[test]
+    fn parses_identification_responses() {
+        assert_eq!(parse_single_value(&lines(&["+QCCID: 89860318740210983456"])).unwrap(), "89860318740210983456");
+        assert_eq!(parse_revision(&lines(&["Revision:1418B04SIM800L24"])).unwrap(), "1418B04SIM800L24");
+        assert_eq!(parse_revision(&lines(&["+CGMR: LE20B04SIM7600M22"])).unwrap(), "LE20B04SIM7600M22");
+        assert_eq!(parse_service_provider(&lines(&["+CSPN: \"Vodafone UK\",0"])).unwrap(), "Vodafone UK");
+    }
+
+    #[test]
+    fn parses_sim_responses() {
+        assert_eq!(parse_sim_state(&lines(&["+CPIN: SIM PIN"])).unwrap(), SimState::PinRequired);
+        assert_eq!(parse_sim_state(&lines(&["+CPIN: PH-SIM PIN"])).unwrap(), SimState::Other("PH-SIM PIN".to_string()));
+        assert_eq!(parse_pin_attempts(&lines(&["+SPIC: 3,3,10,10"])).unwrap(), PinAttempts { pin: 3, puk: 10 });
+        assert_eq!(parse_pin_attempts(&lines(&["+QPINC: \"SC\",2,10"])).unwrap(), PinAttempts { pin: 2, puk: 10 });
+    }
+
+    #[test]
+    fn parses_call_list() {
+        let calls = parse_call_list(&lines(&[
+            "+CLCC: 1,0,0,0,0,\"+15551234567\",145,\"\"",
+            "+CLCC: 2,1,5,0,0,\"\",128",
+        ])).unwrap();
+
+        assert_eq!(calls.len(), 2);
+        assert_eq!((calls[0].direction, calls[0].state), (CallDirection::Outgoing, CallState::Active));
+        assert_eq!(calls[0].number, Some("+15551234567".to_string()));
+        assert_eq!((calls[1].id, calls[1].state, calls[1].number.clone()), (2, CallState::Waiting, None));
+        assert!(parse_call_list(&[]).unwrap().is_empty());
+        assert!(parse_call_list(&lines(&["+CLCC: 1,0,9,0,0"])).is_err());
+    }
+
+    #[test]
+    fn parses_supplementary_services() {
+        let rules = parse_call_forwarding(&lines(&["+CCFC: 1,1,\"+15550000000\",145,,,20"])).unwrap();
+        assert_eq!((rules[0].enabled, rules[0].class, rules[0].no_reply_time), (true, 1, Some(20)));
+        assert_eq!(rules[0].number, Some("+15550000000".to_string()));
+        assert!(!parse_call_forwarding(&lines(&["+CCFC: 0,7"])).unwrap()[0].enabled);
+
+        assert_eq!(parse_call_waiting(&lines(&["+CCWA: 1,1", "+CCWA: 0,4"])).unwrap(), true);
+        assert_eq!(parse_call_waiting(&lines(&["+CCWA: 0,7"])).unwrap(), false);
+    }
+
+    #[test]
+    fn parses_phonebook() {
+        assert_eq!(parse_phonebook_storages(&lines(&["+CPBS: (\"SM\",\"FD\",\"LD\")"])).unwrap(),
+                   vec!["SM".to_string(), "FD".to_string(), "LD".to_string()]);
+
+        let range = parse_phonebook_range(&lines(&["+CPBR: (1-250),40,14"])).unwrap();
+        assert_eq!((range.first, range.last, range.number_length, range.name_length), (1, 250, Some(40), Some(14)));
+
+        let entries = parse_phonebook_entries(&lines(&[
+            "+CPBR: 1,\"+15551234567\",145,\"004A006F00EB006C\"",
+            "+CPBR: 7,\"12345678\",129,\"0042\"",
+        ])).unwrap();
+        assert_eq!((entries[0].index, entries[0].number.as_ref(), entries[0].name.as_ref()), (1, "+15551234567", "Joël"));
+        // Digits that happen to be valid UCS2.
+        assert_eq!((entries[1].index, entries[1].number.as_ref(), entries[1].name.as_ref()), (7, "12345678", "B"));
+
+        let entries = parse_ucs2_phonebook_entries(&lines(&[
+            "+CPBR: 7,\"002A0031003000300023\",129,\"0042\"",
+        ])).unwrap();
+        assert_eq!((entries[0].index, entries[0].number.as_ref(), entries[0].name.as_ref()), (7, "*100#", "B"));
+        assert!(parse_ucs2_phonebook_entries(&lines(&["+CPBR: 1,\"+15551234567\",145,\"0042\""])).is_err());
+    }
+}
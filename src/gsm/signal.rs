@@ -0,0 +1,112 @@
+extern crate chrono;
+
+// Samples the signal strength with AT+CSQ every few seconds so that the
+// status bar and the HTTP API always have a recent reading without
+// asking the modem themselves.
+
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use self::chrono::prelude::*;
+
+use gsm::command::Pipeline;
+use gsm::errors::Error;
+use gsm::responses::SignalQuality;
+use gsm::supervisor;
+
+const SIGNAL_SAMPLE_INTERVAL_MS: u64 = 15000;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SignalReading {
+    // The raw +CSQ values.
+    pub rssi: u8,
+    pub ber: u8,
+    // None when the modem doesn't know (rssi 99).
+    pub dbm: Option<i32>,
+    pub bars: u8,
+    pub sampled_at: DateTime<Utc>,
+}
+
+impl SignalReading {
+    fn new(quality: SignalQuality) -> SignalReading {
+        SignalReading {
+            dbm: quality.dbm(),
+            bars: quality.bars(),
+            rssi: quality.rssi,
+            ber: quality.ber,
+            sampled_at: Utc::now(),
+        }
+    }
+}
+
+// The latest reading, shared with everyone who shows it.
+#[derive(Clone)]
+pub struct SignalMonitor(Arc<Mutex<Option<SignalReading>>>);
+
+impl SignalMonitor {
+    fn new() -> SignalMonitor {
+        SignalMonitor(Arc::new(Mutex::new(None)))
+    }
+
+    // None until the first sample has been taken.
+    pub fn latest(&self) -> Option<SignalReading> {
+        self.0.lock().unwrap().clone()
+    }
+
+    fn set(&self, reading: SignalReading) {
+        *self.0.lock().unwrap() = Some(reading);
+    }
+}
+
+pub struct SignalSampler {
+    monitor: SignalMonitor,
+    join_handle: thread::JoinHandle<Result<(), ()>>,
+}
+
+impl SignalSampler {
+    pub fn new(pipeline: Pipeline) -> SignalSampler {
+        let monitor = SignalMonitor::new();
+
+        SignalSampler {
+            join_handle: SignalSampler::start_daemon(pipeline, monitor.clone()).unwrap(),
+            monitor: monitor,
+        }
+    }
+
+    pub fn get_monitor(&self) -> SignalMonitor {
+        self.monitor.clone()
+    }
+
+    pub fn exit(self) {
+        println!("exited signal sampler {:?}", self.join_handle.join());
+    }
+
+    fn start_daemon(pipeline: Pipeline, monitor: SignalMonitor) -> io::Result<thread::JoinHandle<Result<(), ()>>> {
+        thread::Builder::new().name("aji/signal".to_string()).spawn(
+            move || {
+                loop {
+                    let delay = match pipeline.signal_quality().and_then(|r| r.wait()) {
+                        Ok(quality) => {
+                            monitor.set(SignalReading::new(quality));
+                            SIGNAL_SAMPLE_INTERVAL_MS
+                        },
+                        Err(Error::Disconnected) => return Err(()),
+                        Err(Error::Unavailable) => {
+                            println!("modem is reconnecting, sampling the signal quality again soon");
+                            supervisor::RETRY_DELAY_MS
+                        },
+                        // Keep the last reading; the next sample will
+                        // probably work.
+                        Err(e) => {
+                            println!("could not sample the signal quality: {:?}", e);
+                            SIGNAL_SAMPLE_INTERVAL_MS
+                        },
+                    };
+
+                    thread::sleep(Duration::from_millis(delay));
+                }
+            })
+    }
+}
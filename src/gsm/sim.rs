@@ -0,0 +1,99 @@
+// A PIN-protected SIM refuses everything but AT+CPIN until it has been
+// unlocked, so every SMS command fails with a CME error in the
+// meantime. This module reports what the SIM is waiting for and
+// manages its PIN (entering it, turning the lock on and off and
+// changing it).
+
+use gsm::command::Pipeline;
+use gsm::errors::Error;
+use gsm::framing::FinalResult;
+
+// CME errors that say something about the SIM rather than the command.
+const CME_SIM_NOT_INSERTED: u32 = 10;
+const CME_SIM_PIN_REQUIRED: u32 = 11;
+const CME_SIM_PUK_REQUIRED: u32 = 12;
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub enum SimState {
+    Ready,
+    PinRequired,
+    PukRequired,
+    Pin2Required,
+    Puk2Required,
+    NotInserted,
+    // Anything else the modem reports, such as a phone-to-SIM lock.
+    Other(String),
+}
+
+impl SimState {
+    // The code reported by +CPIN.
+    pub fn from_code(code: &str) -> SimState {
+        match code {
+            "READY" => SimState::Ready,
+            "SIM PIN" => SimState::PinRequired,
+            "SIM PUK" => SimState::PukRequired,
+            "SIM PIN2" => SimState::Pin2Required,
+            "SIM PUK2" => SimState::Puk2Required,
+            // SIM7600 modules say SIM REMOVED.
+            "NOT INSERTED" | "SIM REMOVED" => SimState::NotInserted,
+            other => SimState::Other(other.to_string()),
+        }
+    }
+
+    pub fn is_ready(&self) -> bool {
+        *self == SimState::Ready
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PinAttempts {
+    pub pin: u8,
+    pub puk: u8,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SimStatus {
+    pub state: SimState,
+    // Whether the SIM asks for its PIN at power on. Only known once
+    // the SIM is unlocked.
+    pub pin_enabled: Option<bool>,
+    // Only reported by modems that have a vendor command for it.
+    pub attempts: Option<PinAttempts>,
+}
+
+// PINs are 4 to 8 digits and PUKs 8. Anything else would either be
+// refused by the SIM (using up an attempt) or break the quoting of the
+// command.
+pub fn is_valid_code(code: &str) -> bool {
+    code.len() >= 4 && code.len() <= 8 && code.chars().all(|c| c.is_digit(10))
+}
+
+pub fn query_sim_state(pipeline: &Pipeline) -> Result<SimState, Error> {
+    match pipeline.sim_state().and_then(|r| r.wait()) {
+        // Some modems answer AT+CPIN? with an error instead of a
+        // state when the SIM is missing or locked.
+        Err(Error::CommandFailed(FinalResult::CmeError(CME_SIM_NOT_INSERTED))) => Ok(SimState::NotInserted),
+        Err(Error::CommandFailed(FinalResult::CmeError(CME_SIM_PIN_REQUIRED))) => Ok(SimState::PinRequired),
+        Err(Error::CommandFailed(FinalResult::CmeError(CME_SIM_PUK_REQUIRED))) => Ok(SimState::PukRequired),
+        result => result,
+    }
+}
+
+pub fn query_sim_status(pipeline: &Pipeline) -> Result<SimStatus, Error> {
+    let state = try!(query_sim_state(pipeline));
+
+    let pin_enabled = if state.is_ready() {
+        pipeline.pin_lock_state().and_then(|r| r.wait()).ok()
+    } else {
+        None
+    };
+
+    // Unsupported on modems without a counter command.
+    let attempts = pipeline.pin_attempts().and_then(|r| r.wait()).ok();
+
+    Ok(SimStatus {
+        state: state,
+        pin_enabled: pin_enabled,
+        attempts: attempts,
+    })
+}
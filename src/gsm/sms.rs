@@ -8,8 +8,6 @@ use std::time::Duration;
 
 use gsm;
 
-use nom::IResult;
-
 use self::chrono::prelude::*;
 
 enum AdditionResult {
@@ -75,9 +73,12 @@ impl PartialMessage {
     }
 }
 
+// The reference the network gave the message, or why it wasn't sent.
+pub type SendResult = Result<gsm::responses::MessageReference, gsm::errors::Error>;
+
 pub enum Request {
     GetMessages { response: mpsc::Sender<Vec<Message>> },
-    SendMessage { destination: String, content: String, response: mpsc::Sender<()> }
+    SendMessage { destination: String, content: String, response: mpsc::Sender<SendResult> }
 }
 
 #[derive(Clone, Debug)]
@@ -85,21 +86,22 @@ pub struct MessagingPipe(mpsc::Sender<Request>);
 
 impl MessagingPipe {
     pub fn get_messages(&self) -> mpsc::Receiver<Vec<Message>> {
+        // Without the manager, the receiver reports the disconnection.
         let (send, recv) = mpsc::channel();
         self.0.send(Request::GetMessages {
             response: send,
-        }).unwrap();
+        }).ok();
 
         recv
     }
 
-    pub fn send_message(&self, destination: String, content: String) -> mpsc::Receiver<()> {
+    pub fn send_message(&self, destination: String, content: String) -> mpsc::Receiver<SendResult> {
         let (send, recv) = mpsc::channel();
         self.0.send(Request::SendMessage{
             destination: destination,
             content: content,
             response: send,
-        }).unwrap();
+        }).ok();
 
         recv
     }
@@ -161,7 +163,7 @@ fn parse_messages(msgs: Vec<gsm::responses::SMS>) -> Vec<Message> {
     parsed_messages
 }
 
-fn send_sms(pipeline: &gsm::command::Pipeline, destination: String, content: String) {
+fn send_sms(pipeline: &gsm::command::Pipeline, destination: String, content: String) -> SendResult {
     // TODO: Presumably, this is the layer that would handle breaking long text messages up into
     // shorter ones (or implementing the serialize-side of the concatenation protocol). Right now
     // let's just crash if you have something meaningful to say.
@@ -178,7 +180,7 @@ fn send_sms(pipeline: &gsm::command::Pipeline, destination: String, content: Str
                                                                   gsm::pdu::Number::new_international(destination),
                                                                   gsm::pdu::UserData::new_utf16(content))
         .serialize_to_pdu();
-    pipeline.send_sms(serialized_message, None).unwrap();
+    try!(pipeline.send_sms(serialized_message)).wait()
 }
 
 struct MessageData {
@@ -186,12 +188,12 @@ struct MessageData {
 }
 
 impl MessagingManager {
-    pub fn new(pipeline: gsm::command::Pipeline) -> MessagingManager {
+    pub fn new(pipeline: gsm::command::Pipeline, new_messages: mpsc::Receiver<gsm::urc::Urc>) -> MessagingManager {
         let (send, recv) = mpsc::channel::<Request>();
 
         // Just crash if the program didn't start the thread
         // successfully for now.
-        let join_handle = MessagingManager::start_daemon(pipeline, recv).unwrap();
+        let join_handle = MessagingManager::start_daemon(pipeline, recv, new_messages).unwrap();
 
         MessagingManager {
             cmd_send: MessagingPipe(send),
@@ -209,7 +211,8 @@ impl MessagingManager {
     }
 
     fn start_daemon(pipeline: gsm::command::Pipeline,
-                    cmd_recv: mpsc::Receiver<Request>) -> io::Result<thread::JoinHandle<Result<(), ()>>> {
+                    cmd_recv: mpsc::Receiver<Request>,
+                    new_messages: mpsc::Receiver<gsm::urc::Urc>) -> io::Result<thread::JoinHandle<Result<(), ()>>> {
         thread::Builder::new().name("aji/sms".to_string()).spawn(
             move || {
                 // At the moment, this thread just loops and
@@ -239,24 +242,31 @@ impl MessagingManager {
                 let mut iteration = 0;
                 let sms_load_frequency = 1000;
 
-                let (load_callback, load_response) = mpsc::channel();
-                let mut waiting_for_load = false;
+                let mut pending_load: Option<gsm::command::Response<Vec<gsm::responses::SMS>>> = None;
+                let mut reload_requested = false;
 
                 loop {
                     iteration = (iteration + 1) % sms_load_frequency;
-                    if !waiting_for_load {
+
+                    // A +CMTI means a message just landed on the SIM,
+                    // so there's no point waiting for the next poll.
+                    if let Ok(gsm::urc::Urc::NewMessage { .. }) = new_messages.try_recv() {
+                        reload_requested = true;
+                    }
+
+                    if pending_load.is_none() {
                         // Send-side of the loop
 
                         // Attempt to send a list_sms message
-                        if iteration == 0 {
-                            match pipeline.list_sms(gsm::command::SMSStore::All, Some(load_callback.clone())) {
-                                Ok(_) => (),
+                        if iteration == 0 || reload_requested {
+                            reload_requested = false;
+                            match pipeline.list_sms(gsm::command::SMSStore::All) {
+                                Ok(response) => pending_load = Some(response),
                                 Err(a) => {
                                     println!("received error sending list sms command {:?}, quitting", a);
                                     return Err(());
                                 }
                             };
-                            waiting_for_load = true;
                         }
 
                         // Check the request queue to see if there is anything to process
@@ -271,30 +281,29 @@ impl MessagingManager {
                                 content,
                                 response
                             }) => {
-                                send_sms(&pipeline, destination, content);
-                                response.send(()).unwrap();
+                                response.send(send_sms(&pipeline, destination, content)).ok();
                             },
                             Err(_) => (),
                         };
 
                     } else {
-                        match load_response.try_recv() {
-                            Ok((typ, response)) => {
-                                assert!(typ == gsm::command::CommandType::ListSMS);
-                                waiting_for_load = false;
-
-                                match gsm::responses::parse_list_sms_response(response.as_bytes()) {
-                                    IResult::Done(_, list_output) => {
-                                        if list_output.code != gsm::responses::ResponseCode::Ok {
-                                            return Err(())
-                                        }
+                        let result = pending_load.as_ref().and_then(|response| response.try_wait());
+                        match result {
+                            Some(result) => {
+                                pending_load = None;
 
+                                match result {
+                                    Ok(sms) => {
                                         // Update the data with the parsed messages
-                                        data.messages = parse_messages(list_output.sms);
+                                        data.messages = parse_messages(sms);
                                     },
-                                    a => {
-                                        println!("received error parsing the sms messages {:?}", a);
-                                        println!("response: {}", response);
+                                    Err(gsm::errors::Error::Disconnected) => return Err(()),
+                                    // The next poll lists them again.
+                                    Err(gsm::errors::Error::Unavailable) => {
+                                        println!("modem is reconnecting, loading the sms messages later");
+                                    },
+                                    Err(a) => {
+                                        println!("received error loading the sms messages {:?}", a);
                                     }
                                 }
 
@@ -307,7 +316,7 @@ impl MessagingManager {
                                 // on S3, but it looks like disk storage will have to be good for
                                 // now before I get TCP over the modem working.
                             },
-                            Err(_) => (),
+                            None => (),
                         }
                     }
 
@@ -0,0 +1,221 @@
+// The modem can stop answering, disappear (a USB modem being reset, a
+// TCP link dropping) or reboot on its own after a brown-out. The
+// supervisor watches the serial thread for those cases, reopens the
+// port when it has to, and keeps a health state that the rest of the
+// system can show to the user.
+
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// This many commands in a row without any answer from the modem means
+// that it is wedged rather than just slow.
+const MAX_CONSECUTIVE_TIMEOUTS: u32 = 3;
+
+// Reopening the port backs off from the first delay to the last one
+// while the device stays gone.
+const FIRST_RECONNECT_DELAY_MS: u64 = 500;
+const MAX_RECONNECT_DELAY_MS: u64 = 30000;
+
+// How long the managers wait before they try again after a command was
+// refused with Error::Unavailable.
+pub const RETRY_DELAY_MS: u64 = 5000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum ModemHealth {
+    // The port is open but the modem hasn't answered yet.
+    Starting,
+    Healthy,
+    // Commands are timing out, but not enough of them to give up.
+    Unresponsive,
+    // The port is being reopened or the modem reconfigured.
+    Recovering,
+    // The port is gone and there is no way to open it again.
+    Disconnected,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct HealthReport {
+    pub state: ModemHealth,
+    // How many times the port has been reopened or the configuration
+    // replayed since startup.
+    pub restarts: u32,
+    pub consecutive_timeouts: u32,
+}
+
+// A shared view of the modem's health.
+#[derive(Clone)]
+pub struct HealthMonitor(Arc<Mutex<HealthReport>>);
+
+impl HealthMonitor {
+    pub fn new() -> HealthMonitor {
+        HealthMonitor(Arc::new(Mutex::new(HealthReport {
+            state: ModemHealth::Starting,
+            restarts: 0,
+            consecutive_timeouts: 0,
+        })))
+    }
+
+    pub fn report(&self) -> HealthReport {
+        self.0.lock().unwrap().clone()
+    }
+
+    pub fn state(&self) -> ModemHealth {
+        self.0.lock().unwrap().state
+    }
+
+    fn update<F: FnOnce(&mut HealthReport)>(&self, f: F) {
+        f(&mut self.0.lock().unwrap());
+    }
+}
+
+// Opens the transport to the modem again after it failed.
+pub type Connector<T> = Box<FnMut() -> io::Result<T> + Send>;
+
+pub struct Supervisor<T> {
+    connector: Option<Connector<T>>,
+    health: HealthMonitor,
+}
+
+impl<T> Supervisor<T> {
+    pub fn new(connector: Option<Connector<T>>, health: HealthMonitor) -> Supervisor<T> {
+        Supervisor {
+            connector: connector,
+            health: health,
+        }
+    }
+
+    // Called for every line the modem sends: a modem that talks is
+    // not wedged.
+    pub fn modem_answered(&self) {
+        self.health.update(|report| {
+            report.state = ModemHealth::Healthy;
+            report.consecutive_timeouts = 0;
+        });
+    }
+
+    // Called when a command ran out of time and retries. Returns true
+    // once the modem should be considered wedged.
+    pub fn command_timed_out(&self) -> bool {
+        let mut wedged = false;
+
+        self.health.update(|report| {
+            report.consecutive_timeouts += 1;
+            report.state = ModemHealth::Unresponsive;
+            wedged = report.consecutive_timeouts >= MAX_CONSECUTIVE_TIMEOUTS;
+        });
+
+        wedged
+    }
+
+    // Called when the modem announced that it has (re)booted, so its
+    // configuration has to be sent again.
+    pub fn modem_restarted(&self) {
+        self.health.update(|report| {
+            report.state = ModemHealth::Recovering;
+            report.restarts += 1;
+        });
+    }
+
+    // Keeps trying to open the port again, calling `wait` with the
+    // delay before each attempt. Returns None when the transport can't
+    // be reopened at all.
+    pub fn reconnect<F: FnMut(Duration)>(&mut self, mut wait: F) -> Option<T> {
+        let connector = match self.connector {
+            Some(ref mut connector) => connector,
+            None => {
+                self.health.update(|report| report.state = ModemHealth::Disconnected);
+                return None;
+            }
+        };
+
+        self.health.update(|report| {
+            report.state = ModemHealth::Recovering;
+            report.restarts += 1;
+            report.consecutive_timeouts = 0;
+        });
+
+        let mut delay = FIRST_RECONNECT_DELAY_MS;
+
+        loop {
+            wait(Duration::from_millis(delay));
+
+            match connector() {
+                Ok(transport) => {
+                    self.health.update(|report| report.state = ModemHealth::Starting);
+                    return Some(transport);
+                },
+                Err(e) => {
+                    println!("Could not reopen the modem: {:?}", e);
+                    delay = (delay * 2).min(MAX_RECONNECT_DELAY_MS);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::time::Duration;
+
+    use super::{HealthMonitor, ModemHealth, Supervisor};
+
+    #[test]
+    fn tracks_health() {
+        let health = HealthMonitor::new();
+        let supervisor: Supervisor<()> = Supervisor::new(None, health.clone());
+        assert_eq!(health.state(), ModemHealth::Starting);
+
+        supervisor.modem_answered();
+        assert_eq!(health.state(), ModemHealth::Healthy);
+
+        assert!(!supervisor.command_timed_out());
+        assert!(!supervisor.command_timed_out());
+        assert_eq!(health.state(), ModemHealth::Unresponsive);
+        // Any answer resets the count.
+        supervisor.modem_answered();
+        assert!(!supervisor.command_timed_out());
+        assert!(!supervisor.command_timed_out());
+        assert!(supervisor.command_timed_out());
+        assert_eq!(health.report().consecutive_timeouts, 3);
+
+        supervisor.modem_restarted();
+        assert_eq!(health.state(), ModemHealth::Recovering);
+        assert_eq!(health.report().restarts, 1);
+    }
+
+    #[test]
+    fn reconnects_with_backoff() {
+        let health = HealthMonitor::new();
+        let mut attempts = 0;
+        let mut supervisor = Supervisor::new(Some(Box::new(move || {
+            attempts += 1;
+            if attempts < 4 {
+                Err(io::Error::new(io::ErrorKind::NotFound, "no such device"))
+            } else {
+                Ok(attempts)
+            }
+        })), health.clone());
+
+        supervisor.command_timed_out();
+        let mut delays = Vec::new();
+        assert_eq!(supervisor.reconnect(|delay| delays.push(delay)), Some(4));
+        assert_eq!(delays, vec![Duration::from_millis(500), Duration::from_millis(1000),
+                                Duration::from_millis(2000), Duration::from_millis(4000)]);
+
+        let report = health.report();
+        assert_eq!(report.state, ModemHealth::Starting);
+        assert_eq!(report.restarts, 1);
+        assert_eq!(report.consecutive_timeouts, 0);
+    }
+
+    #[test]
+    fn gives_up_without_a_connector() {
+        let health = HealthMonitor::new();
+        let mut supervisor: Supervisor<()> = Supervisor::new(None, health.clone());
+
+        assert_eq!(supervisor.reconnect(|_| panic!("nothing to wait for")), None);
+        assert_eq!(health.state(), ModemHealth::Disconnected);
+    }
+}
@@ -0,0 +1,185 @@
+// Supplementary services: call forwarding (AT+CCFC), call waiting
+// (AT+CCWA), hiding our number from the people we call (AT+CLIR) and
+// juggling several calls at once (AT+CHLD). Forwarding and waiting are
+// stored by the network rather than the modem, so every query and
+// change is a round trip to it and can take several seconds.
+//
+// Only the voice class is dealt with; data and fax forwarding are of
+// no use on this device.
+
+use gsm::command::Pipeline;
+use gsm::errors::Error;
+
+// The voice bit of the 27.007 <class> bitmask.
+pub const CLASS_VOICE: u8 = 1;
+
+// How long an unanswered call rings before it is forwarded, in seconds.
+const MIN_NO_REPLY_TIME: u8 = 1;
+const MAX_NO_REPLY_TIME: u8 = 30;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ForwardingReason {
+    Unconditional, // 0
+    Busy, // 1
+    NoReply, // 2
+    NotReachable, // 3
+}
+
+impl ForwardingReason {
+    pub fn code(&self) -> u8 {
+        match *self {
+            ForwardingReason::Unconditional => 0,
+            ForwardingReason::Busy => 1,
+            ForwardingReason::NoReply => 2,
+            ForwardingReason::NotReachable => 3,
+        }
+    }
+
+    pub fn all() -> [ForwardingReason; 4] {
+        [ForwardingReason::Unconditional, ForwardingReason::Busy, ForwardingReason::NoReply,
+         ForwardingReason::NotReachable]
+    }
+}
+
+// One line of +CCFC.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ForwardingRule {
+    pub enabled: bool,
+    pub class: u8,
+    pub number: Option<String>,
+    // Only for NoReply.
+    pub no_reply_time: Option<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CallForwarding {
+    pub reason: ForwardingReason,
+    pub enabled: bool,
+    pub number: Option<String>,
+    pub no_reply_time: Option<u8>,
+}
+
+pub fn is_valid_no_reply_time(seconds: u8) -> bool {
+    seconds >= MIN_NO_REPLY_TIME && seconds <= MAX_NO_REPLY_TIME
+}
+
+// The voice forwarding for every reason.
+pub fn query_call_forwarding(pipeline: &Pipeline) -> Result<Vec<CallForwarding>, Error> {
+    // Queued together so that the network round trips overlap with
+    // nothing else in between.
+    let responses: Vec<_> = ForwardingReason::all().iter()
+        .map(|&reason| (reason, pipeline.call_forwarding(reason)))
+        .collect();
+
+    let mut forwarding = Vec::new();
+    for (reason, response) in responses.into_iter() {
+        let rules = try!(try!(response).wait());
+
+        // A disabled service is reported once for all classes.
+        let voice = rules.into_iter().find(|r| r.enabled && r.class & CLASS_VOICE != 0);
+        forwarding.push(CallForwarding {
+            reason: reason,
+            enabled: voice.is_some(),
+            number: voice.as_ref().and_then(|r| r.number.clone()),
+            no_reply_time: voice.as_ref().and_then(|r| r.no_reply_time),
+        });
+    }
+
+    Ok(forwarding)
+}
+
+// The <n> of AT+CLIR: whether our number is shown to the people we
+// call.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum CallerIdPresentation {
+    // Whatever the subscription says.
+    Default, // 0
+    Hidden, // 1
+    Shown, // 2
+}
+
+impl CallerIdPresentation {
+    pub fn from_code(code: u8) -> Result<CallerIdPresentation, Error> {
+        match code {
+            0 => Ok(CallerIdPresentation::Default),
+            1 => Ok(CallerIdPresentation::Hidden),
+            2 => Ok(CallerIdPresentation::Shown),
+            _ => Err(Error::ParseError),
+        }
+    }
+
+    pub fn code(&self) -> u8 {
+        match *self {
+            CallerIdPresentation::Default => 0,
+            CallerIdPresentation::Hidden => 1,
+            CallerIdPresentation::Shown => 2,
+        }
+    }
+}
+
+// The <m> of +CLIR: what the subscription allows.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum ClirProvisioning {
+    NotProvisioned, // 0
+    // The number is always hidden.
+    Permanent, // 1
+    Unknown, // 2
+    // Hidden unless the caller asks otherwise.
+    TemporaryRestricted, // 3
+    // Shown unless the caller asks otherwise.
+    TemporaryAllowed, // 4
+}
+
+impl ClirProvisioning {
+    pub fn from_code(code: u8) -> Result<ClirProvisioning, Error> {
+        match code {
+            0 => Ok(ClirProvisioning::NotProvisioned),
+            1 => Ok(ClirProvisioning::Permanent),
+            2 => Ok(ClirProvisioning::Unknown),
+            3 => Ok(ClirProvisioning::TemporaryRestricted),
+            4 => Ok(ClirProvisioning::TemporaryAllowed),
+            _ => Err(Error::ParseError),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CallerIdRestriction {
+    // Forgotten by most modems when they restart.
+    pub presentation: CallerIdPresentation,
+    pub provisioning: ClirProvisioning,
+}
+
+// The operations of AT+CHLD. The numbered ones take the call index
+// from +CLCC (Call::id).
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum HoldAction {
+    // Ends the held calls, or turns down a waiting call.
+    ReleaseHeld, // 0
+    // Ends the active calls and picks up the held or waiting one.
+    ReleaseActive, // 1
+    ReleaseCall(u32), // 1x
+    // Holds the active calls and picks up the held or waiting one.
+    Swap, // 2
+    // Holds every call but this one.
+    Split(u32), // 2x
+    // Joins the active and held calls.
+    Conference, // 3
+    // Connects the two other parties and leaves the call.
+    Transfer, // 4
+}
+
+impl HoldAction {
+    // The argument of AT+CHLD.
+    pub fn argument(&self) -> String {
+        match *self {
+            HoldAction::ReleaseHeld => "0".to_string(),
+            HoldAction::ReleaseActive => "1".to_string(),
+            HoldAction::ReleaseCall(id) => format!("1{}", id),
+            HoldAction::Swap => "2".to_string(),
+            HoldAction::Split(id) => format!("2{}", id),
+            HoldAction::Conference => "3".to_string(),
+            HoldAction::Transfer => "4".to_string(),
+        }
+    }
+}
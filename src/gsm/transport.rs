@@ -0,0 +1,257 @@
+// Everything above this module only needs a stream of bytes to talk
+// to the modem. Keeping the concrete stream behind a trait means the
+// GSM stack can run over the real serial port on the device, a pty or
+// TCP socket on a development machine, or an in-memory pipe in tests.
+
+use std::ffi::CStr;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+use super::config::{FlowControl, ModemConfig, Parity};
+use super::libc;
+use super::serial::{self, SerialPort};
+
+// The file descriptor is what the event loop polls for readability.
+pub trait Transport: Read + Write + AsRawFd + Send {
+    // Bound how long a read may block. A read that runs out of time
+    // must fail with `io::ErrorKind::TimedOut` since that is how the
+    // event loop notices that the modem has gone quiet.
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()>;
+}
+
+impl Transport for serial::SystemPort {
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        try!(SerialPort::set_timeout(self, timeout));
+        Ok(())
+    }
+}
+
+// Opens the configured serial device (this works equally well for a
+// pty) and sets up the line for the modem.
+pub fn open_serial_port(config: &ModemConfig) -> io::Result<serial::SystemPort> {
+    let mut port = try!(serial::open(&config.port));
+
+    let flow_control = match config.flow_control {
+        FlowControl::None => serial::FlowNone,
+        FlowControl::Software => serial::FlowSoftware,
+        FlowControl::Hardware => serial::FlowHardware,
+    };
+
+    let parity = match config.parity {
+        Parity::None => serial::ParityNone,
+        Parity::Odd => serial::ParityOdd,
+        Parity::Even => serial::ParityEven,
+    };
+
+    try!(port.reconfigure(&|settings| {
+        try!(settings.set_baud_rate(serial::BaudRate::from_speed(config.baud_rate)));
+        settings.set_char_size(serial::Bits8);
+        settings.set_parity(parity);
+        settings.set_stop_bits(serial::Stop1);
+        settings.set_flow_control(flow_control);
+        Ok(())
+    }));
+
+    Ok(port)
+}
+
+// Sockets report an expired read timeout as `WouldBlock` on unix, so
+// translate that into the error the serial port would have returned.
+fn normalize_timeout<T>(result: io::Result<T>) -> io::Result<T> {
+    match result {
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+            Err(io::Error::new(io::ErrorKind::TimedOut, "read timed out"))
+        },
+        r => r,
+    }
+}
+
+// A modem reachable over TCP, e.g. a board sharing its serial port
+// with `ser2net` or the emulator listening on a socket.
+#[derive(Debug)]
+pub struct TcpTransport(TcpStream);
+
+impl TcpTransport {
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<TcpTransport> {
+        let stream = try!(TcpStream::connect(addr));
+        try!(stream.set_nodelay(true));
+        Ok(TcpTransport(stream))
+    }
+}
+
+impl Read for TcpTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        normalize_timeout(self.0.read(buf))
+    }
+}
+
+impl Write for TcpTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl AsRawFd for TcpTransport {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+impl Transport for TcpTransport {
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.0.set_read_timeout(Some(timeout))
+    }
+}
+
+// One end of an in-memory duplex pipe. Whatever is written to one end
+// can be read from the other.
+#[derive(Debug)]
+pub struct MemoryTransport(UnixStream);
+
+pub fn memory_pair() -> io::Result<(MemoryTransport, MemoryTransport)> {
+    let (left, right) = try!(UnixStream::pair());
+    Ok((MemoryTransport(left), MemoryTransport(right)))
+}
+
+impl Read for MemoryTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        normalize_timeout(self.0.read(buf))
+    }
+}
+
+impl Write for MemoryTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl AsRawFd for MemoryTransport {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.0.set_read_timeout(Some(timeout))
+    }
+}
+
+// The master side of a pseudo-terminal. Anything that can open a
+// serial port (including `open_serial_port`) can open the slave side
+// and talk to whatever is driving the master, which is how the modem
+// emulator presents itself to the rest of the system.
+#[derive(Debug)]
+pub struct PtyTransport {
+    master: File,
+    // Holding the slave open keeps reads on the master from failing
+    // with EIO while no client is attached.
+    slave: File,
+    slave_path: String,
+    timeout: Option<Duration>,
+}
+
+fn check_libc(result: libc::c_int) -> io::Result<libc::c_int> {
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+impl PtyTransport {
+    pub fn open() -> io::Result<PtyTransport> {
+        unsafe {
+            let master_fd = try!(check_libc(libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY)));
+            let master = File::from_raw_fd(master_fd);
+
+            try!(check_libc(libc::grantpt(master_fd)));
+            try!(check_libc(libc::unlockpt(master_fd)));
+
+            let name = libc::ptsname(master_fd);
+            if name.is_null() {
+                return Err(io::Error::last_os_error());
+            }
+            let slave_path = CStr::from_ptr(name).to_string_lossy().into_owned();
+
+            let slave = try!(OpenOptions::new().read(true).write(true)
+                             .custom_flags(libc::O_NOCTTY).open(&slave_path));
+
+            // Put the line discipline into raw mode so that carriage
+            // returns and control characters pass through untouched.
+            let mut settings: libc::termios = mem::zeroed();
+            try!(check_libc(libc::tcgetattr(slave.as_raw_fd(), &mut settings)));
+            libc::cfmakeraw(&mut settings);
+            try!(check_libc(libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &settings)));
+
+            Ok(PtyTransport {
+                master: master,
+                slave: slave,
+                slave_path: slave_path,
+                timeout: None,
+            })
+        }
+    }
+
+    // The device path that clients should open, e.g. /dev/pts/3.
+    pub fn slave_path(&self) -> &str {
+        &self.slave_path
+    }
+}
+
+impl Read for PtyTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(timeout) = self.timeout {
+            let millis = timeout.as_secs() * 1000 + (timeout.subsec_nanos() / 1_000_000) as u64;
+            let mut fds = libc::pollfd {
+                fd: self.master.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+
+            let ready = try!(check_libc(unsafe { libc::poll(&mut fds, 1, millis as libc::c_int) }));
+            if ready == 0 {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "read timed out"));
+            }
+        }
+
+        self.master.read(buf)
+    }
+}
+
+impl Write for PtyTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.master.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.master.flush()
+    }
+}
+
+impl AsRawFd for PtyTransport {
+    fn as_raw_fd(&self) -> RawFd {
+        self.master.as_raw_fd()
+    }
+}
+
+impl Transport for PtyTransport {
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.timeout = Some(timeout);
+        Ok(())
+    }
+}
@@ -0,0 +1,478 @@
+extern crate chrono;
+
+// Unsolicited result codes are lines that the modem sends on its own
+// (an incoming call, a new text message, a change in network
+// registration, ...). The event loop pulls them out of the response
+// stream, parses them into `Urc`s here and hands them to whichever
+// components subscribed to that kind of event.
+
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc;
+
+use self::chrono::prelude::*;
+
+use super::errors::Error;
+use super::network::NetworkMode;
+use super::responses::{parse_number, split_parameters, strip_prefix, unquote};
+use super::sim::SimState;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum RegistrationStatus {
+    NotRegistered, // 0
+    RegisteredHome, // 1
+    Searching, // 2
+    Denied, // 3
+    Unknown, // 4
+    RegisteredRoaming, // 5
+}
+
+impl RegistrationStatus {
+    pub fn from_code(code: u8) -> Result<RegistrationStatus, Error> {
+        match code {
+            0 => Ok(RegistrationStatus::NotRegistered),
+            1 => Ok(RegistrationStatus::RegisteredHome),
+            2 => Ok(RegistrationStatus::Searching),
+            3 => Ok(RegistrationStatus::Denied),
+            4 => Ok(RegistrationStatus::Unknown),
+            5 => Ok(RegistrationStatus::RegisteredRoaming),
+            _ => Err(Error::ParseError),
+        }
+    }
+
+    pub fn is_registered(&self) -> bool {
+        *self == RegistrationStatus::RegisteredHome || *self == RegistrationStatus::RegisteredRoaming
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Registration {
+    pub status: RegistrationStatus,
+    // Location area code and cell ID are only reported with
+    // AT+CREG=2/AT+CGREG=2.
+    pub lac: Option<u32>,
+    pub cell_id: Option<u32>,
+    pub access_technology: Option<u8>,
+}
+
+fn parse_hex_parameter(data: &str) -> Result<u32, Error> {
+    u32::from_str_radix(unquote(data), 16).or(Err(Error::ParseError))
+}
+
+// Parses `<stat>[,<lac>,<ci>[,<act>]]`, the shape shared by the
+// +CREG/+CGREG unsolicited codes.
+pub fn parse_registration(parameters: &[&str]) -> Result<Registration, Error> {
+    if parameters.is_empty() {
+        return Err(Error::ParseError);
+    }
+
+    let status = try!(RegistrationStatus::from_code(try!(parse_number(parameters[0]))));
+
+    let (lac, cell_id) = if parameters.len() >= 3 {
+        (Some(try!(parse_hex_parameter(parameters[1]))),
+         Some(try!(parse_hex_parameter(parameters[2]))))
+    } else {
+        (None, None)
+    };
+
+    let access_technology = match parameters.get(3) {
+        Some(act) => Some(try!(parse_number(act))),
+        None => None,
+    };
+
+    Ok(Registration {
+        status: status,
+        lac: lac,
+        cell_id: cell_id,
+        access_technology: access_technology,
+    })
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Urc {
+    Ring, // RING
+    CallerId { number: String, number_type: u8, name: Option<String> }, // +CLIP
+    // Another call while one is up, once call waiting is on.
+    CallWaiting { number: String, number_type: u8, name: Option<String> }, // +CCWA
+    NewMessage { storage: String, index: u32 }, // +CMTI
+    MessageDelivery { length: u32, pdu: String }, // +CMT
+    StatusReport { length: u32, pdu: String }, // +CDS
+    NetworkRegistration(Registration), // +CREG
+    GprsRegistration(Registration), // +CGREG
+    Ussd { status: u8, message: Option<String>, dcs: Option<u8> }, // +CUSD
+    NoCarrier, // NO CARRIER
+    Busy, // BUSY
+    NoAnswer, // NO ANSWER
+    UnderVoltage { power_down: bool }, // UNDER-VOLTAGE
+    NetworkTime { time: DateTime<Utc>, zone_quarters: i32, dst: u8 }, // *PSUTTZ
+    PowerOn(String), // RDY, Call Ready, SMS Ready
+    SimState(SimState), // +CPIN
+    SystemMode(NetworkMode), // +CNSMOD (SIMCom)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UrcKind {
+    Ring,
+    CallerId,
+    CallWaiting,
+    NewMessage,
+    MessageDelivery,
+    StatusReport,
+    NetworkRegistration,
+    GprsRegistration,
+    Ussd,
+    NoCarrier,
+    Busy,
+    NoAnswer,
+    UnderVoltage,
+    NetworkTime,
+    PowerOn,
+    SimState,
+    SystemMode,
+}
+
+impl Urc {
+    pub fn kind(&self) -> UrcKind {
+        match *self {
+            Urc::Ring => UrcKind::Ring,
+            Urc::CallerId { .. } => UrcKind::CallerId,
+            Urc::CallWaiting { .. } => UrcKind::CallWaiting,
+            Urc::NewMessage { .. } => UrcKind::NewMessage,
+            Urc::MessageDelivery { .. } => UrcKind::MessageDelivery,
+            Urc::StatusReport { .. } => UrcKind::StatusReport,
+            Urc::NetworkRegistration(_) => UrcKind::NetworkRegistration,
+            Urc::GprsRegistration(_) => UrcKind::GprsRegistration,
+            Urc::Ussd { .. } => UrcKind::Ussd,
+            Urc::NoCarrier => UrcKind::NoCarrier,
+            Urc::Busy => UrcKind::Busy,
+            Urc::NoAnswer => UrcKind::NoAnswer,
+            Urc::UnderVoltage { .. } => UrcKind::UnderVoltage,
+            Urc::NetworkTime { .. } => UrcKind::NetworkTime,
+            Urc::PowerOn(_) => UrcKind::PowerOn,
+            Urc::SimState(_) => UrcKind::SimState,
+            Urc::SystemMode(_) => UrcKind::SystemMode,
+        }
+    }
+}
+
+// Lines that start an unsolicited result code. The numeric forms are
+// what RING, NO CARRIER, BUSY and NO ANSWER look like after ATV0; the
+// last three end a call that was set up with ATD.
+const URC_PREFIXES: &[&str] = &[
+    "RING", "2", "NO CARRIER", "3", "BUSY", "7", "NO ANSWER", "8", "+CLIP", "+CCWA", "+CMTI", "+CMT", "+CDS", "+CREG",
+    "+CGREG", "+CUSD", "UNDER-VOLTAGE", "*PSUTTZ", "RDY", "Call Ready", "SMS Ready", "+CPIN",
+];
+
+// Returns the URC family that `line` belongs to, if any. The caller
+// uses this to tell a URC apart from the response to the command in
+// flight (whose lines can share a prefix, e.g. AT+CREG?).
+pub fn unsolicited_prefix(line: &str) -> Option<&'static str> {
+    for prefix in URC_PREFIXES.iter() {
+        let matches = if prefix.starts_with('+') || prefix.starts_with('*') {
+            line.starts_with(prefix) && line[prefix.len()..].starts_with(':')
+        } else if *prefix == "UNDER-VOLTAGE" {
+            line.starts_with(prefix)
+        } else {
+            line == *prefix
+        };
+
+        if matches {
+            return Some(prefix);
+        }
+    }
+
+    None
+}
+
+enum PendingPdu {
+    Delivery(u32),
+    StatusReport(u32),
+}
+
+// Turns unsolicited lines into `Urc`s. Message deliveries and status
+// reports span two lines (a header and then the PDU), so the parser
+// remembers the header until the PDU arrives.
+pub struct UrcParser {
+    pending: Option<PendingPdu>,
+}
+
+impl UrcParser {
+    pub fn new() -> UrcParser {
+        UrcParser {
+            pending: None,
+        }
+    }
+
+    // Whether the next line is the PDU of a two-line URC.
+    pub fn awaiting_pdu(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    pub fn feed(&mut self, line: &str) -> Result<Option<Urc>, Error> {
+        match self.pending.take() {
+            Some(PendingPdu::Delivery(length)) => {
+                return Ok(Some(Urc::MessageDelivery { length: length, pdu: line.to_string() }))
+            },
+            Some(PendingPdu::StatusReport(length)) => {
+                return Ok(Some(Urc::StatusReport { length: length, pdu: line.to_string() }))
+            },
+            None => {},
+        }
+
+        match line {
+            "RING" | "2" => return Ok(Some(Urc::Ring)),
+            "NO CARRIER" | "3" => return Ok(Some(Urc::NoCarrier)),
+            "BUSY" | "7" => return Ok(Some(Urc::Busy)),
+            "NO ANSWER" | "8" => return Ok(Some(Urc::NoAnswer)),
+            "RDY" | "Call Ready" | "SMS Ready" => return Ok(Some(Urc::PowerOn(line.to_string()))),
+            _ => {},
+        }
+
+        if line.starts_with("UNDER-VOLTAGE") {
+            return Ok(Some(Urc::UnderVoltage { power_down: line.contains("POWER DOWN") }));
+        }
+
+        if let Some(data) = strip_prefix(line, "+CLIP") {
+            let parameters = split_parameters(data);
+            if parameters.len() < 2 {
+                return Err(Error::ParseError);
+            }
+
+            let name = parameters.get(4).map(|n| unquote(n).to_string()).and_then(|n| {
+                if n.is_empty() { None } else { Some(n) }
+            });
+
+            return Ok(Some(Urc::CallerId {
+                number: unquote(parameters[0]).to_string(),
+                number_type: try!(parse_number(parameters[1])),
+                name: name,
+            }));
+        }
+
+        // +CCWA: <number>,<type>,<class>[,<alpha>]
+        if let Some(data) = strip_prefix(line, "+CCWA") {
+            let parameters = split_parameters(data);
+            if parameters.len() < 3 {
+                return Err(Error::ParseError);
+            }
+
+            let name = parameters.get(3).map(|n| unquote(n).to_string()).and_then(|n| {
+                if n.is_empty() { None } else { Some(n) }
+            });
+
+            return Ok(Some(Urc::CallWaiting {
+                number: unquote(parameters[0]).to_string(),
+                number_type: try!(parse_number(parameters[1])),
+                name: name,
+            }));
+        }
+
+        if let Some(data) = strip_prefix(line, "+CMTI") {
+            let parameters = split_parameters(data);
+            if parameters.len() != 2 {
+                return Err(Error::ParseError);
+            }
+
+            return Ok(Some(Urc::NewMessage {
+                storage: unquote(parameters[0]).to_string(),
+                index: try!(parse_number(parameters[1])),
+            }));
+        }
+
+        if let Some(data) = strip_prefix(line, "+CMT") {
+            // +CMT: [<alpha>],<length>
+            let length = match split_parameters(data).last() {
+                Some(l) => try!(parse_number(l)),
+                None => return Err(Error::ParseError),
+            };
+
+            self.pending = Some(PendingPdu::Delivery(length));
+            return Ok(None);
+        }
+
+        if let Some(data) = strip_prefix(line, "+CDS") {
+            self.pending = Some(PendingPdu::StatusReport(try!(parse_number(data))));
+            return Ok(None);
+        }
+
+        if let Some(data) = strip_prefix(line, "+CREG") {
+            return Ok(Some(Urc::NetworkRegistration(try!(parse_registration(&split_parameters(data))))));
+        }
+
+        if let Some(data) = strip_prefix(line, "+CGREG") {
+            return Ok(Some(Urc::GprsRegistration(try!(parse_registration(&split_parameters(data))))));
+        }
+
+        if let Some(data) = strip_prefix(line, "+CUSD") {
+            return parse_ussd(&split_parameters(data)).map(Some);
+        }
+
+        // Sent when the SIM is unlocked, removed or inserted.
+        if let Some(data) = strip_prefix(line, "+CPIN") {
+            return Ok(Some(Urc::SimState(SimState::from_code(unquote(data)))));
+        }
+
+        if let Some(data) = strip_prefix(line, "*PSUTTZ") {
+            return parse_network_time(&split_parameters(data)).map(Some);
+        }
+
+        Err(Error::ParseError)
+    }
+}
+
+// +CUSD: <status>[,"<message>",<dcs>], also found in the response to
+// AT+CUSD on modems that wait for the network before answering.
+pub fn parse_ussd(parameters: &[&str]) -> Result<Urc, Error> {
+    if parameters.is_empty() {
+        return Err(Error::ParseError);
+    }
+
+    let dcs = match parameters.get(2) {
+        Some(d) => Some(try!(parse_number(d))),
+        None => None,
+    };
+
+    Ok(Urc::Ussd {
+        status: try!(parse_number(parameters[0])),
+        message: parameters.get(1).map(|m| unquote(m).to_string()),
+        dcs: dcs,
+    })
+}
+
+// *PSUTTZ: <year>,<month>,<day>,<hour>,<min>,<sec>,"<tz>",<dst>
+//
+// The time is UTC and the time zone is given in quarters of an hour.
+fn parse_network_time(parameters: &[&str]) -> Result<Urc, Error> {
+    if parameters.len() != 8 {
+        return Err(Error::ParseError);
+    }
+
+    let mut fields = [0u32; 6];
+    for (i, field) in fields.iter_mut().enumerate() {
+        *field = try!(parse_number(parameters[i]));
+    }
+
+    let date = match NaiveDate::from_ymd_opt(fields[0] as i32, fields[1], fields[2])
+        .and_then(|d| d.and_hms_opt(fields[3], fields[4], fields[5])) {
+        Some(d) => d,
+        None => return Err(Error::ParseError),
+    };
+
+    Ok(Urc::NetworkTime {
+        time: Utc.from_utc_datetime(&date),
+        zone_quarters: try!(parse_number(parameters[6])),
+        dst: try!(parse_number(parameters[7])),
+    })
+}
+
+struct Subscription {
+    kinds: Vec<UrcKind>,
+    // Hands a URC on, and returns false once nobody is listening.
+    deliver: Box<Fn(&Urc) -> bool + Send>,
+}
+
+// Fans URCs out to subscribers. Clones share the same subscriber list.
+#[derive(Clone)]
+pub struct UrcDispatcher {
+    subscriptions: Arc<Mutex<Vec<Subscription>>>,
+}
+
+impl UrcDispatcher {
+    pub fn new() -> UrcDispatcher {
+        UrcDispatcher {
+            subscriptions: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    // Receives every URC of the given kinds from now on.
+    pub fn subscribe(&self, kinds: &[UrcKind]) -> mpsc::Receiver<Urc> {
+        let (send, recv) = mpsc::channel();
+        self.forward(kinds, send, |urc| urc);
+
+        recv
+    }
+
+    // Sends every URC of the given kinds to `sender`, wrapped with
+    // `wrap`, so that a thread can block on a single channel for both
+    // its requests and the URCs it cares about.
+    pub fn forward<T: Send + 'static>(&self, kinds: &[UrcKind], sender: mpsc::Sender<T>, wrap: fn(Urc) -> T) {
+        self.subscriptions.lock().unwrap().push(Subscription {
+            kinds: kinds.to_vec(),
+            deliver: Box::new(move |urc: &Urc| sender.send(wrap(urc.clone())).is_ok()),
+        });
+    }
+
+    // Returns whether anyone was listening for this URC.
+    pub fn dispatch(&self, urc: &Urc) -> bool {
+        let kind = urc.kind();
+        let mut delivered = false;
+
+        // Subscribers that hung up are dropped on the way through.
+        self.subscriptions.lock().unwrap().retain(|subscription| {
+            if !subscription.kinds.contains(&kind) {
+                return true;
+            }
+
+            let listening = (subscription.deliver)(urc);
+            delivered = delivered || listening;
+            listening
+        });
+
+        delivered
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{RegistrationStatus, Urc, UrcDispatcher, UrcKind, UrcParser, unsolicited_prefix};
+
+    #[test]
+    fn parses_single_line_codes() {
+        let mut parser = UrcParser::new();
+
+        assert_eq!(parser.feed("2").unwrap(), Some(Urc::Ring));
+        assert_eq!(parser.feed("+CMTI: \"SM\",3").unwrap(),
+                   Some(Urc::NewMessage { storage: "SM".to_string(), index: 3 }));
+        assert_eq!(parser.feed("+CLIP: \"+15551234567\",145,\"\",0,\"\",0").unwrap(),
+                   Some(Urc::CallerId { number: "+15551234567".to_string(), number_type: 145, name: None }));
+
+        match parser.feed("+CREG: 5,\"1A2B\",\"00C3F1\"").unwrap() {
+            Some(Urc::NetworkRegistration(r)) => {
+                assert_eq!(r.status, RegistrationStatus::RegisteredRoaming);
+                assert_eq!(r.lac, Some(0x1A2B));
+                assert_eq!(r.cell_id, Some(0xC3F1));
+            },
+            u => panic!("unexpected {:?}", u),
+        }
+
+        assert!(parser.feed("+CLIP: garbage").is_err());
+    }
+
+    #[test]
+    fn parses_two_line_codes() {
+        let mut parser = UrcParser::new();
+
+        assert_eq!(parser.feed("+CMT: ,24").unwrap(), None);
+        assert!(parser.awaiting_pdu());
+        assert_eq!(parser.feed("0791").unwrap(), Some(Urc::MessageDelivery { length: 24, pdu: "0791".to_string() }));
+        assert!(!parser.awaiting_pdu());
+    }
+
+    #[test]
+    fn recognises_prefixes() {
+        assert_eq!(unsolicited_prefix("+CMTI: \"SM\",1"), Some("+CMTI"));
+        assert_eq!(unsolicited_prefix("+CMT: ,24"), Some("+CMT"));
+        assert_eq!(unsolicited_prefix("+CMGL: 1,1,,24"), None);
+        assert_eq!(unsolicited_prefix("UNDER-VOLTAGE WARNNING"), Some("UNDER-VOLTAGE"));
+    }
+
+    #[test]
+    fn dispatches_by_kind() {
+        let dispatcher = UrcDispatcher::new();
+        let rings = dispatcher.subscribe(&[UrcKind::Ring]);
+
+        assert!(dispatcher.dispatch(&Urc::Ring));
+        assert!(!dispatcher.dispatch(&Urc::NoCarrier));
+        assert_eq!(rings.try_recv().unwrap(), Urc::Ring);
+        assert!(rings.try_recv().is_err());
+    }
+}
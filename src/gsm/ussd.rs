@@ -0,0 +1,358 @@
+// USSD, the *100# codes that prepaid SIMs use for balance checks and
+// top-ups. A request goes out with AT+CUSD and the network answers a
+// few seconds later with a +CUSD URC. When the answer is a menu or a
+// question (status 1) the session stays open and the next request is
+// the reply to it, until the network ends the session or we cancel it
+// with AT+CUSD=2.
+//
+// The text of an answer is encoded as its DCS says (3GPP 23.038). The
+// modem converts GSM 7-bit text into the character set of AT+CSCS,
+// which is hex in turn if that is UCS2, while UCS2 and 8-bit text
+// always arrive as hex (27.007 7.15).
+
+use std::collections::VecDeque;
+use std::io;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use gsm::command::Pipeline;
+use gsm::errors::Error;
+use gsm::pdu;
+use gsm::urc::{Urc, UrcDispatcher, UrcKind};
+
+// The DCS of our requests: GSM 7-bit, language unspecified.
+pub const DCS_GSM7: u8 = 15;
+
+// 160 octets of packed GSM 7-bit text.
+const MAX_REQUEST_LENGTH: usize = 182;
+
+// How long the network gets to answer a request.
+const REPLY_TIMEOUT_MS: u64 = 30000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum UssdStatus {
+    // The network answered and the session is over.
+    Done, // 0
+    // The network wants a reply (UssdPipe::reply).
+    ActionRequired, // 1
+    TerminatedByNetwork, // 2
+    // Another client of the modem answered.
+    OtherClient, // 3
+    NotSupported, // 4
+    NetworkTimeout, // 5
+}
+
+impl UssdStatus {
+    pub fn from_code(code: u8) -> Result<UssdStatus, Error> {
+        match code {
+            0 => Ok(UssdStatus::Done),
+            1 => Ok(UssdStatus::ActionRequired),
+            2 => Ok(UssdStatus::TerminatedByNetwork),
+            3 => Ok(UssdStatus::OtherClient),
+            4 => Ok(UssdStatus::NotSupported),
+            5 => Ok(UssdStatus::NetworkTimeout),
+            _ => Err(Error::ParseError),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct UssdReply {
+    pub status: UssdStatus,
+    // None if the network sent no text, e.g. when it ends a session.
+    pub message: Option<String>,
+}
+
+impl UssdReply {
+    fn from_urc(status: u8, message: Option<String>, dcs: Option<u8>, charset: &str) -> Result<UssdReply, Error> {
+        Ok(UssdReply {
+            status: try!(UssdStatus::from_code(status)),
+            message: message.map(|m| decode_message(&m, dcs.unwrap_or(DCS_GSM7), charset)),
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Alphabet {
+    Gsm7,
+    EightBit,
+    Ucs2,
+}
+
+// The alphabet of a CBS data coding scheme, which USSD shares.
+fn alphabet(dcs: u8) -> Alphabet {
+    match dcs >> 4 {
+        0x1 if dcs == 0x11 => Alphabet::Ucs2,
+        // General data coding (with or without a header): bits 3-2.
+        0x4 | 0x5 | 0x6 | 0x7 | 0x9 => match (dcs >> 2) & 0x03 {
+            1 => Alphabet::EightBit,
+            2 => Alphabet::Ucs2,
+            _ => Alphabet::Gsm7,
+        },
+        0xF if dcs & 0x04 != 0 => Alphabet::EightBit,
+        _ => Alphabet::Gsm7,
+    }
+}
+
+// Decodes the text of a +CUSD, sent while the modem's character set
+// was `charset`. Text that isn't encoded the way it should be is
+// returned as it came.
+pub fn decode_message(message: &str, dcs: u8, charset: &str) -> String {
+    let ucs2 = |text: &str| pdu::decode_ucs2(text).unwrap_or_else(|_| text.to_string());
+
+    match (alphabet(dcs), dcs) {
+        (Alphabet::Gsm7, _) => {
+            let text = if charset == "UCS2" { ucs2(message) } else { message.to_string() };
+
+            // Preceded by the language as two characters and a CR.
+            if dcs == 0x10 { text.chars().skip(3).collect() } else { text }
+        },
+        // Preceded by the language as two packed GSM 7-bit characters.
+        (Alphabet::Ucs2, 0x11) if message.len() >= 4 && pdu::is_hex(message) => ucs2(&message[4..]),
+        (Alphabet::Ucs2, _) => ucs2(message),
+        (Alphabet::EightBit, _) => message.to_string(),
+    }
+}
+
+// Printable ASCII without the quote that ends the AT+CUSD argument.
+pub fn is_valid_request(text: &str) -> bool {
+    !text.is_empty() && text.len() <= MAX_REQUEST_LENGTH &&
+        text.chars().all(|c| c >= ' ' && c <= '~' && c != '"')
+}
+
+enum Request {
+    Send { text: String, response: mpsc::Sender<Result<UssdReply, Error>> },
+    Reply { text: String, response: mpsc::Sender<Result<UssdReply, Error>> },
+    Cancel { response: mpsc::Sender<Result<(), Error>> },
+    GetSession { response: mpsc::Sender<Option<UssdReply>> },
+    // Forwarded by the UrcDispatcher.
+    Urc(Urc),
+}
+
+#[derive(Clone, Debug)]
+pub struct UssdPipe(mpsc::Sender<Request>);
+
+impl UssdPipe {
+    // Starts a session with `code`, e.g. *100#, ending any open one.
+    // Receives the network's answer.
+    pub fn send(&self, code: String) -> mpsc::Receiver<Result<UssdReply, Error>> {
+        // Without the manager, the receiver reports the disconnection.
+        let (send, recv) = mpsc::channel();
+        self.0.send(Request::Send { text: code, response: send }).ok();
+
+        recv
+    }
+
+    // Answers the open session's menu or question.
+    pub fn reply(&self, text: String) -> mpsc::Receiver<Result<UssdReply, Error>> {
+        let (send, recv) = mpsc::channel();
+        self.0.send(Request::Reply { text: text, response: send }).ok();
+
+        recv
+    }
+
+    pub fn cancel(&self) -> mpsc::Receiver<Result<(), Error>> {
+        let (send, recv) = mpsc::channel();
+        self.0.send(Request::Cancel { response: send }).ok();
+
+        recv
+    }
+
+    // The last answer of the session waiting for a reply, if any.
+    pub fn session(&self) -> Option<UssdReply> {
+        let (send, recv) = mpsc::channel();
+        self.0.send(Request::GetSession { response: send }).ok();
+
+        recv.recv().unwrap_or(None)
+    }
+}
+
+struct SessionData {
+    // Set while the network waits for a reply.
+    open: Option<UssdReply>,
+    // Whoever is waiting for the network to answer, and since when.
+    pending: Option<(mpsc::Sender<Result<UssdReply, Error>>, Instant)>,
+    // The modem's character set as of the last request, which the
+    // answers are converted to.
+    charset: String,
+}
+
+impl SessionData {
+    fn handle_reply(&mut self, reply: UssdReply) {
+        self.open = if reply.status == UssdStatus::ActionRequired { Some(reply.clone()) } else { None };
+
+        match self.pending.take() {
+            Some((response, _)) => { response.send(Ok(reply)).ok(); },
+            // The network can start a session on its own.
+            None => println!("unsolicited USSD: {:?}", reply),
+        }
+    }
+
+    // Sends `text` and waits for the network's answer, unless the
+    // modem has already passed it on.
+    fn request(&mut self, pipeline: &Pipeline, text: &str, response: mpsc::Sender<Result<UssdReply, Error>>) {
+        if let Ok(charset) = pipeline.character_set().and_then(|r| r.wait()) {
+            self.charset = charset;
+        }
+
+        match pipeline.ussd(text).and_then(|r| r.wait()) {
+            Ok(Some(Urc::Ussd { status, message, dcs })) => {
+                self.pending = Some((response, Instant::now()));
+                match UssdReply::from_urc(status, message, dcs, &self.charset) {
+                    Ok(reply) => self.handle_reply(reply),
+                    Err(e) => self.fail(e),
+                }
+            },
+            Ok(_) => self.pending = Some((response, Instant::now())),
+            Err(e) => { response.send(Err(e)).ok(); },
+        }
+    }
+
+    fn fail(&mut self, error: Error) {
+        self.open = None;
+        if let Some((response, _)) = self.pending.take() {
+            response.send(Err(error)).ok();
+        }
+    }
+}
+
+pub struct UssdManager {
+    cmd_send: UssdPipe,
+    join_handle: thread::JoinHandle<Result<(), ()>>,
+}
+
+impl UssdManager {
+    pub fn new(pipeline: Pipeline, urcs: &UrcDispatcher) -> UssdManager {
+        let (send, recv) = mpsc::channel::<Request>();
+        urcs.forward(&[UrcKind::Ussd], send.clone(), Request::Urc);
+
+        UssdManager {
+            join_handle: UssdManager::start_daemon(pipeline, recv).unwrap(),
+            cmd_send: UssdPipe(send),
+        }
+    }
+
+    pub fn get_pipe(&self) -> UssdPipe {
+        self.cmd_send.clone()
+    }
+
+    // Returns once every UssdPipe is gone and the UrcDispatcher has
+    // been dropped.
+    pub fn exit(self) {
+        drop(self.cmd_send);
+        println!("exited USSD manager {:?}", self.join_handle.join());
+    }
+
+    fn start_daemon(pipeline: Pipeline,
+                    cmd_recv: mpsc::Receiver<Request>) -> io::Result<thread::JoinHandle<Result<(), ()>>> {
+        thread::Builder::new().name("aji/ussd".to_string()).spawn(
+            move || {
+                let mut data = SessionData {
+                    open: None,
+                    pending: None,
+                    // The default of 27.007, until a request asks.
+                    charset: "IRA".to_string(),
+                };
+
+                // Requests that wait for the network to answer the one
+                // before them.
+                let mut queued = VecDeque::new();
+
+                loop {
+                    // Sleep until a request or URC comes in, or the
+                    // network has taken too long to answer.
+                    let deadline = data.pending.as_ref().map(|&(_, sent_at)| {
+                        sent_at + Duration::from_millis(REPLY_TIMEOUT_MS)
+                    });
+                    let received = match deadline {
+                        Some(at) => {
+                            let now = Instant::now();
+                            if at > now {
+                                cmd_recv.recv_timeout(at - now)
+                            } else {
+                                Err(mpsc::RecvTimeoutError::Timeout)
+                            }
+                        },
+                        None => cmd_recv.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+                    };
+
+                    match received {
+                        Ok(Request::Urc(Urc::Ussd { status, message, dcs })) => {
+                            match UssdReply::from_urc(status, message, dcs, &data.charset) {
+                                Ok(reply) => data.handle_reply(reply),
+                                Err(e) => data.fail(e),
+                            }
+                        },
+                        Ok(Request::Urc(_)) => {},
+                        Ok(Request::GetSession { response }) => { response.send(data.open.clone()).ok(); },
+                        Ok(Request::Cancel { response }) => {
+                            let result = if data.open.is_none() && data.pending.is_none() {
+                                Err(Error::NoSession)
+                            } else {
+                                data.fail(Error::NoSession);
+                                pipeline.cancel_ussd().and_then(|r| r.wait())
+                            };
+
+                            response.send(result).ok();
+                        },
+                        Ok(request) => queued.push_back(request),
+                        Err(mpsc::RecvTimeoutError::Timeout) => {
+                            // Don't leave the modem in a session nobody
+                            // is following any more.
+                            pipeline.cancel_ussd().and_then(|r| r.wait()).ok();
+                            data.fail(Error::Timeout);
+                        },
+                        Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(()),
+                    }
+
+                    while data.pending.is_none() {
+                        match queued.pop_front() {
+                            Some(Request::Send { text, response }) => {
+                                if !is_valid_request(&text) {
+                                    response.send(Err(Error::InvalidArgument)).ok();
+                                } else {
+                                    if data.open.take().is_some() {
+                                        pipeline.cancel_ussd().and_then(|r| r.wait()).ok();
+                                    }
+
+                                    data.request(&pipeline, &text, response);
+                                }
+                            },
+                            Some(Request::Reply { text, response }) => {
+                                if data.open.is_none() {
+                                    response.send(Err(Error::NoSession)).ok();
+                                } else {
+                                    data.request(&pipeline, &text, response);
+                                }
+                            },
+                            Some(_) => {},
+                            None => break,
+                        }
+                    }
+                }
+            })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decode_message, is_valid_request};
+
+    #[test]
+    fn decodes_messages() {
+        assert_eq!(decode_message("Balance: 5.00", 15, "IRA"), "Balance: 5.00");
+        // Text that only looks like hex.
+        assert_eq!(decode_message("CAFE", 15, "IRA"), "CAFE");
+        assert_eq!(decode_message("00480069", 15, "UCS2"), "Hi");
+        assert_eq!(decode_message("en\rHi", 0x10, "IRA"), "Hi");
+        assert_eq!(decode_message("00480069", 72, "IRA"), "Hi");
+        assert_eq!(decode_message("E5B700480069", 0x11, "IRA"), "Hi");
+        // Already converted by the modem.
+        assert_eq!(decode_message("Hi", 72, "IRA"), "Hi");
+
+        assert!(is_valid_request("*100#"));
+        assert!(!is_valid_request("*100#\",15\rATD1"));
+        assert!(!is_valid_request(""));
+    }
+}
@@ -6,30 +6,103 @@
 
 extern crate hyper;
 extern crate futures;
+extern crate futures_cpupool;
+extern crate serde;
 extern crate serde_json;
 
 use self::futures::Stream;
 use self::futures::future::Future;
+use self::futures_cpupool::CpuPool;
+use self::serde::Serialize;
 
 use self::hyper::{Body, Chunk, Method, StatusCode};
 use self::hyper::header::{ContentType};
 use self::hyper::server::{Http, Request, Response, Service};
 
+use super::gsm::call::{Call, CallState};
+use super::gsm::dtmf::DtmfOptions;
+use super::gsm::errors::Error;
+use super::gsm::operator::{AccessTechnology, CurrentOperator, OperatorScan};
+use super::gsm::phonebook::SIM_STORAGE;
+use super::gsm::supplementary::{CallerIdPresentation, ForwardingReason, HoldAction};
+
+// hyper answers every request on a single thread, so anything that
+// waits for the modem (a dial, a USSD session, a phonebook read) runs
+// on one of these instead.
+const MODEM_THREADS: usize = 4;
+
 pub struct Server {
     radio: super::gsm::RadioClient,
+    pool: CpuPool,
 }
 
 impl Server {
     pub fn start(radio: super::gsm::Radio) {
         println!("starting server on 127.0.0.1:3000");
         let addr = "0.0.0.0:3000".parse().unwrap();
+        let pool = CpuPool::new(MODEM_THREADS);
 
         let server = Http::new().bind(&addr, move || Ok(Server{
-            radio: radio.get_client()
+            radio: radio.get_client(),
+            pool: pool.clone(),
         })).unwrap();
 
         server.run().unwrap();
     }
+
+    // Runs `handler` on the pool and answers with what it returns.
+    fn blocking<F>(&self, response: <Server as Service>::Response, handler: F) -> <Server as Service>::Future
+        where F: FnOnce() -> Reply + Send + 'static {
+        Box::new(self.pool.spawn_fn(move || Ok(handler())).map(move |reply: Reply| reply.into_response(response)))
+    }
+
+    // The same, for a handler that needs the request body.
+    fn blocking_with_body<F>(&self, response: <Server as Service>::Response, body: Body,
+                             handler: F) -> <Server as Service>::Future
+        where F: FnOnce(&[u8]) -> Reply + Send + 'static {
+        let pool = self.pool.clone();
+
+        Box::new(body.concat2()
+                 .and_then(move |body: Chunk| pool.spawn_fn(move || Ok(handler(&body))))
+                 .map(move |reply: Reply| reply.into_response(response)))
+    }
+}
+
+// The answer of a handler that ran on the pool. The Response itself is
+// put together back on hyper's thread, since its body can't be sent
+// between threads.
+struct Reply {
+    status: StatusCode,
+    // Sent as application/json.
+    json: Option<String>,
+}
+
+impl Reply {
+    fn status(status: StatusCode) -> Reply {
+        Reply {
+            status: status,
+            json: None,
+        }
+    }
+
+    fn json<T: Serialize>(value: &T) -> Reply {
+        Reply {
+            status: StatusCode::Ok,
+            json: Some(serde_json::to_string(value).unwrap()),
+        }
+    }
+
+    fn into_response(self, mut response: <Server as Service>::Response) -> <Server as Service>::Response {
+        response.set_status(self.status);
+
+        if let Some(json) = self.json {
+            let body: Box<Stream<Item=_, Error=_>> = Box::new(Body::from(json));
+            response.headers_mut().set(ContentType::json());
+            response.set_body(body);
+        }
+
+        response
+    }
 }
 
 impl Service for Server {
@@ -46,27 +119,406 @@ impl Service for Server {
 
         match (method, uri.path()) {
             (Method::Get, "/messages") => {
-                let messages = self.radio.sms.get_messages().recv().unwrap();
-                let body: Box<Stream<Item=_, Error=_>> = Box::new(Body::from(serde_json::to_string(&messages).unwrap()));
+                let sms = self.radio.sms.clone();
+
+                // The MessagingManager doesn't answer while it sends.
+                self.blocking(response, move || {
+                    match sms.get_messages().recv() {
+                        Ok(messages) => Reply::json(&messages),
+                        Err(_) => Reply::status(StatusCode::ServiceUnavailable),
+                    }
+                })
+            },
+            (Method::Get, "/health") => {
+                let health = self.radio.health.report();
+                let body: Box<Stream<Item=_, Error=_>> = Box::new(Body::from(serde_json::to_string(&health).unwrap()));
                 response.headers_mut().set(ContentType::json());
                 response.set_body(body);
 
                 Box::new(futures::future::ok(response))
             },
-            (Method::Post, "/messages/new") => {
+            (Method::Get, "/signal") => {
+                // null until the first sample.
+                let signal = self.radio.signal.latest();
+                let body: Box<Stream<Item=_, Error=_>> = Box::new(Body::from(serde_json::to_string(&signal).unwrap()));
+                response.headers_mut().set(ContentType::json());
+                response.set_body(body);
+
+                Box::new(futures::future::ok(response))
+            },
+            (Method::Get, "/network") => {
+                let network = self.radio.network.state();
+                let body: Box<Stream<Item=_, Error=_>> = Box::new(Body::from(serde_json::to_string(&network).unwrap()));
+                response.headers_mut().set(ContentType::json());
+                response.set_body(body);
+
+                Box::new(futures::future::ok(response))
+            },
+            (Method::Get, "/operators") => {
+                let client = self.radio.clone();
+
+                self.blocking(response, move || {
+                    Reply::json(&WireOperators {
+                        current: client.current_operator().ok(),
+                        scan: client.operators.latest_scan(),
+                    })
+                })
+            },
+            (Method::Post, "/operators/scan") => {
+                // The scan takes minutes; GET /operators shows the
+                // result once it's done.
+                self.radio.operators.scan();
+                response.set_status(StatusCode::Accepted);
+
+                Box::new(futures::future::ok(response))
+            },
+            (Method::Post, "/operators/select") => {
+                let client = self.radio.clone();
+
+                // A manual selection waits for the network to register.
+                self.blocking_with_body(response, body, move |body| {
+                    let result = match serde_json::from_slice::<WireOperatorSelection>(body) {
+                        Ok(WireOperatorSelection { numeric: Some(numeric), access_technology }) => {
+                            client.select_operator(&numeric, access_technology)
+                        },
+                        Ok(WireOperatorSelection { numeric: None, .. }) => client.select_operator_automatically(),
+                        Err(_) => Err(Error::InvalidArgument),
+                    };
+
+                    match result {
+                        Ok(_) => Reply::status(StatusCode::Ok),
+                        Err(Error::InvalidArgument) => Reply::status(StatusCode::BadRequest),
+                        Err(e) => {
+                            println!("Could not select the operator: {:?}", e);
+                            Reply::status(StatusCode::ServiceUnavailable)
+                        }
+                    }
+                })
+            },
+            (Method::Get, "/device") => {
+                let client = self.radio.clone();
+
+                self.blocking(response, move || {
+                    match client.device_info() {
+                        Ok(info) => Reply::json(&info),
+                        Err(e) => {
+                            println!("Could not read the device info: {:?}", e);
+                            Reply::status(StatusCode::ServiceUnavailable)
+                        }
+                    }
+                })
+            },
+            (Method::Get, "/sim") => {
+                let client = self.radio.clone();
+
+                self.blocking(response, move || {
+                    match client.sim_status() {
+                        Ok(status) => Reply::json(&status),
+                        Err(e) => {
+                            println!("Could not read the SIM status: {:?}", e);
+                            Reply::status(StatusCode::ServiceUnavailable)
+                        }
+                    }
+                })
+            },
+            (Method::Post, "/sim/unlock") => {
+                let client = self.radio.clone();
+
+                // The SIM takes seconds to check a PIN.
+                self.blocking_with_body(response, body, move |body| {
+                    let result = match serde_json::from_slice::<WireUnlock>(body) {
+                        Ok(WireUnlock { pin, puk: Some(puk) }) => client.enter_puk(&puk, &pin),
+                        Ok(WireUnlock { pin, puk: None }) => client.enter_pin(&pin),
+                        Err(_) => Err(Error::InvalidArgument),
+                    };
+
+                    match result {
+                        Ok(_) => Reply::status(StatusCode::Ok),
+                        Err(Error::InvalidArgument) => Reply::status(StatusCode::BadRequest),
+                        // Most likely the wrong PIN.
+                        Err(Error::CommandFailed(_)) => Reply::status(StatusCode::Forbidden),
+                        Err(_) => Reply::status(StatusCode::ServiceUnavailable),
+                    }
+                })
+            },
+            (Method::Get, "/calls") => {
+                let client = self.radio.clone();
+
+                // The call manager doesn't answer while it dials.
+                self.blocking(response, move || {
+                    match client.calls.calls().recv() {
+                        Ok(calls) => Reply::json(&WireCalls {
+                            state: client.calls.state(),
+                            calls: calls,
+                        }),
+                        Err(_) => Reply::status(StatusCode::ServiceUnavailable),
+                    }
+                })
+            },
+            (Method::Get, "/calls/log") => {
+                let entries = self.radio.call_log.entries();
+                let body: Box<Stream<Item=_, Error=_>> = Box::new(Body::from(serde_json::to_string(&entries).unwrap()));
+                response.headers_mut().set(ContentType::json());
+                response.set_body(body);
+
+                Box::new(futures::future::ok(response))
+            },
+            (Method::Post, "/calls/dial") => {
+                let client = self.radio.clone();
+
+                self.blocking_with_body(response, body, move |body| {
+                    let result = match serde_json::from_slice::<WireDial>(body) {
+                        Ok(w) => client.calls.dial(w.number).recv().unwrap_or(Err(Error::Disconnected)),
+                        Err(_) => Err(Error::InvalidArgument),
+                    };
+
+                    Reply::status(call_control_status(result))
+                })
+            },
+            (Method::Post, "/calls/dtmf") => {
+                let client = self.radio.clone();
+
+                // A long string of tones takes a while to play.
+                self.blocking_with_body(response, body, move |body| {
+                    let result = match serde_json::from_slice::<WireDtmf>(body) {
+                        Ok(w) => {
+                            let defaults = DtmfOptions::default();
+                            client.send_dtmf(&w.digits, &DtmfOptions {
+                                tone_duration_ms: w.tone_duration_ms.unwrap_or(defaults.tone_duration_ms),
+                                pause_ms: w.pause_ms.unwrap_or(defaults.pause_ms),
+                            })
+                        },
+                        Err(_) => Err(Error::InvalidArgument),
+                    };
+
+                    Reply::status(call_control_status(result))
+                })
+            },
+            (Method::Post, "/calls/answer") => {
+                let calls = self.radio.calls.clone();
+
+                self.blocking(response, move || {
+                    Reply::status(call_control_status(calls.answer().recv().unwrap_or(Err(Error::Disconnected))))
+                })
+            },
+            (Method::Post, "/calls/reject") => {
+                let calls = self.radio.calls.clone();
+
+                self.blocking(response, move || {
+                    Reply::status(call_control_status(calls.reject().recv().unwrap_or(Err(Error::Disconnected))))
+                })
+            },
+            (Method::Post, "/calls/hangup") => {
+                let calls = self.radio.calls.clone();
+
+                self.blocking(response, move || {
+                    Reply::status(call_control_status(calls.hang_up().recv().unwrap_or(Err(Error::Disconnected))))
+                })
+            },
+            (Method::Post, "/calls/hold") => {
+                let client = self.radio.clone();
+
+                self.blocking_with_body(response, body, move |body| {
+                    let result = match serde_json::from_slice::<WireHold>(body) {
+                        Ok(w) => client.calls.hold(w.action).recv().unwrap_or(Err(Error::Disconnected)),
+                        Err(_) => Err(Error::InvalidArgument),
+                    };
+
+                    Reply::status(call_control_status(result))
+                })
+            },
+            (Method::Get, "/calls/forwarding") => {
+                let client = self.radio.clone();
+
+                self.blocking(response, move || {
+                    match client.call_forwarding() {
+                        Ok(forwarding) => Reply::json(&forwarding),
+                        Err(e) => {
+                            println!("Could not read the call forwarding: {:?}", e);
+                            Reply::status(StatusCode::ServiceUnavailable)
+                        }
+                    }
+                })
+            },
+            (Method::Post, "/calls/forwarding") => {
+                let client = self.radio.clone();
+
+                self.blocking_with_body(response, body, move |body| {
+                    let result = match serde_json::from_slice::<WireForwarding>(body) {
+                        Ok(w) => client.set_call_forwarding(w.reason, w.number.as_ref().map(|n| n.as_str()),
+                                                            w.no_reply_time),
+                        Err(_) => Err(Error::InvalidArgument),
+                    };
+
+                    Reply::status(call_control_status(result))
+                })
+            },
+            (Method::Get, "/calls/waiting") => {
+                let client = self.radio.clone();
+
+                self.blocking(response, move || {
+                    match client.call_waiting() {
+                        Ok(enabled) => Reply::json(&WireCallWaiting { enabled: enabled }),
+                        Err(e) => {
+                            println!("Could not read the call waiting setting: {:?}", e);
+                            Reply::status(StatusCode::ServiceUnavailable)
+                        }
+                    }
+                })
+            },
+            (Method::Post, "/calls/waiting") => {
                 let client = self.radio.clone();
 
-                Box::new(body.concat2().and_then(move |body: Chunk| {
-                    let w: WireMessage = serde_json::from_slice(&body).unwrap();
+                self.blocking_with_body(response, body, move |body| {
+                    let result = match serde_json::from_slice::<WireCallWaiting>(body) {
+                        Ok(w) => client.set_call_waiting(w.enabled),
+                        Err(_) => Err(Error::InvalidArgument),
+                    };
+
+                    Reply::status(call_control_status(result))
+                })
+            },
+            (Method::Get, "/calls/caller-id") => {
+                let client = self.radio.clone();
+
+                self.blocking(response, move || {
+                    match client.caller_id_restriction() {
+                        Ok(restriction) => Reply::json(&restriction),
+                        Err(e) => {
+                            println!("Could not read the caller ID restriction: {:?}", e);
+                            Reply::status(StatusCode::ServiceUnavailable)
+                        }
+                    }
+                })
+            },
+            (Method::Post, "/calls/caller-id") => {
+                let client = self.radio.clone();
+
+                self.blocking_with_body(response, body, move |body| {
+                    let result = match serde_json::from_slice::<WireCallerId>(body) {
+                        Ok(w) => client.set_caller_id_presentation(w.presentation),
+                        Err(_) => Err(Error::InvalidArgument),
+                    };
+
+                    Reply::status(call_control_status(result))
+                })
+            },
+            (Method::Get, "/ussd") => {
+                let ussd = self.radio.ussd.clone();
+
+                self.blocking(response, move || Reply::json(&ussd.session()))
+            },
+            (Method::Post, "/ussd") | (Method::Post, "/ussd/reply") => {
+                let ussd = self.radio.ussd.clone();
+                let starts_session = uri.path() == "/ussd";
+
+                // The network takes seconds to answer.
+                self.blocking_with_body(response, body, move |body| {
+                    let result = match serde_json::from_slice::<WireUssd>(body) {
+                        Ok(w) if starts_session => ussd.send(w.text).recv().unwrap_or(Err(Error::Disconnected)),
+                        Ok(w) => ussd.reply(w.text).recv().unwrap_or(Err(Error::Disconnected)),
+                        Err(_) => Err(Error::InvalidArgument),
+                    };
+
+                    match result {
+                        Ok(reply) => Reply::json(&reply),
+                        Err(e) => Reply::status(ussd_status(e)),
+                    }
+                })
+            },
+            (Method::Post, "/ussd/cancel") => {
+                let ussd = self.radio.ussd.clone();
+
+                self.blocking(response, move || {
+                    match ussd.cancel().recv().unwrap_or(Err(Error::Disconnected)) {
+                        Ok(_) => Reply::status(StatusCode::Ok),
+                        Err(e) => Reply::status(ussd_status(e)),
+                    }
+                })
+            },
+            (Method::Get, "/phonebook") => {
+                let client = self.radio.clone();
+
+                // Every storage is opened in turn, and the SIM is slow.
+                self.blocking(response, move || {
+                    match client.phonebook_storages() {
+                        Ok(storages) => Reply::json(&storages),
+                        Err(e) => {
+                            println!("Could not list the phonebooks: {:?}", e);
+                            Reply::status(StatusCode::ServiceUnavailable)
+                        }
+                    }
+                })
+            },
+            (Method::Post, "/phonebook/read") | (Method::Post, "/phonebook/find") => {
+                let client = self.radio.clone();
+                let find = uri.path() == "/phonebook/find";
+
+                self.blocking_with_body(response, body, move |body| {
+                    let result = match serde_json::from_slice::<WirePhonebookQuery>(body) {
+                        Ok(WirePhonebookQuery { storage, name: Some(ref name) }) if find => {
+                            client.find_phonebook_entries(storage.as_ref().map_or(SIM_STORAGE, |s| s.as_str()), name)
+                        },
+                        Ok(WirePhonebookQuery { storage, name: None }) if !find => {
+                            client.phonebook_entries(storage.as_ref().map_or(SIM_STORAGE, |s| s.as_str()))
+                        },
+                        _ => Err(Error::InvalidArgument),
+                    };
+
+                    match result {
+                        Ok(entries) => Reply::json(&entries),
+                        Err(e) => Reply::status(phonebook_status(e)),
+                    }
+                })
+            },
+            (Method::Post, "/phonebook/write") => {
+                let client = self.radio.clone();
+
+                self.blocking_with_body(response, body, move |body| {
+                    let result = match serde_json::from_slice::<WirePhonebookEntry>(body) {
+                        Ok(w) => client.write_phonebook_entry(w.storage.as_ref().map_or(SIM_STORAGE, |s| s.as_str()),
+                                                              w.index, &w.number, &w.name),
+                        Err(_) => Err(Error::InvalidArgument),
+                    };
+
+                    match result {
+                        Ok(_) => Reply::status(StatusCode::Ok),
+                        Err(e) => Reply::status(phonebook_status(e)),
+                    }
+                })
+            },
+            (Method::Post, "/phonebook/delete") => {
+                let client = self.radio.clone();
+
+                self.blocking_with_body(response, body, move |body| {
+                    let result = match serde_json::from_slice::<WirePhonebookDelete>(body) {
+                        Ok(w) => client.delete_phonebook_entry(w.storage.as_ref().map_or(SIM_STORAGE, |s| s.as_str()),
+                                                               w.index),
+                        Err(_) => Err(Error::InvalidArgument),
+                    };
+
+                    match result {
+                        Ok(_) => Reply::status(StatusCode::Ok),
+                        Err(e) => Reply::status(phonebook_status(e)),
+                    }
+                })
+            },
+            (Method::Post, "/messages/new") => {
+                let sms = self.radio.sms.clone();
+
+                self.blocking_with_body(response, body, move |body| {
+                    let w: WireMessage = serde_json::from_slice(body).unwrap();
                     assert!(w.content.len() < 70);
 
                     // Quick send the message before we do so safely.
-                    client.sms.send_message(w.destination_address, w.content).recv().unwrap();
+                    let result = sms.send_message(w.destination_address, w.content).recv()
+                        .unwrap_or(Err(Error::Disconnected));
 
-                    let body: Box<Stream<Item=_, Error=_>> = Box::new(Body::from(""));
-                    response.set_body(body);
-                    futures::future::ok(response)
-                }))
+                    match result {
+                        Ok(reference) => Reply::json(&WireSentMessage { reference: reference.0 }),
+                        Err(e) => Reply::status(sms_status(e)),
+                    }
+                })
             },
             _ => {
                 response.set_status(StatusCode::NotFound);
@@ -77,8 +529,161 @@ impl Service for Server {
     }
 }
 
+// The status for the result of dialing, answering, hanging up,
+// sending tones or changing a call setting.
+fn call_control_status(result: Result<(), Error>) -> StatusCode {
+    match result {
+        Ok(_) => StatusCode::Ok,
+        Err(Error::InvalidArgument) => StatusCode::BadRequest,
+        // Nothing to answer or hang up.
+        Err(Error::NoCall) => StatusCode::Conflict,
+        Err(e) => {
+            println!("Call control failed: {:?}", e);
+            StatusCode::ServiceUnavailable
+        }
+    }
+}
+
+fn sms_status(error: Error) -> StatusCode {
+    match error {
+        // The modem or the network refused the message (+CMS ERROR).
+        Error::CommandFailed(_) => StatusCode::BadGateway,
+        Error::Timeout => StatusCode::GatewayTimeout,
+        e => {
+            println!("Could not send the message: {:?}", e);
+            StatusCode::ServiceUnavailable
+        }
+    }
+}
+
+fn ussd_status(error: Error) -> StatusCode {
+    match error {
+        Error::InvalidArgument => StatusCode::BadRequest,
+        // Nothing to reply to or cancel.
+        Error::NoSession => StatusCode::Conflict,
+        // The network never answered.
+        Error::Timeout => StatusCode::GatewayTimeout,
+        e => {
+            println!("USSD failed: {:?}", e);
+            StatusCode::ServiceUnavailable
+        }
+    }
+}
+
+fn phonebook_status(error: Error) -> StatusCode {
+    match error {
+        Error::InvalidArgument => StatusCode::BadRequest,
+        // The modem refused, e.g. because the storage is full, the
+        // name too long or the SIM still locked.
+        Error::CommandFailed(_) => StatusCode::UnprocessableEntity,
+        e => {
+            println!("Phonebook access failed: {:?}", e);
+            StatusCode::ServiceUnavailable
+        }
+    }
+}
+
 #[derive(Deserialize)]
 struct WireMessage {
     destination_address: String,
     content: String,
 }
+
+// The message reference the network assigned, which status reports
+// refer to.
+#[derive(Serialize)]
+struct WireSentMessage {
+    reference: u8,
+}
+
+// The PIN, or the PUK and the new PIN for a blocked SIM.
+#[derive(Deserialize)]
+struct WireUnlock {
+    pin: String,
+    puk: Option<String>,
+}
+
+#[derive(Serialize)]
+struct WireOperators {
+    current: Option<CurrentOperator>,
+    scan: OperatorScan,
+}
+
+// Without a numeric operator the modem goes back to automatic
+// selection.
+#[derive(Deserialize)]
+struct WireOperatorSelection {
+    numeric: Option<String>,
+    access_technology: Option<AccessTechnology>,
+}
+
+#[derive(Serialize)]
+struct WireCalls {
+    state: CallState,
+    calls: Vec<Call>,
+}
+
+#[derive(Deserialize)]
+struct WireDial {
+    number: String,
+}
+
+// Digits such as "1,,4#". The timings default to DtmfOptions::default.
+#[derive(Deserialize)]
+struct WireDtmf {
+    digits: String,
+    tone_duration_ms: Option<u32>,
+    pause_ms: Option<u32>,
+}
+
+// e.g. {"action": "Swap"} or {"action": {"ReleaseCall": 2}}.
+#[derive(Deserialize)]
+struct WireHold {
+    action: HoldAction,
+}
+
+// Without a number, forwarding for `reason` is turned off.
+#[derive(Deserialize)]
+struct WireForwarding {
+    reason: ForwardingReason,
+    number: Option<String>,
+    no_reply_time: Option<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct WireCallWaiting {
+    enabled: bool,
+}
+
+#[derive(Deserialize)]
+struct WireCallerId {
+    presentation: CallerIdPresentation,
+}
+
+// A code such as "*100#", or the reply to a menu.
+#[derive(Deserialize)]
+struct WireUssd {
+    text: String,
+}
+
+// The storage defaults to the SIM's contacts. A search needs a name.
+#[derive(Deserialize)]
+struct WirePhonebookQuery {
+    storage: Option<String>,
+    name: Option<String>,
+}
+
+// Without an index the entry goes in the first free slot.
+#[derive(Deserialize)]
+struct WirePhonebookEntry {
+    storage: Option<String>,
+    index: Option<u32>,
+    number: String,
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct WirePhonebookDelete {
+    storage: Option<String>,
+    index: u32,
+}
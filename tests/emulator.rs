@@ -0,0 +1,174 @@
+// Runs the whole GSM stack against the modem emulator over an
+// in-memory transport, the way it runs against the real modem over the
+// serial port.
+
+extern crate ajidamal;
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ajidamal::gsm::{Radio, RadioClient};
+use ajidamal::gsm::command::Pipeline;
+use ajidamal::gsm::config::ModemConfig;
+use ajidamal::gsm::emulator::{Emulator, EmulatorHandle, StoredMessage};
+use ajidamal::gsm::errors::Error;
+use ajidamal::gsm::sms::Message;
+use ajidamal::gsm::supervisor::ModemHealth;
+use ajidamal::gsm::transport;
+
+const PDU: &'static str = "07911326040000F0040B911346610089F60000208062917314080CC8F71D14969741F977FD07";
+
+// How long the MessagingManager gets to pick up a new message.
+const LOAD_TIMEOUT_MS: u64 = 5000;
+
+// How long the supervisor gets to notice the modem is gone.
+const RECONNECT_TIMEOUT_MS: u64 = 5000;
+
+// How long a scan refused during a reconnect takes to be repeated and
+// answered.
+const SCAN_TIMEOUT_MS: u64 = 10000;
+
+// How long the port gets to be opened again and the modem behind it
+// to be identified.
+const IDENTIFY_TIMEOUT_MS: u64 = 10000;
+
+fn start(emulator: Emulator) -> (Radio, EmulatorHandle) {
+    let (host, modem) = transport::memory_pair().unwrap();
+    let (handle, _) = emulator.spawn(modem).unwrap();
+
+    // Keep the call log off the disk.
+    let config = ModemConfig {
+        call_log: None,
+        ..ModemConfig::default()
+    };
+
+    (Radio::new_with_transport(host, &config).unwrap(), handle)
+}
+
+fn wait_for_health(client: &RadioClient, state: ModemHealth) {
+    let started = Instant::now();
+
+    while client.health.state() != state {
+        assert!(started.elapsed() < Duration::from_millis(RECONNECT_TIMEOUT_MS),
+                "the modem never got to {:?}", state);
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn wait_for_messages(client: &RadioClient, count: usize) -> Vec<Message> {
+    let started = Instant::now();
+
+    loop {
+        let messages = client.sms.get_messages().recv().unwrap();
+        if messages.len() >= count || started.elapsed() > Duration::from_millis(LOAD_TIMEOUT_MS) {
+            return messages;
+        }
+
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn lists_reads_and_sends_messages() {
+    let mut emulator = Emulator::new();
+    emulator.store(StoredMessage { status: 1, pdu: PDU.to_string() });
+
+    let (radio, handle) = start(emulator);
+    let client = radio.get_client();
+
+    let pipeline = Pipeline::new(client.phone.clone());
+    let sms = pipeline.read_sms(1).and_then(|r| r.wait()).unwrap();
+    assert_eq!(sms.message.user_data.data, "How are you?");
+    assert!(pipeline.read_sms(7).and_then(|r| r.wait()).is_err());
+
+    // The +CMTI makes the MessagingManager list the inbox again.
+    handle.deliver(PDU.to_string()).unwrap();
+    let messages = wait_for_messages(&client, 2);
+    assert_eq!(messages.len(), 2);
+    assert!(messages.iter().all(|m| m.contents == "How are you?"));
+
+    let reference = client.sms.send_message("+15550000000".to_string(), "Hello".to_string()).recv().unwrap();
+    assert_eq!(reference.unwrap().0, 0);
+    let reference = client.sms.send_message("+15550000000".to_string(), "Again".to_string()).recv().unwrap();
+    assert_eq!(reference.unwrap().0, 1);
+}
+
+#[test]
+fn keeps_working_after_a_reconnect() {
+    // Every time the port is opened, a fresh emulator answers.
+    let handles = Arc::new(Mutex::new(Vec::<EmulatorHandle>::new()));
+    let opened = handles.clone();
+    let config = ModemConfig {
+        call_log: None,
+        ..ModemConfig::default()
+    };
+    let radio = Radio::new_with_connector(&config, move || {
+        let (host, modem) = try!(transport::memory_pair());
+        let (handle, _) = try!(Emulator::new().spawn(modem));
+        opened.lock().unwrap().push(handle);
+        Ok(host)
+    }).unwrap();
+    let client = radio.get_client();
+
+    handles.lock().unwrap()[0].disconnect().unwrap();
+    wait_for_health(&client, ModemHealth::Recovering);
+
+    // Refused while the port is closed. The dial fails, but the
+    // scanner tries again once the port is back.
+    client.operators.scan();
+    match client.calls.dial("+15551234567".to_string()).recv().unwrap() {
+        Err(Error::Unavailable) => {},
+        other => panic!("expected the dial to be refused, got {:?}", other),
+    }
+
+    let started = Instant::now();
+    while client.operators.latest_scan().scanning {
+        assert!(started.elapsed() < Duration::from_millis(SCAN_TIMEOUT_MS), "the scan never finished");
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert!(client.operators.latest_scan().scanned_at.is_some());
+    assert_eq!(handles.lock().unwrap().len(), 2);
+
+    client.calls.dial("+15551234567".to_string()).recv().unwrap().unwrap();
+    let calls = client.calls.calls().recv().unwrap();
+    assert_eq!(calls.len(), 1);
+    assert_eq!(calls[0].number, Some("+15551234567".to_string()));
+}
+
+#[test]
+fn identifies_the_modem_again_after_a_reconnect() {
+    // The first modem passes for a Quectel; the one plugged in after
+    // it is the usual SIM800.
+    let handles = Arc::new(Mutex::new(Vec::<EmulatorHandle>::new()));
+    let opened = handles.clone();
+    let config = ModemConfig {
+        call_log: None,
+        ..ModemConfig::default()
+    };
+    let radio = Radio::new_with_connector(&config, move || {
+        let mut emulator = Emulator::new();
+        if opened.lock().unwrap().is_empty() {
+            emulator.set_identification("Quectel EC25 Revision: EC25EFAR06A06M4G".to_string(),
+                                        "EC25".to_string());
+        }
+
+        let (host, modem) = try!(transport::memory_pair());
+        let (handle, _) = try!(emulator.spawn(modem));
+        opened.lock().unwrap().push(handle);
+        Ok(host)
+    }).unwrap();
+    let client = radio.get_client();
+    assert_eq!(client.phone.profile().name(), "Quectel EC25");
+
+    handles.lock().unwrap()[0].disconnect().unwrap();
+    wait_for_health(&client, ModemHealth::Recovering);
+
+    let started = Instant::now();
+    while client.phone.profile().name() != "SIMCom SIM800" {
+        assert!(started.elapsed() < Duration::from_millis(IDENTIFY_TIMEOUT_MS),
+                "still using the {} profile", client.phone.profile().name());
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert_eq!(handles.lock().unwrap().len(), 2);
+}